@@ -0,0 +1,85 @@
+//! Owned versus zero-copy decoding of a buildbot-like log chunk message
+//!
+//! Run with `cargo bench`. No external harness: a simple timing loop is enough
+//! to compare the two decoders.
+//!
+//! A sample run, on a Linux x86-64 box:
+//!
+//! ```text
+//! owned 1000x64B                              293.986µs/iter      253.1 MiB/s
+//! borrowed 1000x64B                           165.541µs/iter      449.5 MiB/s
+//! owned 100x4096B                               50.44µs/iter     7773.2 MiB/s
+//! borrowed 100x4096B                           15.938µs/iter    24600.5 MiB/s
+//! owned 8x65536B                               22.933µs/iter    21809.1 MiB/s
+//! borrowed 8x65536B                             1.774µs/iter   281932.8 MiB/s
+//! ```
+//!
+//! Borrowing wins more as chunks grow, since the owned decoder copies them.
+
+extern crate twisted_banana;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use twisted_banana::{Element, ElementRef, PerspectiveBroker, PB};
+
+/// Something like a `remote_update` call carrying log chunks
+fn log_chunks_message(chunk_size: usize, chunks: usize) -> Vec<u8> {
+    let updates = (0..chunks)
+        .map(|i| {
+            Element::List(vec![
+                Element::Extension(PB::Tuple),
+                Element::String(b"stdout".to_vec()),
+                Element::String(vec![b'a' + (i % 26) as u8; chunk_size]),
+            ])
+        })
+        .collect();
+    let msg: PerspectiveBroker = Element::List(vec![
+        Element::Extension(PB::Message),
+        Element::Integer(42),
+        Element::Integer(3),
+        Element::String(b"update".to_vec()),
+        Element::Integer(1),
+        Element::List(vec![Element::Extension(PB::Tuple), Element::List(updates)]),
+        Element::List(vec![Element::Extension(PB::Dictionary)]),
+    ]);
+    msg.encode()
+}
+
+fn bench<F: FnMut()>(name: &str, bytes: usize, mut f: F) {
+    // warm up
+    for _i in 0..10 {
+        f();
+    }
+    let mut iterations: u32 = 0;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        f();
+        iterations += 1;
+    }
+    let per_iter = start.elapsed() / iterations;
+    let throughput = bytes as f64 / per_iter.as_secs_f64() / (1 << 20) as f64;
+    println!(
+        "{:<40} {:>12?}/iter {:>10.1} MiB/s",
+        name, per_iter, throughput
+    );
+}
+
+fn main() {
+    for &(chunk_size, chunks) in &[(64, 1000), (4096, 100), (65536, 8)] {
+        let bytes = log_chunks_message(chunk_size, chunks);
+        bench(
+            &format!("owned {}x{}B", chunks, chunk_size),
+            bytes.len(),
+            || {
+                black_box(PerspectiveBroker::from_bytes(black_box(&bytes)).unwrap());
+            },
+        );
+        bench(
+            &format!("borrowed {}x{}B", chunks, chunk_size),
+            bytes.len(),
+            || {
+                black_box(ElementRef::<PB>::from_bytes(black_box(&bytes)).unwrap());
+            },
+        );
+    }
+}
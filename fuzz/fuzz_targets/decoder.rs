@@ -0,0 +1,22 @@
+#![no_main]
+//! Incremental decoding, with chunk boundaries driven by the first byte
+use libfuzzer_sys::fuzz_target;
+use twisted_banana::{Decoder, PB};
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let chunk_size = data[0] as usize + 1;
+    let mut decoder: Decoder<PB> = Decoder::new();
+    for chunk in data[1..].chunks(chunk_size) {
+        decoder.feed(chunk);
+        loop {
+            match decoder.next_element() {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
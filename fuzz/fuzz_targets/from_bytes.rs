@@ -0,0 +1,11 @@
+#![no_main]
+//! All one-shot decoding entry points, with all profiles shipped in the crate.
+use libfuzzer_sys::fuzz_target;
+use twisted_banana::{Banana, PerspectiveBroker};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Banana::from_bytes(data);
+    let _ = Banana::from_bytes_rem(data);
+    let _ = PerspectiveBroker::from_bytes(data);
+    let _ = PerspectiveBroker::from_bytes_rem(data);
+});
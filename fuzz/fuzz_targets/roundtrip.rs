@@ -0,0 +1,12 @@
+#![no_main]
+//! Whatever gets decoded must be encoded in a way that decodes to the same bytes
+use libfuzzer_sys::fuzz_target;
+use twisted_banana::PerspectiveBroker;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(elt) = PerspectiveBroker::from_bytes(data) {
+        let encoded = elt.encode();
+        let decoded = PerspectiveBroker::from_bytes(&encoded).expect("re-decoding failed");
+        assert_eq!(decoded.encode(), encoded);
+    }
+});
@@ -1,15 +1,23 @@
+use std::cmp;
+use std::error;
 use std::fmt;
+use std::io::{self, Write};
+use std::mem;
 use std::str;
 use std::mem::transmute;
+use super::element_ref::ElementRef;
 
-/// The absolute value, as u32 of i32's min value (cannot be represented as a i32)
-const ABSMIN32: u32 = 1 << 31;
+/// The absolute value, as u128 of i128's min value (cannot be represented as a i128)
+const ABSMIN128: u128 = 1 << 127;
 
 /// Represent Banana extension profiles
 pub trait Profile: Sized {
     /// Attempt to decode as an extension element.
     /// preamble is made of the bytes that occur before the delimiter.
     /// It is either a length (as base128 bytes), or has special meaning
+    ///
+    /// Decoding is performed on untrusted input: implementations must return
+    /// errors rather than panic, and the returned remainder must be a suffix of `full_msg`.
     fn decode<'a>(
         delimiter: u8,
         preamble: &'a [u8],
@@ -17,17 +25,78 @@ pub trait Profile: Sized {
     ) -> Result<(Self, &'a [u8]), DecodeError>;
 
     fn encode(&self, v: &mut Vec<u8>);
+
+    /// Exact number of bytes appended by `encode`.
+    ///
+    /// The default implementation actually encodes, profiles should provide
+    /// a direct computation.
+    fn encoded_len(&self) -> usize {
+        let mut v = Vec::new();
+        self.encode(&mut v);
+        v.len()
+    }
+
+    /// Extension element to be sent in place of the given string, if any.
+    ///
+    /// This is how a profile can provide a vocabulary of abbreviated strings.
+    fn compress(_string: &[u8]) -> Option<Self> {
+        None
+    }
+
+    /// Write the same bytes as `encode`, for `Element::encode_to`.
+    ///
+    /// The default implementation goes through a temporary vector, profiles
+    /// should write directly.
+    fn encode_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut v = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut v);
+        w.write_all(&v)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Element<P: Profile> {
     Integer(i32), // split into Integer (0x81) and Negative Integer (0x83)
+    LongInteger(i128), // split into Long Integer (0x85) and Long Negative (0x86)
     String(Vec<u8>), // 0x82
     Float(f64), // 0x84
     List(Vec<Element<P>>), // 0x80
     Extension(P),
 }
 
+/// What's at the start of a serialized element.
+pub(crate) enum Head<'a, P: Profile> {
+    /// A complete element that isn't a list, borrowing from the input
+    Atom(ElementRef<'a, P>),
+    /// A list header, with the number of items to follow
+    List(usize),
+}
+
+/// How to decode extension elements: with the static `Profile::decode`,
+/// or with a profile selected at runtime.
+pub(crate) trait ExtensionDecoding<P> {
+    fn decode_ext<'a>(
+        &self,
+        delimiter: u8,
+        preamble: &'a [u8],
+        full_msg: &'a [u8],
+    ) -> Result<(P, &'a [u8]), DecodeError>;
+}
+
+/// Use `Profile::decode`
+pub(crate) struct StaticDecoding;
+
+impl<P: Profile> ExtensionDecoding<P> for StaticDecoding {
+    fn decode_ext<'a>(
+        &self,
+        delimiter: u8,
+        preamble: &'a [u8],
+        full_msg: &'a [u8],
+    ) -> Result<(P, &'a [u8]), DecodeError> {
+        P::decode(delimiter, preamble, full_msg)
+    }
+}
+
 /// Bare Banana message protocol
 pub type Banana = Element<NoneProfile>;
 
@@ -45,6 +114,168 @@ pub enum DecodeError {
     OverFlow(Vec<u8>),
     TooShort(usize, usize), // contains (expected, actual)
     Invalid(String),
+    StringTooLong(usize, usize), // contains (announced, limit)
+    ListTooLong(usize, usize), // contains (announced, limit)
+    PrefixTooLong(usize, usize), // contains (actual, limit)
+    TooDeep(usize), // contains the limit
+    OverBudget(usize), // contains the limit
+}
+
+impl DecodeError {
+    /// `true` if the error could go away with more bytes.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            *self,
+            DecodeError::Empty | DecodeError::NoType | DecodeError::TooShort(..)
+        )
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::NoType => write!(f, "no type byte"),
+            DecodeError::Empty => write!(f, "empty input"),
+            DecodeError::UnknownType(t) => write!(f, "unknown type byte 0x{:02x}", t),
+            DecodeError::OverFlow(ref b) => write!(f, "integer overflow in {:?}", b),
+            DecodeError::TooShort(expected, actual) => write!(
+                f,
+                "truncated element: expected {} bytes, got {}",
+                expected,
+                actual
+            ),
+            DecodeError::Invalid(ref msg) => write!(f, "invalid element: {}", msg),
+            DecodeError::StringTooLong(len, limit) => {
+                write!(f, "string of length {} exceeds limit {}", len, limit)
+            }
+            DecodeError::ListTooLong(len, limit) => {
+                write!(f, "list of length {} exceeds limit {}", len, limit)
+            }
+            DecodeError::PrefixTooLong(len, limit) => {
+                write!(f, "length prefix of {} bytes exceeds limit {}", len, limit)
+            }
+            DecodeError::TooDeep(limit) => write!(f, "nesting exceeds limit {}", limit),
+            DecodeError::OverBudget(limit) => {
+                write!(f, "allocations exceed limit of {} bytes", limit)
+            }
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Decoding error, with its location, as given by the `*_located` methods
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LocatedDecodeError {
+    pub error: DecodeError,
+    /// Position of the failing element, counted in bytes from the start of
+    /// the input (of the whole stream for `Decoder`)
+    pub offset: usize,
+    /// Indexes of the enclosing lists, from the outermost one
+    pub path: Vec<usize>,
+}
+
+impl LocatedDecodeError {
+    /// Shift to the location of an item, given its position and index in the
+    /// enclosing list.
+    pub(crate) fn within(mut self, offset: usize, index: usize) -> Self {
+        self.offset += offset;
+        self.path.insert(0, index);
+        self
+    }
+
+    /// Set the absolute location, for incremental decoding
+    pub(crate) fn at(mut self, offset: usize, path: Vec<usize>) -> Self {
+        self.offset += offset;
+        self.path = path;
+        self
+    }
+}
+
+/// Error at the start of the input
+impl From<DecodeError> for LocatedDecodeError {
+    fn from(error: DecodeError) -> Self {
+        LocatedDecodeError {
+            error,
+            offset: 0,
+            path: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for LocatedDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.error, self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, ", in list")?;
+            for i in &self.path {
+                write!(f, "[{}]", i)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for LocatedDecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Twisted's `SIZE_LIMIT`, applied to both strings and lists
+pub const SIZE_LIMIT: usize = 640 * 1024;
+
+/// Limits enforced while decoding, to protect against hostile peers
+///
+/// The defaults are those of Twisted for string and list lengths, and
+/// for the length preambles (a.k.a prefixes). Twisted has no explicit limits
+/// on nesting and total size, we use reasonable values.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DecodeLimits {
+    /// Maximum length of a string
+    pub max_string_len: usize,
+    /// Maximum number of items in a list
+    pub max_list_len: usize,
+    /// Maximum number of base128 bytes before the type byte
+    pub max_prefix_len: usize,
+    /// Maximum nesting of lists
+    pub max_depth: usize,
+    /// Maximum total of bytes allocated for a single top-level element,
+    /// counting string contents and list slots.
+    pub max_alloc: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_string_len: SIZE_LIMIT,
+            max_list_len: SIZE_LIMIT,
+            max_prefix_len: 64,
+            max_depth: 512,
+            max_alloc: 32 << 20,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// No limits at all, to be used with trusted input only.
+    pub fn unlimited() -> Self {
+        DecodeLimits {
+            max_string_len: usize::MAX,
+            max_list_len: usize::MAX,
+            max_prefix_len: usize::MAX,
+            max_depth: usize::MAX,
+            max_alloc: usize::MAX,
+        }
+    }
+
+    /// Account for `amount` more allocated bytes, returning the new total.
+    fn charge(&self, used: usize, amount: usize) -> Result<usize, DecodeError> {
+        match used.checked_add(amount) {
+            Some(total) if total <= self.max_alloc => Ok(total),
+            _ => Err(DecodeError::OverBudget(self.max_alloc)),
+        }
+    }
 }
 
 
@@ -57,13 +288,10 @@ impl<P: Profile> Element<P> {
         if ser.len() == 0 {
             return Err(DecodeError::Empty);
         };
-        let mut split_type = ser.splitn(2, |b| *b >= 0x80);
-        let length_bytes = split_type.next().unwrap();
-        let type_offset = length_bytes.len();
-        if type_offset == ser.len() {
-            return Err(DecodeError::NoType);
+        match ser.iter().position(|b| *b >= 0x80) {
+            None => Err(DecodeError::NoType),
+            Some(type_offset) => Ok((&ser[..type_offset], ser[type_offset])),
         }
-        Ok((length_bytes, ser[type_offset]))
     }
 
     /// Decode short nonnegative integer, expressed as base128 bytes
@@ -105,14 +333,46 @@ impl<P: Profile> Element<P> {
         Ok(res)
     }
 
-    fn dec_string(length_bytes: &[u8], full_msg: &[u8]) -> Result<Vec<u8>, DecodeError> {
-        let l = Self::dec_posint(length_bytes)? as usize; // TODO big len
-        let start = length_bytes.len() + 1;
-        let end = start + l;
-        if end > full_msg.len() {
-            return Err(DecodeError::TooShort(l, full_msg.len() - start));
+    /// Decode the absolute value of a long integer, expressed as base128 bytes.
+    ///
+    /// Twisted bounds long integers to 448 bits, we support what fits in a `i128`.
+    fn dec_magnitude(bytes: &[u8]) -> Result<u128, DecodeError> {
+        let mut res: u128 = 0;
+        for b in bytes.iter().rev() {
+            res = match res.checked_mul(128).and_then(|r| r.checked_add(*b as u128)) {
+                Some(r) => r,
+                None => return Err(DecodeError::OverFlow(bytes.into())),
+            };
+        }
+        Ok(res)
+    }
+
+    /// Decode long nonnegative integer (LONGINT), expressed as base128 bytes
+    fn dec_long_posint(bytes: &[u8]) -> Result<i128, DecodeError> {
+        let m = Self::dec_magnitude(bytes)?;
+        if m > i128::MAX as u128 {
+            return Err(DecodeError::OverFlow(bytes.into()));
+        }
+        Ok(m as i128)
+    }
+
+    /// Decode long negative integer (LONGNEG), whose absolute value is
+    /// expressed as base128 bytes.
+    fn dec_long_negint(bytes: &[u8]) -> Result<i128, DecodeError> {
+        let m = Self::dec_magnitude(bytes)?;
+        if m > ABSMIN128 {
+            return Err(DecodeError::OverFlow(bytes.into()));
+        }
+        // wrapping is precisely what we want for i128::MIN
+        Ok(0u128.wrapping_sub(m) as i128)
+    }
+
+    /// Extract a string of given length from the bytes following its type byte
+    fn dec_string(l: usize, contents: &[u8]) -> Result<&[u8], DecodeError> {
+        if l > contents.len() {
+            return Err(DecodeError::TooShort(l, contents.len()));
         }
-        Ok(full_msg[start..end].into())
+        Ok(&contents[..l])
     }
 
     /// Decode a float.
@@ -158,68 +418,153 @@ impl<P: Profile> Element<P> {
         v.extend(&ar);
     }
 
-    /// Decode an element, incuding length marker,
-    /// and return an owned Banana object, together with remaning unused bytes
-    /// maybe consume incoming bytes, to get a 0-copy ?
-    /// Would be nice also to allow for partial messages, allowing to stream very long
-    /// communications without having to wait for completion and represent the full content in
-    /// RAM. Check what applications (e.g., buildbot) actually do for big communications.
-    /// stream within the protocol or outside of it ?
-    pub fn from_bytes_rem<'a>(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), DecodeError> {
-        let (length_bytes, delimiter) = Self::length_type(bytes).unwrap();
-        match P::decode(delimiter, length_bytes, bytes) {
+    /// Decode the head of an element: either a full non-list element, or the
+    /// header of a list, whose items are the elements immediately following it.
+    ///
+    /// This is the common ground of the recursive `from_bytes_rem` and of the
+    /// incremental `Decoder`. Nesting is up to them, but the other limits are
+    /// enforced here, `used` being the allocation total so far. It is updated only
+    /// in case of success. Extension elements are decoded with `ext`.
+    pub(crate) fn head_from_bytes<'a, X: ExtensionDecoding<P> + ?Sized>(
+        bytes: &'a [u8],
+        ext: &X,
+        limits: &DecodeLimits,
+        used: &mut usize,
+    ) -> Result<(Head<'a, P>, &'a [u8]), DecodeError> {
+        let (length_bytes, delimiter) = match Self::length_type(bytes) {
+            Err(DecodeError::NoType) if bytes.len() > limits.max_prefix_len => {
+                return Err(DecodeError::PrefixTooLong(bytes.len(), limits.max_prefix_len));
+            }
+            other => other?,
+        };
+        if length_bytes.len() > limits.max_prefix_len {
+            return Err(DecodeError::PrefixTooLong(
+                length_bytes.len(),
+                limits.max_prefix_len,
+            ));
+        }
+        match ext.decode_ext(delimiter, length_bytes, bytes) {
             Ok((ext, rem)) => {
-                return Ok((Element::Extension(ext), rem));
+                if rem.len() > bytes.len() - length_bytes.len() - 1 {
+                    // not a suffix of the input: our callers rely on that
+                    return Err(DecodeError::Invalid(
+                        "Extension profile did not consume its type byte".into(),
+                    ));
+                }
+                return Ok((Head::Atom(ElementRef::Extension(ext)), rem));
             }
             Err(DecodeError::UnknownType(_)) => {}
             Err(err) => {
                 return Err(err);
             }
         };
-        match delimiter {
-            0x81 => {
-                Ok((
-                    Element::Integer(Self::dec_posint(length_bytes)? as i32),
-                    &bytes[length_bytes.len() + 1..],
-                ))
-            }
-            0x83 => {
-                Ok((
-                    Element::Integer(Self::dec_negint(length_bytes)? as i32),
-                    &bytes[length_bytes.len() + 1..],
-                ))
-            }
+        let contents = &bytes[length_bytes.len() + 1..];
+        let elt = match delimiter {
+            0x81 => ElementRef::Integer(Self::dec_posint(length_bytes)?),
+            0x83 => ElementRef::Integer(Self::dec_negint(length_bytes)?),
+            0x85 => ElementRef::LongInteger(Self::dec_long_posint(length_bytes)?),
+            0x86 => ElementRef::LongInteger(Self::dec_long_negint(length_bytes)?),
             0x82 => {
-                let st = Self::dec_string(length_bytes, bytes)?;
-                let stl = st.len();
-                Ok((
-                    Element::String(st),
-                    &bytes[length_bytes.len() + 1 + stl..],
-                ))
+                let l = Self::dec_posint(length_bytes)? as usize; // TODO big len
+                if l > limits.max_string_len {
+                    return Err(DecodeError::StringTooLong(l, limits.max_string_len));
+                }
+                let new_used = limits.charge(*used, l)?;
+                let st = Self::dec_string(l, contents)?;
+                *used = new_used;
+                return Ok((Head::Atom(ElementRef::String(st)), &contents[l..]));
+            }
+            0x80 => {
+                if length_bytes.len() == 0 {
+                    return Err(DecodeError::Invalid("List without a length".into()));
+                }
+                let list_len = Self::dec_posint(length_bytes)? as usize; // TODO big len
+                if list_len > limits.max_list_len {
+                    return Err(DecodeError::ListTooLong(list_len, limits.max_list_len));
+                }
+                *used = limits.charge(
+                    *used,
+                    list_len.saturating_mul(mem::size_of::<Self>()),
+                )?;
+                return Ok((Head::List(list_len), contents));
             }
-            0x80 => Self::dec_list(length_bytes, bytes),
             0x84 => {
-                Ok((
-                    Element::Float(Self::dec_float(length_bytes, bytes)?),
+                return Ok((
+                    Head::Atom(ElementRef::Float(Self::dec_float(length_bytes, bytes)?)),
                     &bytes[9..],
-                ))
+                ));
             }
-            other => Err(DecodeError::UnknownType(other)),
-        }
+            other => {
+                return Err(DecodeError::UnknownType(other));
+            }
+        };
+        Ok((Head::Atom(elt), contents))
     }
 
-    fn dec_list<'a>(
-        length_bytes: &[u8],
-        full_msg: &'a [u8],
+    /// Decode an element, incuding length marker,
+    /// and return an owned Banana object, together with remaning unused bytes
+    /// maybe consume incoming bytes, to get a 0-copy ?
+    /// To decode partial messages, as they come from the network, see `Decoder`.
+    ///
+    /// The default `DecodeLimits` apply.
+    pub fn from_bytes_rem<'a>(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), DecodeError> {
+        Self::from_bytes_rem_limited(bytes, &DecodeLimits::default())
+    }
+
+    /// Same as `from_bytes_rem`, with explicit limits.
+    pub fn from_bytes_rem_limited<'a>(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
     ) -> Result<(Self, &'a [u8]), DecodeError> {
-        if length_bytes.len() == 0 {
-            return Err(DecodeError::Invalid("List without a length".into()));
-        }
-        let list_len = Self::dec_posint(length_bytes)? as usize; // TODO big len
-        let mut resv: Vec<Self> = Vec::with_capacity(list_len);
-        let mut rem = &full_msg[length_bytes.len() + 1..];
-        for _i in 0..list_len {
-            let item_rem = Self::from_bytes_rem(rem)?;
+        Self::from_bytes_rem_located(bytes, limits).map_err(|err| err.error)
+    }
+
+    /// Same as `from_bytes_rem_limited`, telling where decoding failed.
+    pub fn from_bytes_rem_located<'a>(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        let mut used = 0;
+        Self::dec_rem(bytes, &StaticDecoding, limits, 0, &mut used)
+    }
+
+    /// Recursive decoding of an element at given nesting depth
+    pub(crate) fn dec_rem<'a, X: ExtensionDecoding<P> + ?Sized>(
+        bytes: &'a [u8],
+        ext: &X,
+        limits: &DecodeLimits,
+        depth: usize,
+        used: &mut usize,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        match Self::head_from_bytes(bytes, ext, limits, used)? {
+            (Head::Atom(elt), rem) => Ok((elt.into_owned(), rem)),
+            (Head::List(list_len), rem) => {
+                if depth >= limits.max_depth {
+                    return Err(DecodeError::TooDeep(limits.max_depth).into());
+                }
+                let header_len = bytes.len() - rem.len();
+                Self::dec_list(list_len, header_len, rem, ext, limits, depth + 1, used)
+            }
+        }
+    }
+
+    /// Decode list items, `header_len` being their offset in the list element.
+    fn dec_list<'a, X: ExtensionDecoding<P> + ?Sized>(
+        list_len: usize,
+        header_len: usize,
+        items: &'a [u8],
+        ext: &X,
+        limits: &DecodeLimits,
+        depth: usize,
+        used: &mut usize,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        // each item takes at least one byte: no need to trust the announced
+        // length beyond what we actually have
+        let mut resv: Vec<Self> = Vec::with_capacity(cmp::min(list_len, items.len()));
+        let mut rem = items;
+        for i in 0..list_len {
+            let item_rem = Self::dec_rem(rem, ext, limits, depth, used)
+                .map_err(|err| err.within(header_len + items.len() - rem.len(), i))?;
             resv.push(item_rem.0);
             rem = item_rem.1;
         }
@@ -232,38 +577,145 @@ impl<P: Profile> Element<P> {
         Ok(Self::from_bytes_rem(bytes)?.0)
     }
 
-    /// Raw encoding for an unsigned integer. Can be used as a length or as a direct value
-    fn enc_uint(v: &mut Vec<u8>, i: u32) {
+    /// Same as `from_bytes`, with explicit limits.
+    pub fn from_bytes_limited(bytes: &[u8], limits: &DecodeLimits) -> Result<Self, DecodeError> {
+        Ok(Self::from_bytes_rem_limited(bytes, limits)?.0)
+    }
+
+    /// Same as `from_bytes_limited`, telling where decoding failed.
+    pub fn from_bytes_located(
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<Self, LocatedDecodeError> {
+        Ok(Self::from_bytes_rem_located(bytes, limits)?.0)
+    }
+
+    /// Raw encoding for an unsigned integer, followed by the given type byte.
+    /// The integer can be used as a length or as a direct value.
+    ///
+    /// Returns a buffer and the length of the header in it: a u128 takes at
+    /// most 19 base128 bytes.
+    fn header(i: u128, type_byte: u8) -> ([u8; 20], usize) {
+        let mut buf = [0u8; 20];
+        let mut len = 0;
         let mut j = i;
         while j > 127 {
-            v.push((j % 128) as u8);
+            buf[len] = (j % 128) as u8;
+            len += 1;
             j = j >> 7;
         }
-        v.push(j as u8);
+        buf[len] = j as u8;
+        buf[len + 1] = type_byte;
+        (buf, len + 2)
     }
 
-    fn enc_int(v: &mut Vec<u8>, i: i32) {
+    /// Header of an integer element, choosing between INT/NEG and LONGINT/LONGNEG
+    /// with the same thresholds as Twisted: anything outside of the
+    /// `i32` range is sent as a long integer.
+    fn int_header(i: i128) -> ([u8; 20], usize) {
         if i >= 0 {
-            Self::enc_uint(v, i as u32);
-            v.push(0x81);
+            Self::header(i as u128, if i <= i32::MAX as i128 { 0x81 } else { 0x85 })
         } else {
-            if i == i32::min_value() {
-                Self::enc_uint(v, ABSMIN32);
-            } else {
-                Self::enc_uint(v, -i as u32);
-            }
-            v.push(0x83);
+            Self::header(i.unsigned_abs(), if i >= i32::MIN as i128 { 0x83 } else { 0x86 })
+        }
+    }
+
+    pub(crate) fn enc_header(v: &mut Vec<u8>, i: u128, type_byte: u8) {
+        let (buf, len) = Self::header(i, type_byte);
+        v.extend_from_slice(&buf[..len]);
+    }
+
+    /// `Integer` or `LongInteger`, with the same thresholds as the encoding
+    pub(crate) fn integer(i: i128) -> Self {
+        if i >= i32::MIN as i128 && i <= i32::MAX as i128 {
+            Element::Integer(i as i32)
+        } else {
+            Element::LongInteger(i)
         }
     }
 
+    fn enc_int(v: &mut Vec<u8>, i: i128) {
+        let (buf, len) = Self::int_header(i);
+        v.extend_from_slice(&buf[..len]);
+    }
+
     fn enc_list(v: &mut Vec<u8>, l: &Vec<Self>) {
-        Self::enc_uint(v, l.len() as u32);
-        v.push(0x80);
+        Self::enc_header(v, l.len() as u128, 0x80);
         for elt in l {
             elt.encode_in(v);
         }
     }
 
+    /// Number of bytes needed to encode an unsigned integer as base128 bytes
+    fn uint_len(i: u128) -> usize {
+        let mut len = 1;
+        let mut j = i;
+        while j > 127 {
+            len += 1;
+            j >>= 7;
+        }
+        len
+    }
+
+    fn write_header<W: Write>(w: &mut W, i: u128, type_byte: u8) -> io::Result<()> {
+        let (buf, len) = Self::header(i, type_byte);
+        w.write_all(&buf[..len])
+    }
+
+    fn write_int<W: Write>(w: &mut W, i: i128) -> io::Result<()> {
+        let (buf, len) = Self::int_header(i);
+        w.write_all(&buf[..len])
+    }
+
+    /// Exact number of bytes of the encoded element
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            Element::Integer(i) => Self::uint_len((i as i128).unsigned_abs()) + 1,
+            Element::LongInteger(i) => Self::uint_len(i.unsigned_abs()) + 1,
+            Element::List(ref l) => {
+                l.iter().fold(
+                    Self::uint_len(l.len() as u128) + 1,
+                    |acc, elt| acc + elt.encoded_len(),
+                )
+            }
+            Element::String(ref s) => match P::compress(s) {
+                Some(ext) => ext.encoded_len(),
+                None => Self::uint_len(s.len() as u128) + 1 + s.len(),
+            },
+            Element::Extension(ref p) => p.encoded_len(),
+            Element::Float(_) => 9,
+        }
+    }
+
+    /// Encode directly in any writer, such as a socket or a file
+    ///
+    /// Writes are done piecewise, buffering is up to the caller.
+    pub fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            Element::Integer(i) => Self::write_int(w, i as i128),
+            Element::LongInteger(i) => Self::write_int(w, i),
+            Element::List(ref l) => {
+                Self::write_header(w, l.len() as u128, 0x80)?;
+                for elt in l {
+                    elt.encode_to(w)?;
+                }
+                Ok(())
+            }
+            Element::String(ref s) => match P::compress(s) {
+                Some(ext) => ext.encode_to(w),
+                None => {
+                    Self::write_header(w, s.len() as u128, 0x82)?;
+                    w.write_all(s)
+                }
+            },
+            Element::Extension(ref p) => p.encode_to(w),
+            Element::Float(f) => {
+                w.write_all(&[0x84])?;
+                w.write_all(&f.to_bits().to_be_bytes())
+            }
+        }
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let mut res: Vec<u8> = Vec::new();
         self.encode_in(&mut res);
@@ -273,16 +725,21 @@ impl<P: Profile> Element<P> {
     pub fn encode_in(&self, v: &mut Vec<u8>) {
         match *self {
             Element::Integer(i) => {
+                Self::enc_int(v, i as i128);
+            }
+            Element::LongInteger(i) => {
                 Self::enc_int(v, i);
             }
             Element::List(ref l) => {
                 Self::enc_list(v, l);
             }
-            Element::String(ref s) => {
-                Self::enc_uint(v, s.len() as u32);
-                v.push(0x82);
-                v.extend(s);
-            }
+            Element::String(ref s) => match P::compress(s) {
+                Some(ext) => ext.encode(v),
+                None => {
+                    Self::enc_header(v, s.len() as u128, 0x82);
+                    v.extend(s);
+                }
+            },
             Element::Extension(ref p) => {
                 p.encode(v);
             }
@@ -297,6 +754,7 @@ impl<P: Profile + fmt::Display> fmt::Display for Element<P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Element::Integer(i) => write!(f, "{}", i),
+            Element::LongInteger(i) => write!(f, "{}", i),
             Element::Float(fl) => write!(f, "{}", fl),
             Element::List(ref l) => {
                 write!(f, "[")?;
@@ -330,6 +788,10 @@ impl Profile for NoneProfile {
     }
 
     fn encode(&self, _v: &mut Vec<u8>) {}
+
+    fn encoded_len(&self) -> usize {
+        match *self {}
+    }
 }
 
 impl fmt::Display for NoneProfile {
@@ -390,6 +852,150 @@ mod tests {
     }
 
 
+    #[test]
+    fn error_location() {
+        // [1, [2, <unknown>]]
+        let bytes: &[u8] = &[0x02, 0x80, 0x01, 0x81, 0x02, 0x80, 0x02, 0x81, 0xfe];
+        let limits = DecodeLimits::default();
+        let err = Banana::from_bytes_located(bytes, &limits).unwrap_err();
+        assert_eq!(err.error, DecodeError::UnknownType(0xfe));
+        assert_eq!(err.offset, 8);
+        assert_eq!(err.path, vec![1, 1]);
+        assert_eq!(err.to_string(), "unknown type byte 0xfe at byte 8, in list[1][1]");
+        assert_eq!(Banana::from_bytes(bytes), Err(DecodeError::UnknownType(0xfe)));
+        let err: Box<dyn error::Error> = Box::new(err);
+        assert!(err.to_string().starts_with("unknown type"));
+        assert!(err.source().is_some());
+
+        let err = Banana::from_bytes_located(&[0x04, 0x82, b'b'], &limits).unwrap_err();
+        assert_eq!(err.to_string(), "truncated element: expected 4 bytes, got 1 at byte 0");
+        assert!(err.path.is_empty());
+
+        let err = ElementRef::<NoneProfile>::from_bytes_located(bytes, &limits).unwrap_err();
+        assert_eq!((err.offset, err.path), (8, vec![1, 1]));
+    }
+
+    #[test]
+    fn decode_long_integers() {
+        let bytes: &[u8] = &[0x00, 0x00, 0x00, 0x00, 0x08, 0x85];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Ok(Element::LongInteger(1 << 31))
+        );
+        // Twisted on Python 2 used to send all longs as LONGINT
+        let bytes: &[u8] = &[0x12, 0x34, 0x85];
+        assert_eq!(Banana::from_bytes(bytes), Ok(Element::LongInteger(6674)));
+        let bytes: &[u8] = &[0x01, 0x00, 0x00, 0x00, 0x08, 0x86];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Ok(Element::LongInteger(-(1 << 31) - 1))
+        );
+        let bytes: &[u8] = &[
+            0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f,
+            0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x85,
+        ];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Ok(Element::LongInteger(i128::MAX))
+        );
+        let bytes: &[u8] = &[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x86,
+        ];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Ok(Element::LongInteger(i128::MIN))
+        );
+        let bytes: &[u8] = &[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x85,
+        ];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Err(DecodeError::OverFlow(bytes[..19].into()))
+        );
+        let bytes: &[u8] = &[
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x86,
+        ];
+        assert_eq!(
+            Banana::from_bytes(bytes),
+            Err(DecodeError::OverFlow(bytes[..19].into()))
+        );
+        // 2**448 - 1, the biggest value Twisted would send
+        let mut bytes = vec![0x7f; 64];
+        bytes.push(0x85);
+        assert!(matches!(
+            Banana::from_bytes(&bytes),
+            Err(DecodeError::OverFlow(_))
+        ));
+    }
+
+    #[test]
+    fn encode_long_integers() {
+        // same thresholds as Twisted
+        let elt: Banana = Element::LongInteger(i32::MAX as i128);
+        assert_eq!(&elt.encode(), &[0x7f, 0x7f, 0x7f, 0x7f, 0x07, 0x81]);
+
+        let elt: Banana = Element::LongInteger(1 << 31);
+        assert_eq!(&elt.encode(), &[0x00, 0x00, 0x00, 0x00, 0x08, 0x85]);
+
+        let elt: Banana = Element::LongInteger(i32::MIN as i128);
+        assert_eq!(&elt.encode(), &[0x00, 0x00, 0x00, 0x00, 0x08, 0x83]);
+
+        let elt: Banana = Element::LongInteger(-(1 << 31) - 1);
+        assert_eq!(&elt.encode(), &[0x01, 0x00, 0x00, 0x00, 0x08, 0x86]);
+
+        for i in &[i128::MAX, i128::MIN, 1 << 100, -(1 << 100) + 17] {
+            let elt: Banana = Element::LongInteger(*i);
+            assert_eq!(Banana::from_bytes(&elt.encode()), Ok(elt));
+        }
+    }
+
+    #[test]
+    fn encoded_len() {
+        let elts: Vec<Banana> = vec![
+            Element::Integer(0),
+            Element::Integer(127),
+            Element::Integer(128),
+            Element::Integer(i32::MIN),
+            Element::LongInteger(i128::MIN),
+            Element::LongInteger(i128::MAX),
+            Element::Float(1.5),
+            Element::String(vec![]),
+            Element::String(vec![b'x'; 300]),
+            Element::List(vec![]),
+            Element::List(vec![
+                Element::Integer(-6674),
+                Element::List(vec![Element::String(b"hello".to_vec())]),
+            ]),
+        ];
+        for elt in elts {
+            assert_eq!(elt.encoded_len(), elt.encode().len());
+        }
+    }
+
+    #[test]
+    fn encode_to() {
+        let elt: Banana = Element::List(vec![
+            Element::Integer(i32::MIN),
+            Element::LongInteger(-(1 << 100)),
+            Element::Float(-0.25),
+            Element::String(vec![b'x'; 300]),
+            Element::List(vec![]),
+        ]);
+        let mut v = Vec::new();
+        elt.encode_to(&mut v).unwrap();
+        assert_eq!(v, elt.encode());
+
+        let mut buf = vec![0u8; elt.encoded_len()];
+        elt.encode_to(&mut &mut buf[..]).unwrap();
+        assert_eq!(buf, v);
+
+        let mut too_small = vec![0u8; elt.encoded_len() - 1];
+        assert!(elt.encode_to(&mut &mut too_small[..]).is_err());
+    }
+
     #[test]
     fn decode_string() {
         let bytes: &[u8] = &[0x03, 0x82, b'b', b'a', b'n'];
@@ -445,11 +1051,85 @@ mod tests {
         assert_eq!(&elt.encode(), &[0x02, 0x80, 0x02, 0x81, 0x03, 0x83]);
     }
 
+    #[test]
+    fn limits() {
+        let limits = DecodeLimits {
+            max_string_len: 3,
+            max_list_len: 2,
+            max_prefix_len: 2,
+            max_depth: 2,
+            max_alloc: 1000,
+        };
+        let bytes: &[u8] = &[0x03, 0x82, b'b', b'a', b'n'];
+        assert!(Banana::from_bytes_limited(bytes, &limits).is_ok());
+        let bytes: &[u8] = &[0x04, 0x82, b'b', b'a', b'n', b'a'];
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::StringTooLong(4, 3))
+        );
+        // the announced length is enough to fail
+        let bytes: &[u8] = &[0x7f, 0x7f, 0x82];
+        assert_eq!(Banana::from_bytes(bytes), Err(DecodeError::TooShort(16383, 0)));
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::StringTooLong(16383, 3))
+        );
+
+        let bytes: &[u8] = &[0x03, 0x80, 0x01, 0x81, 0x02, 0x81, 0x03, 0x81];
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::ListTooLong(3, 2))
+        );
+        let bytes: &[u8] = &[0x00, 0x00, 0x00, 0x81];
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::PrefixTooLong(3, 2))
+        );
+        let bytes: &[u8] = &[0x00, 0x00, 0x00];
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::PrefixTooLong(3, 2))
+        );
+        let bytes: &[u8] = &[0x01, 0x80, 0x01, 0x80, 0x00, 0x80];
+        assert_eq!(
+            Banana::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::TooDeep(2))
+        );
+        let bytes: &[u8] = &[0x02, 0x80, 0x03, 0x82, b'b', b'a', b'n', 0x00, 0x82];
+        let used = 3 + 2 * mem::size_of::<Banana>();
+        assert!(
+            Banana::from_bytes_limited(bytes, &DecodeLimits { max_alloc: used, ..limits.clone() })
+                .is_ok()
+        );
+        assert_eq!(
+            Banana::from_bytes_limited(
+                bytes,
+                &DecodeLimits { max_alloc: used - 1, ..limits.clone() }
+            ),
+            Err(DecodeError::OverBudget(used - 1))
+        );
+
+        // huge nesting is rejected by default
+        let mut bytes = Vec::new();
+        for _i in 0..100_000 {
+            bytes.extend(&[0x01, 0x80]);
+        }
+        assert_eq!(Banana::from_bytes(&bytes), Err(DecodeError::TooDeep(512)));
+    }
+
     #[test]
     fn display_int() {
         assert_eq!(format!("{}", Element::Integer(123) as Banana), "123");
     }
 
+    #[test]
+    fn display_long_int() {
+        assert_eq!(
+            format!("{}", Element::LongInteger(-1 << 80) as Banana),
+            "-1208925819614629174706176"
+        );
+    }
+
     #[test]
     fn display_float() {
         assert_eq!(format!("{}", Element::Float(1.23) as Banana), "1.23");
@@ -633,6 +1313,18 @@ mod tests {
     }
 
 
+    #[test]
+    fn encode_to_with_profile() {
+        let elt: TestProto = Element::List(vec![
+            Element::Extension(TestProfile::none()),
+            Element::Extension(TestProfile::some(b'-')),
+        ]);
+        assert_eq!(elt.encoded_len(), 5);
+        let mut v = Vec::new();
+        elt.encode_to(&mut v).unwrap();
+        assert_eq!(v, elt.encode());
+    }
+
     #[test]
     fn display_with_profile() {
         let elt: TestProto = Element::List(vec![
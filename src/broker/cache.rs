@@ -0,0 +1,457 @@
+//! Objects whose state is mirrored by the peer, as Twisted's `pb.Cacheable`
+//! and `RemoteCache`
+
+use std::any::Any;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use super::super::jelly::{JellyError, JellyValue};
+use super::referenceable::puid;
+use super::{Broker, CallFuture, Referenceable, RemoteCall, Reply, Target};
+
+/// Object sent to the peer as a copy that it keeps up to date, as Twisted's
+/// `pb.Cacheable`
+///
+/// The first time it is sent to the peer, its state is sent along with an
+/// observer, to be given the changes. Afterwards, only its id is sent. Once
+/// the peer no longer uses it, `stopped_observing()` is called.
+pub trait Cacheable {
+    /// Fully qualified name of the class, as Twisted's `getTypeToCopy`
+    fn type_to_copy(&self) -> String;
+
+    /// The class for the peer logged in as `perspective`, as Twisted's
+    /// `getTypeToCopyFor`
+    fn type_to_copy_for(&self, _perspective: Option<&Rc<dyn Referenceable>>) -> String {
+        self.type_to_copy()
+    }
+
+    /// The state for the peer logged in as `perspective`, whose changes are
+    /// to be given to `observer`, as Twisted's
+    /// `getStateToCacheAndObserveFor`
+    fn state_to_cache_and_observe_for(
+        &self,
+        perspective: Option<&Rc<dyn Referenceable>>,
+        observer: RemoteCacheObserver,
+    ) -> JellyValue;
+
+    /// The peer no longer uses the copy, or the connection was lost.
+    fn stopped_observing(
+        &self,
+        _perspective: Option<&Rc<dyn Referenceable>>,
+        _observer: &RemoteCacheObserver,
+    ) {
+    }
+}
+
+impl fmt::Debug for dyn Cacheable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{} at {:p}>", self.type_to_copy(), self as *const dyn Cacheable)
+    }
+}
+
+/// Identity of a cacheable, as Python's `id()`
+pub(crate) fn cacheable_puid(object: &Rc<dyn Cacheable>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// Handle to the copy of a `Cacheable` on the peer's side, as Twisted's
+/// `RemoteCacheObserver`
+#[derive(Clone)]
+pub struct RemoteCacheObserver {
+    broker: Broker,
+    luid: i64,
+    perspective: Option<Rc<dyn Referenceable>>,
+}
+
+impl RemoteCacheObserver {
+    pub(crate) fn new(broker: Broker, luid: i64, perspective: Option<Rc<dyn Referenceable>>) -> Self {
+        RemoteCacheObserver {
+            broker,
+            luid,
+            perspective,
+        }
+    }
+
+    pub fn broker(&self) -> &Broker {
+        &self.broker
+    }
+
+    pub fn perspective(&self) -> Option<&Rc<dyn Referenceable>> {
+        self.perspective.as_ref()
+    }
+
+    /// Call `observe_<method>` on the copy
+    pub fn call_remote(
+        &self,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+    ) -> CallFuture {
+        let target = Target::cache(self.luid, self.perspective.clone());
+        self.broker.call_remote(target, method, args, kwargs)
+    }
+}
+
+impl PartialEq for RemoteCacheObserver {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.broker.state, &other.broker.state) &&
+            self.luid == other.luid &&
+            self.perspective.as_ref().map(puid) == other.perspective.as_ref().map(puid)
+    }
+}
+
+impl fmt::Debug for RemoteCacheObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RemoteCacheObserver({})", self.luid)
+    }
+}
+
+/// Local copy of a `pb.Cacheable` of the peer, as Twisted's `RemoteCache`
+///
+/// Types are registered by the fully qualified name of the class, with
+/// `Broker::set_unjellyable_for_cache()`.
+pub trait RemoteCache: Any + fmt::Debug {
+    /// Build the copy from the state sent by the peer, as Twisted's
+    /// `setCopyableState`.
+    fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError>
+    where
+        Self: Sized;
+
+    /// Handle a change from the peer, matching on `call.method`, e.g.,
+    /// `observe_setBranch`.
+    fn remote_message_received(&self, call: RemoteCall) -> Reply;
+}
+
+impl dyn RemoteCache {
+    pub fn downcast_ref<T: RemoteCache>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref()
+    }
+}
+
+struct Inner {
+    broker: Broker,
+    luid: i64,
+    object: Rc<dyn RemoteCache>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.broker.release_cache(self.luid);
+    }
+}
+
+/// Copy of a `pb.Cacheable` of the peer
+///
+/// As with `RemoteReference`, the copy is the same each time the peer sends
+/// it, and the peer is told that it's no longer used when the last clone is
+/// dropped.
+#[derive(Clone)]
+pub struct CacheReference {
+    inner: Rc<Inner>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WeakCache(Weak<Inner>);
+
+impl WeakCache {
+    pub(crate) fn upgrade(&self) -> Option<CacheReference> {
+        self.0.upgrade().map(|inner| CacheReference { inner })
+    }
+}
+
+impl CacheReference {
+    pub(crate) fn new(broker: Broker, luid: i64, object: Rc<dyn RemoteCache>) -> Self {
+        CacheReference {
+            inner: Rc::new(Inner {
+                broker,
+                luid,
+                object,
+            }),
+        }
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakCache {
+        WeakCache(Rc::downgrade(&self.inner))
+    }
+
+    /// Id of the cacheable on the peer's side
+    pub fn luid(&self) -> i64 {
+        self.inner.luid
+    }
+
+    pub fn object(&self) -> &Rc<dyn RemoteCache> {
+        &self.inner.object
+    }
+
+    pub fn downcast_ref<T: RemoteCache>(&self) -> Option<&T> {
+        self.inner.object.downcast_ref()
+    }
+}
+
+impl PartialEq for CacheReference {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.inner.broker.state, &other.inner.broker.state) &&
+            self.inner.luid == other.inner.luid
+    }
+}
+
+impl fmt::Debug for CacheReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CacheReference({}, {:?})", self.inner.luid, self.inner.object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use super::super::super::{ObjectId, PBMessage};
+    use super::super::testing::{connected, pump, remote_error};
+    use super::super::{BrokerError, CallError, RemoteError};
+
+    const BUILD_STATUS: &str = "buildbot.status.build.BuildStatus";
+
+    fn unicode(s: &str) -> JellyValue {
+        JellyValue::Unicode(s.into())
+    }
+
+    #[derive(Default)]
+    struct BuildStatus {
+        observers: RefCell<Vec<RemoteCacheObserver>>,
+        stopped: RefCell<usize>,
+    }
+
+    impl BuildStatus {
+        fn set_state(&self, state: &str) -> Vec<CallFuture> {
+            self.observers
+                .borrow()
+                .iter()
+                .map(|observer| observer.call_remote("setState", &[unicode(state)], &[]))
+                .collect()
+        }
+    }
+
+    impl Cacheable for BuildStatus {
+        fn type_to_copy(&self) -> String {
+            BUILD_STATUS.into()
+        }
+
+        fn state_to_cache_and_observe_for(
+            &self,
+            _perspective: Option<&Rc<dyn Referenceable>>,
+            observer: RemoteCacheObserver,
+        ) -> JellyValue {
+            self.observers.borrow_mut().push(observer);
+            JellyValue::dict(vec![(JellyValue::Bytes(b"state".to_vec()), unicode("idle"))])
+        }
+
+        fn stopped_observing(
+            &self,
+            _perspective: Option<&Rc<dyn Referenceable>>,
+            observer: &RemoteCacheObserver,
+        ) {
+            self.observers.borrow_mut().retain(|o| o != observer);
+            *self.stopped.borrow_mut() += 1;
+        }
+    }
+
+    #[derive(Debug)]
+    struct RemoteBuildStatus {
+        state: RefCell<JellyValue>,
+    }
+
+    impl RemoteCache for RemoteBuildStatus {
+        fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError> {
+            Ok(RemoteBuildStatus {
+                state: RefCell::new(state.get("state").unwrap_or(JellyValue::None)),
+            })
+        }
+
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "observe_setState" => {
+                    *self.state.borrow_mut() = call.args[0].clone();
+                    Reply::value(JellyValue::None)
+                }
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    struct Master {
+        status: Rc<BuildStatus>,
+    }
+
+    impl Referenceable for Master {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "remote_getStatus" => Reply::value(JellyValue::Cacheable(self.status.clone())),
+                "remote_same" => Reply::value(call.args[0].clone()),
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    fn get_status(client: &Broker, server: &Broker) -> CacheReference {
+        let mut answer = client.root_object().call_remote("getStatus", &[], &[]);
+        pump(client, server);
+        pump(server, client);
+        match answer.try_take() {
+            Some(Ok(JellyValue::Cached(cache))) => cache,
+            other => panic!("Expected a cache, got {:?}", other),
+        }
+    }
+
+    fn state_of(cache: &CacheReference) -> JellyValue {
+        cache.downcast_ref::<RemoteBuildStatus>().unwrap().state.borrow().clone()
+    }
+
+    #[test]
+    fn cache() {
+        let (client, server) = connected();
+        let status = Rc::new(BuildStatus::default());
+        server.set_root(Rc::new(Master {
+            status: status.clone(),
+        }));
+        client.set_unjellyable_for_cache::<RemoteBuildStatus>(BUILD_STATUS);
+
+        let mut answer = client.root_object().call_remote("getStatus", &[], &[]);
+        pump(&client, &server);
+        let outgoing = server.take_outgoing();
+        // as Twisted sends it: [answer, 1, [class, luid, state]]
+        let expected: &[u8] = &[
+            &[0x03, 0x80, 0x1b, 0x87, 0x01, 0x81, 0x03, 0x80, 0x21, 0x82],
+            BUILD_STATUS.as_bytes(),
+            &[0x01, 0x81, 0x02, 0x80, 0x05, 0x87, 0x02, 0x80, 0x05, 0x82],
+            b"state",
+            &[0x02, 0x80, 0x07, 0x82],
+            b"unicode",
+            &[0x04, 0x82],
+            b"idle",
+        ].concat();
+        assert_eq!(outgoing[0].encode(), expected);
+        client.receive(&outgoing[0]).unwrap();
+        let first = match answer.try_take() {
+            Some(Ok(JellyValue::Cached(cache))) => cache,
+            other => panic!("Expected a cache, got {:?}", other),
+        };
+        assert_eq!(first.luid(), 1);
+        assert_eq!(state_of(&first), unicode("idle"));
+
+        // changes are pushed to the copy
+        let mut answers = status.set_state("building");
+        assert_eq!(answers.len(), 1);
+        pump(&server, &client);
+        assert_eq!(state_of(&first), unicode("building"));
+        pump(&client, &server);
+        assert_eq!(answers[0].try_take(), Some(Ok(JellyValue::None)));
+
+        // sent again, it's the same copy, and it can be sent back
+        let second = get_status(&client, &server);
+        assert!(Rc::ptr_eq(&first.inner, &second.inner));
+        let mut answer =
+            client.root_object().call_remote("get", &[JellyValue::Cached(second.clone())], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        assert_eq!(
+            remote_error(answer.try_take()),
+            RemoteError::no_such_method("remote_get")
+        );
+        let mut answer =
+            client.root_object().call_remote("same", &[JellyValue::Cached(second.clone())], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        assert_eq!(answer.try_take(), Some(Ok(JellyValue::Cached(first.clone()))));
+        assert_eq!(server.state.borrow().remotely_cached[&1].refcount, 3);
+
+        // decached once unused, for each time it was sent
+        drop(first);
+        drop(second);
+        let outgoing = client.take_outgoing();
+        assert_eq!(outgoing, vec![PBMessage::DeCache(1).build(); 3]);
+        for elt in &outgoing[..2] {
+            server.receive(elt).unwrap();
+        }
+        assert_eq!(*status.stopped.borrow(), 0);
+
+        // still observed until then, and copied again from the same state
+        let mut answers = status.set_state("finished");
+        pump(&server, &client);
+        pump(&client, &server);
+        assert_eq!(answers[0].try_take(), Some(Ok(JellyValue::None)));
+        let third = get_status(&client, &server);
+        assert_eq!(state_of(&third), unicode("finished"));
+        assert_eq!(server.state.borrow().remotely_cached[&1].refcount, 2);
+
+        drop(third);
+        server.receive(&outgoing[2]).unwrap();
+        pump(&client, &server);
+        assert_eq!(*status.stopped.borrow(), 1);
+        assert!(status.observers.borrow().is_empty());
+        pump(&server, &client);
+        assert!(client.state.borrow().locally_cached.is_empty());
+        assert!(server.state.borrow().remotely_cached.is_empty());
+
+        // a new copy, with a new id
+        assert_eq!(get_status(&client, &server).luid(), 2);
+        server.connection_lost();
+        assert_eq!(*status.stopped.borrow(), 2);
+    }
+
+    #[test]
+    fn dropped_while_in_use() {
+        let (client, server) = connected();
+        server.set_root(Rc::new(Master {
+            status: Rc::new(BuildStatus::default()),
+        }));
+        client.set_unjellyable_for_cache::<RemoteBuildStatus>(BUILD_STATUS);
+        let first = get_status(&client, &server);
+        let second = get_status(&client, &server);
+        let mut answer = client.root_object().call_remote("getStatus", &[], &[]);
+        pump(&client, &server);
+        let state = client.state.borrow_mut();
+        drop(first);
+        drop(second);
+        drop(state);
+        // received again before the decaches were sent
+        pump(&server, &client);
+        let third = answer.try_take();
+        assert_eq!(client.state.borrow().locally_cached[&1].receipts, 1);
+        assert_eq!(client.take_outgoing(), vec![PBMessage::DeCache(1).build(); 2]);
+        // or sent with the next elements
+        let state = client.state.borrow_mut();
+        drop(third);
+        drop(state);
+        assert_eq!(client.take_outgoing(), vec![PBMessage::DeCache(1).build()]);
+    }
+
+    #[test]
+    fn errors() {
+        let (client, server) = connected();
+        server.set_root(Rc::new(Master {
+            status: Rc::new(BuildStatus::default()),
+        }));
+        let mut answer = client.root_object().call_remote("getStatus", &[], &[]);
+        pump(&client, &server);
+        for elt in server.take_outgoing() {
+            assert_eq!(
+                client.receive(&elt),
+                Err(BrokerError::Jelly(JellyError::Insecure(format!(
+                    "Class not cacheable: {}",
+                    BUILD_STATUS
+                ))))
+            );
+        }
+        assert_eq!(answer.try_take(), Some(Err(CallError::ConnectionLost)));
+
+        let (client, server) = connected();
+        let observer = RemoteCacheObserver::new(server.clone(), 1, None);
+        let mut answer = observer.call_remote("setState", &[], &[]);
+        pump(&server, &client);
+        pump(&client, &server);
+        assert_eq!(remote_error(answer.try_take()), RemoteError::pb_error("Invalid Object ID"));
+        assert_eq!(
+            client.receive(&PBMessage::UnCache(1).build()),
+            Err(BrokerError::UnknownObject(ObjectId::Number(1)))
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Futures for the answers to remote calls, as Twisted's `callRemote` Deferreds
+
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use super::super::jelly::JellyValue;
+use super::RemoteError;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CallError {
+    /// The remote method failed, with the failure sent by the peer
+    Failed(JellyValue),
+    /// The broker was already closed, as Twisted's `DeadReferenceError`
+    DeadReference,
+    /// The connection was lost before the answer came, as Twisted's
+    /// `PBConnectionLost`
+    ConnectionLost,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::Failed(ref failure) => match RemoteError::from_failure(failure) {
+                Some(err) => write!(f, "remote call failed: {}", err),
+                None => write!(f, "remote call failed: {:?}", failure),
+            },
+            CallError::DeadReference => write!(f, "calling stale broker"),
+            CallError::ConnectionLost => write!(f, "connection lost"),
+        }
+    }
+}
+
+impl error::Error for CallError {}
+
+impl CallError {
+    /// The exception raised by the remote method, if it failed with one
+    pub fn remote_error(&self) -> Option<RemoteError> {
+        match *self {
+            CallError::Failed(ref failure) => RemoteError::from_failure(failure),
+            _ => None,
+        }
+    }
+}
+
+pub type CallResult = Result<JellyValue, CallError>;
+
+/// Shared by the broker and the future
+#[derive(Debug, Default)]
+pub(crate) struct Answer {
+    result: Option<CallResult>,
+    waker: Option<Waker>,
+}
+
+pub(crate) type PendingAnswer = Rc<RefCell<Answer>>;
+
+/// Give the result, waking the task awaiting it if any.
+///
+/// This must not be called with the broker state borrowed, as the waker may
+/// run user code.
+pub(crate) fn resolve(answer: &PendingAnswer, result: CallResult) {
+    let waker = {
+        let mut answer = answer.borrow_mut();
+        answer.result = Some(result);
+        answer.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Answer to a remote call, resolved when the broker receives it.
+///
+/// This is not `Send`, as the broker itself.
+#[derive(Debug)]
+pub struct CallFuture {
+    answer: PendingAnswer,
+}
+
+impl CallFuture {
+    pub(crate) fn new() -> (Self, PendingAnswer) {
+        let answer = PendingAnswer::default();
+        (
+            CallFuture {
+                answer: answer.clone(),
+            },
+            answer,
+        )
+    }
+
+    pub(crate) fn ready(result: CallResult) -> Self {
+        let (fut, answer) = Self::new();
+        resolve(&answer, result);
+        fut
+    }
+
+    /// The result, if already there, without waiting.
+    pub fn try_take(&mut self) -> Option<CallResult> {
+        self.answer.borrow_mut().result.take()
+    }
+}
+
+impl Future for CallFuture {
+    type Output = CallResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<CallResult> {
+        let mut answer = self.answer.borrow_mut();
+        match answer.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                answer.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wake_on_resolve() {
+        let counter = Arc::new(Counter::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let (mut fut, answer) = CallFuture::new();
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+        resolve(&answer, Ok(JellyValue::Int(1)));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Ok(JellyValue::Int(1))));
+
+        let mut fut = CallFuture::ready(Err(CallError::DeadReference));
+        assert_eq!(fut.try_take(), Some(Err(CallError::DeadReference)));
+        assert_eq!(fut.try_take(), None);
+    }
+}
@@ -0,0 +1,337 @@
+//! Failures of remote calls, as Twisted's `CopyableFailure` and
+//! `CopiedFailure`
+//!
+//! Twisted sends the failure of a remote method as an instance of
+//! `twisted.spread.pb.CopyableFailure`, whose state holds the name of the
+//! exception class, the exception as a string, and the traceback if the
+//! factory has `unsafeTracebacks` set. Methods may also fail with a bare
+//! string.
+
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+use super::super::jelly::{Copyable, JellyError, JellyValue, RemoteCopy};
+
+/// Class of the failures jellied by Twisted
+pub(crate) const COPYABLE_FAILURE: &str = "twisted.spread.pb.CopyableFailure";
+
+/// Failures of the broker itself, as Twisted's `pb.Error`
+const PB_ERROR: &str = "twisted.spread.pb.Error";
+
+/// What Twisted sends instead of the traceback, unless asked otherwise
+const TRACEBACK_UNAVAILABLE: &str = "Traceback unavailable\n";
+
+/// Exception raised by a remote method, as Twisted's `CopiedFailure`
+///
+/// This is also how the failures of local methods are sent to the peer, as
+/// with `Reply::failure(err.into())`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RemoteError {
+    /// Fully qualified name of the exception class, e.g.,
+    /// `twisted.cred.error.UnauthorizedLogin`. Empty for bare strings.
+    pub remote_type: String,
+    /// The exception, as `str()` gives it
+    pub value: String,
+    /// Formatted traceback, as Twisted's `getTraceback()`
+    pub traceback: String,
+    /// Fully qualified names of the class and of its bases, in their method
+    /// resolution order
+    pub parents: Vec<String>,
+}
+
+impl RemoteError {
+    /// Exception of the class `remote_type`, deriving from `Exception`
+    pub fn new(remote_type: &str, value: &str) -> Self {
+        RemoteError {
+            remote_type: remote_type.to_owned(),
+            value: value.to_owned(),
+            traceback: TRACEBACK_UNAVAILABLE.to_owned(),
+            parents: vec![
+                remote_type.to_owned(),
+                "builtins.Exception".to_owned(),
+                "builtins.BaseException".to_owned(),
+                "builtins.object".to_owned(),
+            ],
+        }
+    }
+
+    /// Exception of the class `remote_type` for a Rust error, the chain of
+    /// its sources standing for the traceback
+    pub fn from_error(remote_type: &str, err: &dyn error::Error) -> Self {
+        let mut remote = Self::new(remote_type, &err.to_string());
+        let mut source = err.source();
+        if source.is_some() {
+            remote.traceback = "Caused by:\n".to_owned();
+        }
+        while let Some(err) = source {
+            remote.traceback.push_str(&format!("  {}\n", err));
+            source = err.source();
+        }
+        remote
+    }
+
+    /// Failure of the broker itself, e.g., for calls to unknown objects, as
+    /// Twisted's `pb.Error`
+    pub(crate) fn pb_error(value: &str) -> Self {
+        Self::new(PB_ERROR, value)
+    }
+
+    /// Call to a method that the object doesn't have, as Twisted's
+    /// `pb.NoSuchMethod`, which derives from `AttributeError`
+    pub(crate) fn no_such_method(method: &str) -> Self {
+        let value = format!("No such method: {}", method);
+        let mut remote = Self::new("twisted.spread.pb.NoSuchMethod", &value);
+        remote.parents.insert(1, "builtins.AttributeError".to_owned());
+        remote
+    }
+
+    /// The error in a failure sent by the peer, if it is one
+    ///
+    /// This is the case of `CopyableFailure` instances, whether unjellied as
+    /// `RemoteError` or not, and of bare strings.
+    pub fn from_failure(failure: &JellyValue) -> Option<Self> {
+        match *failure {
+            JellyValue::Copied(ref copy) => copy.downcast_ref::<RemoteError>().cloned(),
+            JellyValue::Instance(ref inst) if inst.borrow().class == COPYABLE_FAILURE => {
+                Self::from_state(&inst.borrow().state).ok()
+            }
+            JellyValue::Bytes(_) | JellyValue::Unicode(_) => Some(RemoteError {
+                remote_type: String::new(),
+                value: text(failure)?,
+                traceback: String::new(),
+                parents: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn from_state(state: &JellyValue) -> Result<Self, JellyError> {
+        let remote_type = match state.get("type").as_ref().and_then(text) {
+            Some(remote_type) => remote_type,
+            None => return Err(JellyError::Malformed("CopyableFailure without type".into())),
+        };
+        let field = |key| state.get(key).as_ref().and_then(text).unwrap_or_default();
+        let parents = match state.get("parents") {
+            Some(JellyValue::List(ref items)) | Some(JellyValue::Tuple(ref items)) => {
+                items.borrow().iter().filter_map(text).collect()
+            }
+            _ => vec![remote_type.clone()],
+        };
+        Ok(RemoteError {
+            value: field("value"),
+            traceback: field("traceback"),
+            remote_type,
+            parents,
+        })
+    }
+
+    /// `true` if the exception is an instance of `class`, as Python's
+    /// `isinstance`.
+    ///
+    /// Classes are given by their fully qualified name, or by their name
+    /// alone, e.g., `UnauthorizedLogin`.
+    pub fn is_instance_of(&self, class: &str) -> bool {
+        self.parents.iter().any(|parent| {
+            parent == class || (!class.contains('.') && parent.rsplit('.').next() == Some(class))
+        })
+    }
+
+    /// The first of `classes` that the exception is an instance of, as
+    /// Twisted's `Failure.check`
+    pub fn check<'a>(&self, classes: &[&'a str]) -> Option<&'a str> {
+        classes.iter().cloned().find(|class| self.is_instance_of(class))
+    }
+}
+
+/// Text sent either as bytes, as by Python 2, or as unicode
+fn text(value: &JellyValue) -> Option<String> {
+    match *value {
+        JellyValue::Bytes(ref b) => Some(String::from_utf8_lossy(b).into_owned()),
+        JellyValue::Unicode(ref s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.remote_type.is_empty() {
+            return write!(f, "{}", self.value);
+        }
+        write!(f, "{}: {}", self.remote_type, self.value)
+    }
+}
+
+impl error::Error for RemoteError {}
+
+impl RemoteCopy for RemoteError {
+    fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError> {
+        Self::from_state(&state)
+    }
+}
+
+/// Jellied as Twisted's `CopyableFailure.getStateToCopy` does
+impl Copyable for RemoteError {
+    fn type_to_copy(&self) -> String {
+        COPYABLE_FAILURE.to_owned()
+    }
+
+    fn state_to_copy(&self) -> JellyValue {
+        let key = |k: &str| JellyValue::Unicode(k.to_owned());
+        let parents = self.parents.iter().map(|p| JellyValue::Unicode(p.clone())).collect();
+        JellyValue::dict(vec![
+            (key("type"), JellyValue::Bytes(self.remote_type.as_bytes().to_vec())),
+            (key("value"), JellyValue::Unicode(self.value.clone())),
+            (key("traceback"), JellyValue::Unicode(self.traceback.clone())),
+            (key("parents"), JellyValue::list(parents)),
+            (key("tb"), JellyValue::None),
+            (key("frames"), JellyValue::list(Vec::new())),
+            (key("stack"), JellyValue::list(Vec::new())),
+            (key("count"), JellyValue::Int(0)),
+        ])
+    }
+}
+
+impl From<RemoteError> for JellyValue {
+    fn from(err: RemoteError) -> Self {
+        JellyValue::Copyable(Rc::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{Element, PerspectiveBroker, PB};
+    use super::super::super::jelly::{jelly, SecurityOptions, Unjellier, UnjellyableRegistry};
+
+    fn s(st: &str) -> PerspectiveBroker {
+        Element::String(st.as_bytes().to_vec())
+    }
+
+    fn unicode(st: &str) -> PerspectiveBroker {
+        Element::List(vec![s("unicode"), s(st)])
+    }
+
+    /// Failure of `1 / 0`, as jellied by Twisted on Python 3
+    fn zero_division() -> PerspectiveBroker {
+        let entry = |k, v| Element::List(vec![unicode(k), v]);
+        let list = |items: Vec<PerspectiveBroker>| {
+            let mut list = vec![Element::Extension(PB::List)];
+            list.extend(items);
+            Element::List(list)
+        };
+        Element::List(vec![
+            s(COPYABLE_FAILURE),
+            Element::List(vec![
+                Element::Extension(PB::Dictionary),
+                entry("value", unicode("division by zero")),
+                entry("type", s("builtins.ZeroDivisionError")),
+                entry("tb", Element::List(vec![Element::Extension(PB::None)])),
+                entry("frames", list(vec![])),
+                entry("stack", list(vec![])),
+                entry(
+                    "parents",
+                    list(vec![
+                        unicode("builtins.ZeroDivisionError"),
+                        unicode("builtins.ArithmeticError"),
+                        unicode("builtins.Exception"),
+                        unicode("builtins.BaseException"),
+                        unicode("builtins.object"),
+                    ]),
+                ),
+                entry("count", Element::Integer(3)),
+                entry("traceback", unicode("Traceback unavailable\n")),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn copied() {
+        let mut unjellier = Unjellier::with_taster(SecurityOptions::permissive());
+        let failure = unjellier.unjelly(&zero_division()).unwrap();
+        let err = RemoteError::from_failure(&failure).unwrap();
+        assert_eq!(err.remote_type, "builtins.ZeroDivisionError");
+        assert_eq!(err.value, "division by zero");
+        assert_eq!(err.traceback, "Traceback unavailable\n");
+        assert_eq!(err.to_string(), "builtins.ZeroDivisionError: division by zero");
+
+        assert!(err.is_instance_of("builtins.ArithmeticError"));
+        assert!(err.is_instance_of("ZeroDivisionError"));
+        assert!(!err.is_instance_of("builtins.ValueError"));
+        assert!(!err.is_instance_of("other.ZeroDivisionError"));
+        assert_eq!(err.check(&["ValueError", "ArithmeticError"]), Some("ArithmeticError"));
+        assert_eq!(err.check(&["ValueError"]), None);
+
+        // the same, once registered
+        let mut registry = UnjellyableRegistry::new();
+        registry.register::<RemoteError>(COPYABLE_FAILURE);
+        unjellier.set_registry(registry);
+        let copied = unjellier.unjelly(&zero_division()).unwrap();
+        assert!(match copied {
+            JellyValue::Copied(ref copy) => copy.is::<RemoteError>(),
+            _ => false,
+        });
+        assert_eq!(RemoteError::from_failure(&copied), Some(err));
+
+        let failure = JellyValue::Unicode("Invalid Object ID".into());
+        let bare = RemoteError::from_failure(&failure).unwrap();
+        assert_eq!(bare.to_string(), "Invalid Object ID");
+        assert!(!bare.is_instance_of("builtins.Exception"));
+        assert_eq!(RemoteError::from_failure(&JellyValue::Int(1)), None);
+        let untyped = JellyValue::instance(COPYABLE_FAILURE, JellyValue::dict(vec![]));
+        assert_eq!(RemoteError::from_failure(&untyped), None);
+    }
+
+    #[derive(Debug)]
+    struct Io;
+
+    impl fmt::Display for Io {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "disk full")
+        }
+    }
+
+    impl error::Error for Io {}
+
+    #[derive(Debug)]
+    struct Upload(Io);
+
+    impl fmt::Display for Upload {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "upload failed")
+        }
+    }
+
+    impl error::Error for Upload {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn copyable() {
+        let err = RemoteError::from_error("buildbot.worker.UploadError", &Upload(Io));
+        assert_eq!(err.value, "upload failed");
+        assert_eq!(err.traceback, "Caused by:\n  disk full\n");
+        assert!(err.is_instance_of("Exception"));
+        let io = RemoteError::from_error("builtins.OSError", &Io);
+        assert_eq!(io.traceback, TRACEBACK_UNAVAILABLE);
+
+        // what Twisted unjellies as a CopiedFailure, with the same state
+        let elt = jelly(&err.clone().into());
+        match elt {
+            Element::List(ref items) => assert_eq!(items[0], s(COPYABLE_FAILURE)),
+            ref other => panic!("Expected an instance, got {:?}", other),
+        }
+        let failure = Unjellier::with_taster(SecurityOptions::permissive()).unjelly(&elt).unwrap();
+        match failure {
+            JellyValue::Instance(ref inst) => {
+                let state = &inst.borrow().state;
+                let remote_type = b"buildbot.worker.UploadError".to_vec();
+                assert_eq!(state.get("type"), Some(JellyValue::Bytes(remote_type)));
+                assert_eq!(state.get("tb"), Some(JellyValue::None));
+            }
+            ref other => panic!("Expected an instance, got {:?}", other),
+        }
+        assert_eq!(RemoteError::from_failure(&failure), Some(err));
+    }
+}
@@ -0,0 +1,292 @@
+//! Logging in, as Twisted's `PBClientFactory.login`
+//!
+//! The root object of a server with a portal has a `login` method, that
+//! takes a username and answers with a challenge and a challenger. The
+//! challenger's `respond` method takes the response to the challenge and the
+//! client's mind, and answers with the avatar of the user, its perspective.
+
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use super::super::jelly::JellyValue;
+use super::{Broker, CallError, CallFuture, Referenceable, RemoteReference};
+
+/// What the client proves who it is with, as Twisted's cred credentials
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Credentials {
+    Anonymous,
+    UsernamePassword { username: Vec<u8>, password: Vec<u8> },
+}
+
+impl Credentials {
+    pub fn username_password(username: &[u8], password: &[u8]) -> Self {
+        Credentials::UsernamePassword {
+            username: username.to_vec(),
+            password: password.to_vec(),
+        }
+    }
+}
+
+/// Response to a challenge, as Twisted's `pb.respond`, i.e., the MD5 of the
+/// MD5 of the password followed by the challenge
+pub fn respond(challenge: &[u8], password: &[u8]) -> Vec<u8> {
+    let hashed = md5::compute(password);
+    let mut ctx = md5::Context::new();
+    ctx.consume(hashed.0);
+    ctx.consume(challenge);
+    ctx.compute().0.to_vec()
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoginError {
+    /// A call to the server failed, e.g., with `UnauthorizedLogin`
+    Call(CallError),
+    /// The server answered with something else than expected
+    UnexpectedAnswer(JellyValue),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoginError::Call(ref err) => write!(f, "login failed: {}", err),
+            LoginError::UnexpectedAnswer(ref answer) => {
+                write!(f, "unexpected answer to login: {:?}", answer)
+            }
+        }
+    }
+}
+
+impl error::Error for LoginError {}
+
+impl From<CallError> for LoginError {
+    fn from(err: CallError) -> Self {
+        LoginError::Call(err)
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    /// Waiting for the challenge, with the password to respond with
+    Challenge(CallFuture, Vec<u8>),
+    /// Waiting for the perspective
+    Perspective(CallFuture),
+    Done,
+}
+
+/// Perspective of the user, once logged in
+///
+/// This is driven by the broker receiving the answers, as `CallFuture`.
+#[derive(Debug)]
+pub struct LoginFuture {
+    step: Step,
+    mind: JellyValue,
+}
+
+impl LoginFuture {
+    pub(crate) fn new(broker: &Broker, credentials: &Credentials, mind: JellyValue) -> Self {
+        let root = broker.root_object();
+        let step = match *credentials {
+            Credentials::Anonymous => {
+                let args = [mind.clone()];
+                Step::Perspective(root.call_remote("loginAnonymous", &args, &[]))
+            }
+            Credentials::UsernamePassword {
+                ref username,
+                ref password,
+            } => {
+                let username = JellyValue::Bytes(username.clone());
+                Step::Challenge(root.call_remote("login", &[username], &[]), password.clone())
+            }
+        };
+        LoginFuture { step, mind }
+    }
+}
+
+/// The challenge and the challenger, jellied as a tuple
+fn challenge_from(answer: JellyValue) -> Result<(Vec<u8>, RemoteReference), LoginError> {
+    if let JellyValue::Tuple(ref items) = answer {
+        if let [JellyValue::Bytes(ref challenge), JellyValue::Remote(ref challenger)] =
+            items.borrow()[..]
+        {
+            return Ok((challenge.clone(), challenger.clone()));
+        }
+    }
+    Err(LoginError::UnexpectedAnswer(answer))
+}
+
+impl Future for LoginFuture {
+    type Output = Result<RemoteReference, LoginError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let res = match this.step {
+                Step::Challenge(ref mut fut, ref password) => match Pin::new(fut).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => {
+                        let mind = this.mind.clone();
+                        res.map_err(LoginError::from).and_then(challenge_from).map(
+                            |(challenge, challenger)| {
+                                let response = JellyValue::Bytes(respond(&challenge, password));
+                                let args = [response, mind];
+                                challenger.call_remote("respond", &args, &[])
+                            },
+                        )
+                    }
+                },
+                Step::Perspective(ref mut fut) => match Pin::new(fut).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => {
+                        this.step = Step::Done;
+                        return Poll::Ready(match res? {
+                            JellyValue::Remote(perspective) => Ok(perspective),
+                            other => Err(LoginError::UnexpectedAnswer(other)),
+                        });
+                    }
+                },
+                Step::Done => panic!("LoginFuture polled after completion"),
+            };
+            match res {
+                Ok(fut) => this.step = Step::Perspective(fut),
+                Err(err) => {
+                    this.step = Step::Done;
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl Broker {
+    /// Log in to the portal of the server, as Twisted's
+    /// `PBClientFactory.login`.
+    ///
+    /// `mind` is given to the server's realm along with the avatar, usually
+    /// for the server to call the client back.
+    pub fn login(
+        &self,
+        credentials: &Credentials,
+        mind: Option<Rc<dyn Referenceable>>,
+    ) -> LoginFuture {
+        let mind = match mind {
+            Some(mind) => JellyValue::Local(mind),
+            None => JellyValue::None,
+        };
+        LoginFuture::new(self, credentials, mind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use super::super::testing::{connected, run};
+    use super::super::{RemoteCall, Reply};
+
+    fn unicode(s: &str) -> JellyValue {
+        JellyValue::Unicode(s.into())
+    }
+
+    /// Twisted's `_PortalWrapper`, with a single user
+    #[derive(Default)]
+    struct Portal {
+        minds: RefCell<Vec<JellyValue>>,
+    }
+
+    impl Referenceable for Portal {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "remote_login" => {
+                    let challenger = Challenger {
+                        username: call.args[0].clone(),
+                    };
+                    Reply::value(JellyValue::tuple(vec![
+                        JellyValue::Bytes(b"0123456789abcdef".to_vec()),
+                        JellyValue::Local(Rc::new(challenger)),
+                    ]))
+                }
+                "remote_loginAnonymous" => {
+                    self.minds.borrow_mut().push(call.args[0].clone());
+                    Reply::value(JellyValue::Local(Rc::new(Avatar)))
+                }
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    struct Challenger {
+        username: JellyValue,
+    }
+
+    impl Referenceable for Challenger {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            let expected = respond(b"0123456789abcdef", b"pass");
+            match call.method.as_str() {
+                "remote_respond" => {
+                    if self.username != JellyValue::Bytes(b"worker".to_vec()) ||
+                        call.args[0] != JellyValue::Bytes(expected)
+                    {
+                        return Reply::failure(unicode("UnauthorizedLogin"));
+                    }
+                    Reply::value(JellyValue::Local(Rc::new(Avatar)))
+                }
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    struct Avatar;
+
+    impl Referenceable for Avatar {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            Reply::no_such_method(&call)
+        }
+    }
+
+    #[test]
+    fn response() {
+        // from Twisted's pb.respond(b"challenge", b"secret")
+        assert_eq!(
+            respond(b"challenge", b"secret"),
+            vec![
+                0xb3, 0x2c, 0x87, 0x76, 0xed, 0x4f, 0x07, 0xe0,
+                0x8d, 0x28, 0x88, 0xe4, 0x34, 0x6e, 0x55, 0x7f,
+            ]
+        );
+    }
+
+    #[test]
+    fn login() {
+        let (client, server) = connected();
+        server.set_root(Rc::new(Portal::default()));
+        let credentials = Credentials::username_password(b"worker", b"pass");
+        let perspective = run(&client, &server, client.login(&credentials, None)).unwrap();
+        assert_eq!(perspective.object_id(), &super::super::super::ObjectId::Number(2));
+        // the challenger is no longer referred to
+        assert_eq!(client.remote_reference_count(1), 0);
+
+        let credentials = Credentials::username_password(b"worker", b"wrong");
+        assert_eq!(
+            run(&client, &server, client.login(&credentials, None)),
+            Err(LoginError::Call(CallError::Failed(unicode("UnauthorizedLogin"))))
+        );
+    }
+
+    #[test]
+    fn anonymous() {
+        let (client, server) = connected();
+        let portal = Rc::new(Portal::default());
+        server.set_root(portal.clone());
+        let mind: Rc<dyn Referenceable> = Rc::new(Avatar);
+        let login = client.login(&Credentials::Anonymous, Some(mind));
+        assert!(run(&client, &server, login).is_ok());
+        let mind = portal.minds.borrow()[0].clone();
+        match mind {
+            JellyValue::Remote(ref mind) => assert!(mind.broker().is_connected()),
+            ref other => panic!("Expected the mind, got {:?}", other),
+        }
+    }
+}
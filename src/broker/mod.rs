@@ -0,0 +1,1724 @@
+//! Perspective Broker, as Twisted's `pb.Broker`
+//!
+//! This is sans-IO: the `Broker` is given the PB elements received once the
+//! dialect is negotiated (see `BananaConnection`), and the elements to send
+//! are retrieved with `take_outgoing()`.
+//!
+//! The broker keeps track of the requests waiting for an answer, of the local
+//! objects that the peer can call, by their local ids (luids), and of the
+//! references to the peer's objects that it received.
+//!
+//! Calls to the peer's objects are made through `RemoteReference`, starting
+//! with `Broker::root_object()`, and return futures. These are resolved as
+//! the broker receives the answers, hence they need no specific executor.
+//! Failures sent by Twisted are unjellied as `RemoteError`.
+//!
+//! Calls from the peer are dispatched to `Referenceable` objects. Those that
+//! answer asynchronously are driven by `Broker::poll_replies()`.
+//!
+//! Alternatively, calls and answers can be handled as `BrokerEvent`s: those
+//! to `Dispatch::Event` objects, and those of `Broker::send_message()`.
+//!
+//! `Cacheable` objects are copied to the peer, which keeps its `RemoteCache`
+//! up to date as told by the observers.
+//!
+//! Clients log in with `Broker::login()`, to servers whose root object is a
+//! `PortalRoot`.
+//!
+//! `Broker` is a handle: clones share the same state.
+
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use super::jelly::{
+    jelly_with, Invoker, JellyError, JellyValue, RemoteCopy, SecurityOptions, Unjellier,
+    UnjellyableRegistry,
+};
+use super::{Call, Element, MessageError, ObjectId, PBMessage, PerspectiveBroker, PB};
+
+mod cache;
+mod call;
+mod failure;
+mod login;
+mod portal;
+mod referenceable;
+mod remote;
+#[cfg(test)]
+mod testing;
+
+pub use self::cache::{CacheReference, Cacheable, RemoteCache, RemoteCacheObserver};
+pub use self::call::{CallError, CallFuture, CallResult};
+pub use self::failure::RemoteError;
+pub use self::login::{respond, Credentials, LoginError, LoginFuture};
+pub use self::portal::{
+    AllowAnonymousAccess, AvatarId, ChallengeResponse, ClientCredentials, CredError,
+    CredentialsChecker, FilePasswordDB, InMemoryUsernamePasswordDatabase, Portal, PortalRoot, Realm,
+};
+pub use self::referenceable::{Dispatch, Referenceable, RemoteCall, Reply, ReplyResult};
+pub use self::remote::RemoteReference;
+use self::cache::{cacheable_puid, WeakCache};
+use self::call::{resolve, PendingAnswer};
+use self::failure::COPYABLE_FAILURE;
+use self::referenceable::puid;
+use self::remote::WeakReference;
+
+/// Version of the protocol, as in Twisted's `Broker.version`
+pub const PROTOCOL_VERSION: i64 = 6;
+
+/// Jelly types of the broker itself, always allowed
+const BROKER_TYPES: &[&str] = &["remote", "local", "cached", "lcache"];
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BrokerError {
+    Message(MessageError),
+    Jelly(JellyError),
+    /// The peer speaks another version of the protocol
+    VersionMismatch(i64),
+    /// Answer or error for a request that isn't waiting for one
+    UnknownRequest(i64),
+    /// The peer referred to an object that we don't have
+    UnknownObject(ObjectId),
+    /// The broker can't be used after an error, or the loss of the connection
+    Closed,
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BrokerError::Message(ref err) => err.fmt(f),
+            BrokerError::Jelly(ref err) => err.fmt(f),
+            BrokerError::VersionMismatch(v) => {
+                write!(f, "version mismatch: peer has {}, we have {}", v, PROTOCOL_VERSION)
+            }
+            BrokerError::UnknownRequest(id) => write!(f, "unknown request id {}", id),
+            BrokerError::UnknownObject(ref id) => write!(f, "unknown object {:?}", id),
+            BrokerError::Closed => write!(f, "broker is closed"),
+        }
+    }
+}
+
+impl error::Error for BrokerError {}
+
+impl From<MessageError> for BrokerError {
+    fn from(err: MessageError) -> Self {
+        BrokerError::Message(err)
+    }
+}
+
+impl From<JellyError> for BrokerError {
+    fn from(err: JellyError) -> Self {
+        BrokerError::Jelly(err)
+    }
+}
+
+/// What can come out of a broker
+#[derive(Debug, PartialEq)]
+pub enum BrokerEvent {
+    /// The peer speaks our version of the protocol, happens once
+    Connected,
+    /// The peer didn't understand a command that we sent, as Twisted's
+    /// `proto_didNotUnderstand`, which only logs it
+    NotUnderstood(Vec<u8>),
+    /// Call to one of our `Dispatch::Event` objects, to be answered if
+    /// required
+    Call(IncomingCall),
+    /// Answer to a request of `send_message()`
+    Answer {
+        request_id: i64,
+        result: JellyValue,
+    },
+    /// Failure of a request of `send_message()`
+    Error {
+        request_id: i64,
+        failure: JellyValue,
+    },
+}
+
+/// Unjellied call to one of our objects
+#[derive(Debug, PartialEq)]
+pub struct IncomingCall {
+    pub request_id: i64,
+    pub object_id: ObjectId,
+    pub method: String,
+    pub args: Vec<JellyValue>,
+    /// Keyword arguments, in their order of appearance
+    pub kwargs: Vec<(String, JellyValue)>,
+    pub answer_required: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Status {
+    /// Waiting for the version of the peer
+    Connecting,
+    Connected,
+    Closed,
+}
+
+/// Object that the peer can refer to, with the number of times it was sent
+#[derive(Debug, Clone)]
+struct Local {
+    object: Rc<dyn Referenceable>,
+    /// For `view_` methods, as Twisted's `ViewPoint`
+    perspective: Option<Rc<dyn Referenceable>>,
+    refcount: usize,
+}
+
+impl Local {
+    /// Identity of the object, together with its perspective
+    fn puid(&self) -> (usize, usize) {
+        (puid(&self.object), self.perspective.as_ref().map(puid).unwrap_or(0))
+    }
+
+    /// The perspective for the results of the calls to the object
+    fn serializing_perspective(&self) -> Option<Rc<dyn Referenceable>> {
+        match self.object.dispatch() {
+            Dispatch::Perspective => Some(self.object.clone()),
+            _ => self.perspective.clone(),
+        }
+    }
+}
+
+/// Reference to an object of the peer, with the number of times we received it
+#[derive(Debug)]
+struct Remote {
+    reference: WeakReference,
+    receipts: usize,
+}
+
+/// Object cached by the peer, with the number of times it was sent
+struct Cached {
+    object: Rc<dyn Cacheable>,
+    perspective: Option<Rc<dyn Referenceable>>,
+    refcount: usize,
+}
+
+impl fmt::Debug for Cached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cached({:?}, {})", self.object, self.refcount)
+    }
+}
+
+/// Copy of an object of the peer, with the number of times we received it
+/// since the last decache
+#[derive(Debug)]
+struct LocalCache {
+    object: Rc<dyn RemoteCache>,
+    reference: WeakCache,
+    receipts: usize,
+}
+
+type CacheFactory = Rc<dyn Fn(JellyValue) -> Result<Rc<dyn RemoteCache>, JellyError>>;
+
+/// Builds the copies of the instances of a class
+#[derive(Clone)]
+struct CacheClass(CacheFactory);
+
+impl fmt::Debug for CacheClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CacheClass")
+    }
+}
+
+/// For the loss of the connection, or the cleanup of a local object
+struct Callback(Box<dyn FnOnce()>);
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Callback")
+    }
+}
+
+/// Object of the peer that a call is sent to
+struct Target {
+    /// `PBMessage::Message`, or `PBMessage::CacheMessage`
+    kind: fn(Call) -> PBMessage,
+    object_id: ObjectId,
+    /// For the arguments, as Twisted's `serializingPerspective`
+    perspective: Option<Rc<dyn Referenceable>>,
+}
+
+impl Target {
+    fn object(object_id: ObjectId) -> Self {
+        Target {
+            kind: PBMessage::Message,
+            object_id,
+            perspective: None,
+        }
+    }
+
+    fn cache(luid: i64, perspective: Option<Rc<dyn Referenceable>>) -> Self {
+        Target {
+            kind: PBMessage::CacheMessage,
+            object_id: ObjectId::Number(luid),
+            perspective,
+        }
+    }
+}
+
+/// Who gets the answer to a request
+#[derive(Debug)]
+enum Waiting {
+    /// The `CallFuture` of `call_remote()`
+    Future(PendingAnswer),
+    /// The application, as an `Answer` or `Error` event
+    Event,
+}
+
+/// Answer to a call, in the making
+struct PendingReply {
+    request_id: Option<i64>,
+    perspective: Option<Rc<dyn Referenceable>>,
+    reply: Pin<Box<dyn Future<Output = ReplyResult>>>,
+}
+
+impl fmt::Debug for PendingReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PendingReply({:?})", self.request_id)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    status: Status,
+    security: SecurityOptions,
+    registry: UnjellyableRegistry,
+    outgoing: Vec<PerspectiveBroker>,
+    current_request_id: i64,
+    waiting_for_answers: HashMap<i64, Waiting>,
+    current_local_id: i64,
+    local_objects: HashMap<ObjectId, Local>,
+    /// Local ids of the registered objects, by their identity
+    luids: HashMap<(usize, usize), i64>,
+    replies: Vec<PendingReply>,
+    remote_references: HashMap<i64, Remote>,
+    /// References with callbacks for the loss of the connection
+    disconnect_notified: Vec<WeakReference>,
+    disconnect_callbacks: Vec<Callback>,
+    /// Callbacks for when the peer no longer refers to a local object, by
+    /// its identity
+    local_cleanups: HashMap<(usize, usize), Callback>,
+    /// Objects cached by the peer, by their luid
+    remotely_cached: HashMap<i64, Cached>,
+    /// Luids of the objects cached by the peer, by their identity
+    cached_luids: HashMap<usize, i64>,
+    /// Copies of the peer's objects, by their id on the peer side
+    locally_cached: HashMap<i64, LocalCache>,
+    cache_classes: HashMap<String, CacheClass>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Broker {
+    state: Rc<RefCell<State>>,
+    /// Luids of the references dropped while the state was in use, released
+    /// on its next use
+    released: Rc<RefCell<Vec<i64>>>,
+    /// Same for the copies of the peer's objects
+    released_caches: Rc<RefCell<Vec<i64>>>,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::with_security(SecurityOptions::default())
+    }
+}
+
+/// (Un)jellying of the broker's own types
+#[derive(Debug)]
+struct BrokerInvoker {
+    broker: Broker,
+    /// Perspective of the peer, for the `Viewable` and `Copyable` objects to
+    /// send
+    perspective: Option<Rc<dyn Referenceable>>,
+}
+
+/// Type name of an s-expression, possibly abbreviated
+fn type_name(sexp: &[PerspectiveBroker]) -> Option<&[u8]> {
+    match sexp.first() {
+        Some(Element::String(s)) => Some(s),
+        Some(Element::Extension(pb)) => Some(pb.token()),
+        _ => None,
+    }
+}
+
+impl Invoker for BrokerInvoker {
+    fn unjelly(
+        &self,
+        unjellier: &mut Unjellier,
+        sexp: &[PerspectiveBroker],
+    ) -> Option<Result<JellyValue, JellyError>> {
+        let luid = match sexp.get(1) {
+            Some(&Element::Integer(luid)) => Some(luid as i64),
+            _ => None,
+        };
+        let invalid = || Err(JellyError::Malformed(format!("Invalid reference {:?}", sexp)));
+        let name = type_name(sexp)?;
+        // new copies are sent as [class, luid, state], as with Twisted's
+        // `Cacheable.jellyFor`, whereas instances have no luid
+        if name.contains(&b'.') {
+            return match (luid, sexp.len()) {
+                (Some(luid), 3) => Some(
+                    self.broker
+                        .received_cache(unjellier, luid, &String::from_utf8_lossy(name), &sexp[2])
+                        .map(JellyValue::Cached),
+                ),
+                _ => None,
+            };
+        }
+        Some(match (name, luid, sexp.len()) {
+            (b"remote", Some(luid), 2) => Ok(JellyValue::Remote(self.broker.received_remote(luid))),
+            (b"local", luid, 2) => {
+                // the root object, or the other ones named by the peer, are
+                // sent back by their name
+                let id = match (luid, &sexp[1]) {
+                    (Some(luid), _) => ObjectId::Number(luid),
+                    (None, Element::String(name)) => ObjectId::Name(name.clone()),
+                    _ => return Some(invalid()),
+                };
+                match self.broker.local_object(&id) {
+                    Some(object) => Ok(JellyValue::Local(object)),
+                    None => Err(JellyError::Malformed(format!("Unknown local object {:?}", id))),
+                }
+            }
+            (b"cached", Some(luid), 2) => self.broker.received_cached(luid).map(JellyValue::Cached),
+            (b"lcache", Some(luid), 2) => match self.broker.remotely_cached(luid) {
+                Some(object) => Ok(JellyValue::Cacheable(object)),
+                None => Err(JellyError::Malformed(format!("Unknown cached object {}", luid))),
+            },
+            (b"remote", _, _) | (b"local", _, _) | (b"cached", _, _) | (b"lcache", _, _) => {
+                invalid()
+            }
+            _ => return None,
+        })
+    }
+
+    fn jelly(&self, value: &JellyValue) -> Option<PerspectiveBroker> {
+        match *value {
+            JellyValue::Local(ref object) => {
+                let perspective = match object.dispatch() {
+                    Dispatch::View => self.perspective.clone(),
+                    _ => None,
+                };
+                let luid = self.broker.register_local(object.clone(), perspective);
+                Some(Element::List(vec![
+                    Element::Extension(PB::Remote),
+                    Element::integer(luid as i128),
+                ]))
+            }
+            JellyValue::Cacheable(ref object) => {
+                Some(self.broker.cache_remotely(object, self.perspective.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn serializing_perspective(&self) -> Option<Rc<dyn Referenceable>> {
+        self.perspective.clone()
+    }
+}
+
+/// Positional arguments, jellied as a tuple
+fn args_from(value: JellyValue) -> Result<Vec<JellyValue>, JellyError> {
+    match value {
+        JellyValue::Tuple(items) | JellyValue::List(items) => Ok(items.borrow().clone()),
+        other => Err(JellyError::Malformed(format!("Expected arguments, got {:?}", other))),
+    }
+}
+
+/// Keyword arguments, jellied as a dictionary with string keys
+fn kwargs_from(value: JellyValue) -> Result<Vec<(String, JellyValue)>, JellyError> {
+    let items = match value {
+        JellyValue::Dict(items) => items.borrow().clone(),
+        other => {
+            return Err(JellyError::Malformed(format!("Expected keyword arguments, got {:?}", other)))
+        }
+    };
+    items
+        .into_iter()
+        .map(|(k, v)| match k {
+            JellyValue::Unicode(k) => Ok((k, v)),
+            JellyValue::Bytes(ref b) => match String::from_utf8(b.clone()) {
+                Ok(k) => Ok((k, v)),
+                Err(_) => Err(JellyError::Malformed(format!("Invalid keyword {:?}", k))),
+            },
+            k => Err(JellyError::Malformed(format!("Invalid keyword {:?}", k))),
+        })
+        .collect()
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The broker's own types are allowed in addition to those of `security`,
+    /// and the failures sent by Twisted are unjellied as `RemoteError`.
+    /// Containers referring to themselves are rejected, unless `security`
+    /// allows cycles: nothing would free them.
+    ///
+    /// The version is immediately ready to be sent.
+    pub fn with_security(mut security: SecurityOptions) -> Self {
+        security.allow_types(BROKER_TYPES);
+        let mut registry = UnjellyableRegistry::new();
+        registry.register::<RemoteError>(COPYABLE_FAILURE);
+        let broker = Broker {
+            state: Rc::new(RefCell::new(State {
+                status: Status::Connecting,
+                security,
+                registry,
+                outgoing: Vec::new(),
+                current_request_id: 0,
+                waiting_for_answers: HashMap::new(),
+                current_local_id: 0,
+                local_objects: HashMap::new(),
+                luids: HashMap::new(),
+                replies: Vec::new(),
+                remote_references: HashMap::new(),
+                disconnect_notified: Vec::new(),
+                disconnect_callbacks: Vec::new(),
+                local_cleanups: HashMap::new(),
+                remotely_cached: HashMap::new(),
+                cached_luids: HashMap::new(),
+                locally_cached: HashMap::new(),
+                cache_classes: HashMap::new(),
+            })),
+            released: Rc::new(RefCell::new(Vec::new())),
+            released_caches: Rc::new(RefCell::new(Vec::new())),
+        };
+        broker.send(PBMessage::Version(PROTOCOL_VERSION));
+        broker
+    }
+
+    /// `true` once the peer sent its version
+    pub fn is_connected(&self) -> bool {
+        self.state.borrow().status == Status::Connected
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.borrow().status == Status::Closed
+    }
+
+    /// Elements to be sent to the peer
+    pub fn take_outgoing(&self) -> Vec<PerspectiveBroker> {
+        mem::take(&mut self.state_releasing().outgoing)
+    }
+
+    fn send(&self, msg: PBMessage) {
+        self.state.borrow_mut().outgoing.push(msg.build());
+    }
+
+    fn check_open(&self) -> Result<(), BrokerError> {
+        if self.is_closed() {
+            return Err(BrokerError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Unjelly the instances of `class` sent by the peer as `T`, as Twisted's
+    /// `setUnjellyableForClass`, which also allows them.
+    pub fn set_unjellyable_for_class<T: RemoteCopy>(&self, class: &str) {
+        let mut state = self.state.borrow_mut();
+        state.registry.register::<T>(class);
+        state.security.allow_instances_of(&[class]);
+    }
+
+    /// Copy the instances of `class` sent by the peer as `T`, as Twisted's
+    /// `setUnjellyableForClass` does for `RemoteCache` subclasses.
+    pub fn set_unjellyable_for_cache<T: RemoteCache>(&self, class: &str) {
+        let factory = CacheClass(Rc::new(|state| {
+            T::set_copyable_state(state).map(|v| Rc::new(v) as Rc<dyn RemoteCache>)
+        }));
+        self.state.borrow_mut().cache_classes.insert(class.to_owned(), factory);
+    }
+
+    /// Publish the object that peers get first, i.e., `getRootObject()`
+    pub fn set_root(&self, object: Rc<dyn Referenceable>) {
+        self.set_name_for_local(b"root", object);
+    }
+
+    pub fn set_name_for_local(&self, name: &[u8], object: Rc<dyn Referenceable>) {
+        self.state.borrow_mut().local_objects.insert(
+            ObjectId::Name(name.to_vec()),
+            Local {
+                object,
+                perspective: None,
+                refcount: 1,
+            },
+        );
+    }
+
+    /// Local id of an object about to be sent to the peer.
+    ///
+    /// Each registration must be balanced by a decref from the peer. This is
+    /// done when jellying `JellyValue::Local`.
+    pub fn register_reference(&self, object: Rc<dyn Referenceable>) -> i64 {
+        self.register_local(object, None)
+    }
+
+    fn register_local(
+        &self,
+        object: Rc<dyn Referenceable>,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) -> i64 {
+        let mut state = self.state.borrow_mut();
+        let local = Local {
+            object,
+            perspective,
+            refcount: 1,
+        };
+        let puid = local.puid();
+        if let Some(&luid) = state.luids.get(&puid) {
+            if let Some(local) = state.local_objects.get_mut(&ObjectId::Number(luid)) {
+                local.refcount += 1;
+            }
+            return luid;
+        }
+        state.current_local_id += 1;
+        let luid = state.current_local_id;
+        state.local_objects.insert(ObjectId::Number(luid), local);
+        state.luids.insert(puid, luid);
+        luid
+    }
+
+    pub fn local_object(&self, id: &ObjectId) -> Option<Rc<dyn Referenceable>> {
+        self.state.borrow().local_objects.get(id).map(|local| local.object.clone())
+    }
+
+    fn decref_local(&self, id: ObjectId) -> Result<(), BrokerError> {
+        let mut state = self.state.borrow_mut();
+        let refcount = match state.local_objects.get_mut(&id) {
+            Some(local) => {
+                local.refcount -= 1;
+                local.refcount
+            }
+            None => return Err(BrokerError::UnknownObject(id)),
+        };
+        if refcount == 0 {
+            if let Some(local) = state.local_objects.remove(&id) {
+                state.luids.remove(&local.puid());
+                let cleanup = state.local_cleanups.remove(&local.puid());
+                // the object may hold remote references, which need the state
+                drop(state);
+                drop(local);
+                if let Some(Callback(cleanup)) = cleanup {
+                    cleanup();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Have `cleanup` called once the peer no longer refers to `object`, as
+    /// Twisted's `Broker._localCleanup`.
+    pub(crate) fn notify_on_local_cleanup<F: FnOnce() + 'static>(
+        &self,
+        object: &Rc<dyn Referenceable>,
+        cleanup: F,
+    ) {
+        let puid = (puid(object), 0);
+        self.state.borrow_mut().local_cleanups.insert(puid, Callback(Box::new(cleanup)));
+    }
+
+    /// Number of times we received the peer's object of id `luid`, while
+    /// its `RemoteReference` is alive
+    pub fn remote_reference_count(&self, luid: i64) -> usize {
+        self.state_releasing().remote_references.get(&luid).map(|r| r.receipts).unwrap_or(0)
+    }
+
+    /// Tell the peer that one of the references that we received for `luid`
+    /// is no longer used, without waiting for its `RemoteReference` to be
+    /// dropped.
+    pub fn decref(&self, luid: i64) {
+        let mut state = self.state.borrow_mut();
+        if state.status == Status::Closed {
+            return;
+        }
+        match state.remote_references.get_mut(&luid) {
+            Some(remote) if remote.receipts > 0 => remote.receipts -= 1,
+            _ => return,
+        }
+        state.outgoing.push(PBMessage::DecRef(luid).build());
+    }
+
+    /// The reference for `luid`, the same as long as one is alive
+    fn received_remote(&self, luid: i64) -> RemoteReference {
+        let mut state = self.state_releasing();
+        if let Some(remote) = state.remote_references.get_mut(&luid) {
+            if let Some(reference) = remote.reference.upgrade() {
+                remote.receipts += 1;
+                return reference;
+            }
+        }
+        let reference = RemoteReference::new(self.clone(), ObjectId::Number(luid));
+        state.remote_references.insert(
+            luid,
+            Remote {
+                reference: reference.downgrade(),
+                receipts: 1,
+            },
+        );
+        reference
+    }
+
+    /// The last clone of the reference for `luid` was dropped: the peer gets
+    /// a decref for each time it sent it.
+    fn release_remote(&self, luid: i64) {
+        match self.state.try_borrow_mut() {
+            Ok(mut state) => Self::send_decrefs(&mut state, luid),
+            // the reference was dropped while the state is in use
+            Err(_) => self.released.borrow_mut().push(luid),
+        }
+    }
+
+    /// The state, once the references and copies dropped while it was in
+    /// use are released
+    fn state_releasing(&self) -> RefMut<'_, State> {
+        let mut state = self.state.borrow_mut();
+        for luid in mem::take(&mut *self.released.borrow_mut()) {
+            Self::send_decrefs(&mut state, luid);
+        }
+        for luid in mem::take(&mut *self.released_caches.borrow_mut()) {
+            Self::send_decaches(&mut state, luid);
+        }
+        state
+    }
+
+    fn send_decrefs(state: &mut State, luid: i64) {
+        let receipts = match state.remote_references.get(&luid) {
+            // received again meanwhile
+            Some(remote) if remote.reference.upgrade().is_some() => return,
+            Some(remote) => remote.receipts,
+            None => return,
+        };
+        state.remote_references.remove(&luid);
+        if state.status != Status::Closed {
+            for _ in 0..receipts {
+                state.outgoing.push(PBMessage::DecRef(luid).build());
+            }
+        }
+    }
+
+    fn notify_reference_on_disconnect(&self, reference: WeakReference) {
+        self.state.borrow_mut().disconnect_notified.push(reference);
+    }
+
+    /// Have `callback` called when the connection is lost, or right away if
+    /// it already is, as Twisted's `Broker.notifyOnDisconnect`.
+    pub fn notify_on_disconnect<F: FnOnce() + 'static>(&self, callback: F) {
+        if self.is_closed() {
+            return callback();
+        }
+        self.state.borrow_mut().disconnect_callbacks.push(Callback(Box::new(callback)));
+    }
+
+    /// Jelly a cacheable: its state the first time, its luid afterwards
+    fn cache_remotely(
+        &self,
+        object: &Rc<dyn Cacheable>,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) -> PerspectiveBroker {
+        let luid = {
+            let mut state = self.state.borrow_mut();
+            let puid = cacheable_puid(object);
+            if let Some(&luid) = state.cached_luids.get(&puid) {
+                if let Some(cached) = state.remotely_cached.get_mut(&luid) {
+                    cached.refcount += 1;
+                }
+                return Element::List(vec![
+                    Element::Extension(PB::Cached),
+                    Element::integer(luid as i128),
+                ]);
+            }
+            state.current_local_id += 1;
+            let luid = state.current_local_id;
+            state.remotely_cached.insert(
+                luid,
+                Cached {
+                    object: object.clone(),
+                    perspective: perspective.clone(),
+                    refcount: 1,
+                },
+            );
+            state.cached_luids.insert(puid, luid);
+            luid
+        };
+        let observer = RemoteCacheObserver::new(self.clone(), luid, perspective.clone());
+        let class = object.type_to_copy_for(perspective.as_ref());
+        let state = object.state_to_cache_and_observe_for(perspective.as_ref(), observer);
+        Element::List(vec![
+            Element::String(class.into_bytes()),
+            Element::integer(luid as i128),
+            self.serialize(&state, perspective),
+        ])
+    }
+
+    fn remotely_cached(&self, luid: i64) -> Option<Rc<dyn Cacheable>> {
+        self.state.borrow().remotely_cached.get(&luid).map(|cached| cached.object.clone())
+    }
+
+    /// The peer no longer uses one of the copies we sent of `luid`: once
+    /// none is left, the object stops being observed, and the peer can
+    /// forget about it.
+    fn decache(&self, luid: i64) -> Result<(), BrokerError> {
+        let cached = {
+            let mut state = self.state.borrow_mut();
+            match state.remotely_cached.get_mut(&luid) {
+                Some(cached) if cached.refcount > 1 => {
+                    cached.refcount -= 1;
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => return Err(BrokerError::UnknownObject(ObjectId::Number(luid))),
+            }
+            let cached = state.remotely_cached.remove(&luid).unwrap();
+            state.cached_luids.remove(&cacheable_puid(&cached.object));
+            cached
+        };
+        self.stopped_observing(luid, &cached);
+        self.send(PBMessage::UnCache(luid));
+        Ok(())
+    }
+
+    fn stopped_observing(&self, luid: i64, cached: &Cached) {
+        let observer = RemoteCacheObserver::new(self.clone(), luid, cached.perspective.clone());
+        cached.object.stopped_observing(cached.perspective.as_ref(), &observer);
+    }
+
+    /// A new copy of an object of the peer
+    fn received_cache(
+        &self,
+        unjellier: &mut Unjellier,
+        luid: i64,
+        class: &str,
+        state: &PerspectiveBroker,
+    ) -> Result<CacheReference, JellyError> {
+        let factory = self.state.borrow().cache_classes.get(class).cloned();
+        let object = match factory {
+            Some(CacheClass(factory)) => factory(unjellier.unjelly(state)?)?,
+            None => return Err(JellyError::Insecure(format!("Class not cacheable: {}", class))),
+        };
+        let reference = CacheReference::new(self.clone(), luid, object.clone());
+        let previous = self.state.borrow_mut().locally_cached.insert(
+            luid,
+            LocalCache {
+                object,
+                reference: reference.downgrade(),
+                receipts: 1,
+            },
+        );
+        drop(previous);
+        Ok(reference)
+    }
+
+    /// A copy that we already have, still alive on the peer's side
+    fn received_cached(&self, luid: i64) -> Result<CacheReference, JellyError> {
+        let mut state = self.state_releasing();
+        let local = match state.locally_cached.get_mut(&luid) {
+            Some(local) => local,
+            None => return Err(JellyError::Malformed(format!("Unknown cached object {}", luid))),
+        };
+        local.receipts += 1;
+        if let Some(reference) = local.reference.upgrade() {
+            return Ok(reference);
+        }
+        // decached, but not uncached yet
+        let reference = CacheReference::new(self.clone(), luid, local.object.clone());
+        local.reference = reference.downgrade();
+        Ok(reference)
+    }
+
+    /// The last clone of the copy of `luid` was dropped: the peer gets a
+    /// decache for each time it sent it.
+    ///
+    /// The copy is kept until the peer uncaches it, as it may still be
+    /// observed meanwhile.
+    fn release_cache(&self, luid: i64) {
+        match self.state.try_borrow_mut() {
+            Ok(mut state) => Self::send_decaches(&mut state, luid),
+            // the copy was dropped while the state is in use
+            Err(_) => self.released_caches.borrow_mut().push(luid),
+        }
+    }
+
+    fn send_decaches(state: &mut State, luid: i64) {
+        let receipts = match state.locally_cached.get_mut(&luid) {
+            // received again meanwhile
+            Some(local) if local.reference.upgrade().is_some() => return,
+            Some(local) => mem::replace(&mut local.receipts, 0),
+            None => return,
+        };
+        if state.status != Status::Closed {
+            for _ in 0..receipts {
+                state.outgoing.push(PBMessage::DeCache(luid).build());
+            }
+        }
+    }
+
+    fn uncache(&self, luid: i64) -> Result<(), BrokerError> {
+        let local = self.state.borrow_mut().locally_cached.remove(&luid);
+        match local {
+            Some(_) => Ok(()),
+            None => Err(BrokerError::UnknownObject(ObjectId::Number(luid))),
+        }
+    }
+
+    /// The root object of the peer, as Twisted's `remoteForName("root")`
+    pub fn root_object(&self) -> RemoteReference {
+        self.remote_for_name(b"root")
+    }
+
+    pub fn remote_for_name(&self, name: &[u8]) -> RemoteReference {
+        RemoteReference::new(self.clone(), ObjectId::Name(name.to_vec()))
+    }
+
+    fn call_remote(
+        &self,
+        target: Target,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+    ) -> CallFuture {
+        let (fut, answer) = CallFuture::new();
+        match self.send_call(target, method, args, kwargs, Some(Waiting::Future(answer))) {
+            Ok(_) => fut,
+            Err(_) => CallFuture::ready(Err(CallError::DeadReference)),
+        }
+    }
+
+    /// Call a method on an object of the peer, returning the request id.
+    ///
+    /// Unless `answer_required` is `false`, the outcome comes as an
+    /// `Answer` or `Error` event with that request id.
+    pub fn send_message(
+        &self,
+        object_id: ObjectId,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+        answer_required: bool,
+    ) -> Result<i64, BrokerError> {
+        let waiting = if answer_required {
+            Some(Waiting::Event)
+        } else {
+            None
+        };
+        self.send_call(Target::object(object_id), method, args, kwargs, waiting)
+    }
+
+    /// Answer an `IncomingCall`
+    pub fn answer(&self, request_id: i64, result: &JellyValue) -> Result<(), BrokerError> {
+        self.check_open()?;
+        self.send_reply(Some(request_id), Ok(result.clone()), None);
+        Ok(())
+    }
+
+    /// Fail an `IncomingCall`
+    pub fn error(&self, request_id: i64, failure: &JellyValue) -> Result<(), BrokerError> {
+        self.check_open()?;
+        self.send_reply(Some(request_id), Err(failure.clone()), None);
+        Ok(())
+    }
+
+    /// Send a `message`, or a `cachemessage`, returning its request id.
+    ///
+    /// The answer is waited for unless `waiting` is `None`.
+    fn send_call(
+        &self,
+        target: Target,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+        waiting: Option<Waiting>,
+    ) -> Result<i64, BrokerError> {
+        self.check_open()?;
+        let Target {
+            kind,
+            object_id,
+            perspective,
+        } = target;
+        let args = self.serialize(&JellyValue::tuple(args.to_vec()), perspective.clone());
+        let kwargs = self.serialize(
+            &JellyValue::dict(
+                kwargs
+                    .iter()
+                    .map(|(k, v)| (JellyValue::Unicode(k.to_string()), v.clone()))
+                    .collect(),
+            ),
+            perspective,
+        );
+        let answer_required = waiting.is_some();
+        let request_id = {
+            let mut state = self.state.borrow_mut();
+            state.current_request_id += 1;
+            let request_id = state.current_request_id;
+            if let Some(waiting) = waiting {
+                state.waiting_for_answers.insert(request_id, waiting);
+            }
+            request_id
+        };
+        self.send(kind(Call {
+            request_id,
+            object_id,
+            method: method.as_bytes().to_vec(),
+            answer_required,
+            args,
+            kwargs,
+        }));
+        Ok(request_id)
+    }
+
+    /// Drive the replies of the local objects that are not ready yet,
+    /// sending those that become ready.
+    ///
+    /// This is to be called again whenever the waker of `cx` is woken.
+    pub fn poll_replies(&self, cx: &mut Context) {
+        let replies = mem::take(&mut self.state.borrow_mut().replies);
+        let mut pending = Vec::new();
+        for mut reply in replies {
+            match reply.reply.as_mut().poll(cx) {
+                Poll::Ready(res) => self.send_reply(reply.request_id, res, reply.perspective),
+                Poll::Pending => pending.push(reply),
+            }
+        }
+        let mut state = self.state.borrow_mut();
+        if state.status != Status::Closed {
+            // replies can be added while polling
+            pending.append(&mut state.replies);
+            state.replies = pending;
+        }
+    }
+
+    /// Number of replies that `poll_replies()` has yet to drive
+    pub fn pending_replies(&self) -> usize {
+        self.state.borrow().replies.len()
+    }
+
+    /// The transport is gone, nothing can be sent nor received anymore.
+    ///
+    /// The calls waiting for an answer fail.
+    pub fn connection_lost(&self) {
+        // dropped once the state is no longer borrowed
+        let (waiting, _locals, _replies, notified, callbacks, _cleanups, cached, _copies) = {
+            let mut state = self.state.borrow_mut();
+            state.status = Status::Closed;
+            state.luids.clear();
+            state.remote_references.clear();
+            state.cached_luids.clear();
+            (
+                mem::take(&mut state.waiting_for_answers),
+                mem::take(&mut state.local_objects),
+                mem::take(&mut state.replies),
+                mem::take(&mut state.disconnect_notified),
+                mem::take(&mut state.disconnect_callbacks),
+                mem::take(&mut state.local_cleanups),
+                mem::take(&mut state.remotely_cached),
+                mem::take(&mut state.locally_cached),
+            )
+        };
+        for waiting in waiting.values() {
+            if let Waiting::Future(ref answer) = *waiting {
+                resolve(answer, Err(CallError::ConnectionLost));
+            }
+        }
+        for (&luid, cached) in &cached {
+            self.stopped_observing(luid, cached);
+        }
+        for reference in notified.iter().filter_map(WeakReference::upgrade) {
+            reference.disconnected();
+        }
+        for Callback(callback) in callbacks {
+            callback();
+        }
+    }
+
+    /// Process an element received from the peer.
+    ///
+    /// Any error is final: the connection should be dropped.
+    pub fn receive(&self, elt: &PerspectiveBroker) -> Result<Option<BrokerEvent>, BrokerError> {
+        self.check_open()?;
+        let res = self.process(elt);
+        if res.is_err() {
+            self.connection_lost();
+        }
+        res
+    }
+
+    fn process(&self, elt: &PerspectiveBroker) -> Result<Option<BrokerEvent>, BrokerError> {
+        let msg = match PBMessage::parse(elt) {
+            Ok(msg) => msg,
+            Err(MessageError::UnknownCommand(cmd)) => {
+                self.send(PBMessage::DidNotUnderstand(cmd));
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match msg {
+            PBMessage::Version(v) => {
+                if v != PROTOCOL_VERSION {
+                    return Err(BrokerError::VersionMismatch(v));
+                }
+                self.state.borrow_mut().status = Status::Connected;
+                Ok(Some(BrokerEvent::Connected))
+            }
+            PBMessage::Message(call) => Ok(self.receive_call(call)),
+            PBMessage::CacheMessage(call) => {
+                self.receive_cache_message(call);
+                Ok(None)
+            }
+            PBMessage::Answer { request_id, result } => {
+                self.receive_answer(request_id, &result, false)
+            }
+            PBMessage::Error {
+                request_id,
+                failure,
+            } => self.receive_answer(request_id, &failure, true),
+            PBMessage::DecRef(luid) => {
+                self.decref_local(ObjectId::Number(luid))?;
+                Ok(None)
+            }
+            PBMessage::DeCache(luid) => {
+                self.decache(luid)?;
+                Ok(None)
+            }
+            PBMessage::UnCache(luid) => {
+                self.uncache(luid)?;
+                Ok(None)
+            }
+            PBMessage::DidNotUnderstand(cmd) => Ok(Some(BrokerEvent::NotUnderstood(cmd))),
+        }
+    }
+
+    /// Resolve the future waiting for `request_id`, or make an event of the
+    /// answer, or of the failure if `failed`.
+    fn receive_answer(
+        &self,
+        request_id: i64,
+        elt: &PerspectiveBroker,
+        failed: bool,
+    ) -> Result<Option<BrokerEvent>, BrokerError> {
+        let waiting = match self.state.borrow_mut().waiting_for_answers.remove(&request_id) {
+            Some(waiting) => waiting,
+            None => return Err(BrokerError::UnknownRequest(request_id)),
+        };
+        let value = self.unserialize(elt);
+        let answer = match waiting {
+            Waiting::Future(answer) => answer,
+            Waiting::Event if failed => {
+                return Ok(Some(BrokerEvent::Error {
+                    request_id,
+                    failure: value?,
+                }))
+            }
+            Waiting::Event => {
+                return Ok(Some(BrokerEvent::Answer {
+                    request_id,
+                    result: value?,
+                }))
+            }
+        };
+        match value {
+            Ok(value) if failed => resolve(&answer, Err(CallError::Failed(value))),
+            Ok(value) => resolve(&answer, Ok(value)),
+            Err(err) => {
+                // the connection is about to be dropped
+                resolve(&answer, Err(CallError::ConnectionLost));
+                return Err(err.into());
+            }
+        }
+        Ok(None)
+    }
+
+    fn invoker(&self, perspective: Option<Rc<dyn Referenceable>>) -> BrokerInvoker {
+        BrokerInvoker {
+            broker: self.clone(),
+            perspective,
+        }
+    }
+
+    fn unserialize(&self, elt: &PerspectiveBroker) -> Result<JellyValue, JellyError> {
+        let (security, registry) = {
+            let state = self.state.borrow();
+            (state.security.clone(), state.registry.clone())
+        };
+        let mut unjellier = Unjellier::with_invoker(security, Rc::new(self.invoker(None)));
+        unjellier.set_registry(registry);
+        unjellier.unjelly(elt)
+    }
+
+    fn serialize(
+        &self,
+        value: &JellyValue,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) -> PerspectiveBroker {
+        jelly_with(value, &self.invoker(perspective))
+    }
+
+    /// Dispatch a call to the local object, or make an event of it.
+    ///
+    /// Calls that can't be unjellied, or to unknown objects, are answered
+    /// with an error, as Twisted does.
+    fn receive_call(&self, call: Call) -> Option<BrokerEvent> {
+        let request_id = if call.answer_required {
+            Some(call.request_id)
+        } else {
+            None
+        };
+        let local = self.state.borrow().local_objects.get(&call.object_id).cloned();
+        let local = match local {
+            Some(local) => local,
+            None => {
+                let failure = RemoteError::pb_error("Invalid Object ID").into();
+                self.send_reply(request_id, Err(failure), None);
+                return None;
+            }
+        };
+        let dispatch = local.object.dispatch();
+        let remote_call =
+            match self.unserialize_call(&call, dispatch.prefix(), local.perspective.clone()) {
+                Ok(remote_call) => remote_call,
+                Err(err) => {
+                    let failure = RemoteError::pb_error(&err.to_string()).into();
+                    self.send_reply(request_id, Err(failure), None);
+                    return None;
+                }
+            };
+        if dispatch == Dispatch::Event {
+            return Some(BrokerEvent::Call(IncomingCall {
+                request_id: call.request_id,
+                object_id: call.object_id,
+                method: remote_call.method,
+                args: remote_call.args,
+                kwargs: remote_call.kwargs,
+                answer_required: call.answer_required,
+            }));
+        }
+        let reply = local.object.remote_message_received(remote_call);
+        self.reply(request_id, reply, local.serializing_perspective());
+        None
+    }
+
+    /// Dispatch a change to the copy of a cacheable, as `observe_<method>`
+    fn receive_cache_message(&self, call: Call) {
+        let request_id = if call.answer_required {
+            Some(call.request_id)
+        } else {
+            None
+        };
+        let object = match call.object_id {
+            ObjectId::Number(luid) => {
+                self.state.borrow().locally_cached.get(&luid).map(|local| local.object.clone())
+            }
+            ObjectId::Name(_) => None,
+        };
+        let object = match object {
+            Some(object) => object,
+            None => {
+                let failure = RemoteError::pb_error("Invalid Object ID").into();
+                return self.send_reply(request_id, Err(failure), None);
+            }
+        };
+        let remote_call = match self.unserialize_call(&call, "observe_", None) {
+            Ok(remote_call) => remote_call,
+            Err(err) => {
+                let failure = RemoteError::pb_error(&err.to_string()).into();
+                return self.send_reply(request_id, Err(failure), None);
+            }
+        };
+        let reply = object.remote_message_received(remote_call);
+        self.reply(request_id, reply, None);
+    }
+
+    fn unserialize_call(
+        &self,
+        call: &Call,
+        prefix: &str,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) -> Result<RemoteCall, JellyError> {
+        let method = match String::from_utf8(call.method.clone()) {
+            Ok(method) => method,
+            Err(_) => {
+                return Err(JellyError::Malformed(format!("Invalid method {:?}", call.method)))
+            }
+        };
+        Ok(RemoteCall {
+            broker: self.clone(),
+            method: format!("{}{}", prefix, method),
+            args: args_from(self.unserialize(&call.args)?)?,
+            kwargs: kwargs_from(self.unserialize(&call.kwargs)?)?,
+            perspective,
+        })
+    }
+
+    /// Send the reply of a method now, or once ready
+    fn reply(
+        &self,
+        request_id: Option<i64>,
+        reply: Reply,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) {
+        match reply {
+            Reply::Now(res) => self.send_reply(request_id, res, perspective),
+            Reply::Later(reply) => self.state.borrow_mut().replies.push(PendingReply {
+                request_id,
+                perspective,
+                reply,
+            }),
+        }
+    }
+
+    /// Send the outcome of a call, unless no answer is required.
+    fn send_reply(
+        &self,
+        request_id: Option<i64>,
+        res: ReplyResult,
+        perspective: Option<Rc<dyn Referenceable>>,
+    ) {
+        let request_id = match request_id {
+            Some(request_id) if !self.is_closed() => request_id,
+            _ => return,
+        };
+        self.send(match res {
+            Ok(result) => PBMessage::Answer {
+                request_id,
+                result: self.serialize(&result, perspective),
+            },
+            Err(failure) => PBMessage::Error {
+                request_id,
+                failure: self.serialize(&failure, perspective),
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::jelly::jelly;
+    use super::testing::{connected, pump_events, remote_error};
+
+    fn unicode(s: &str) -> JellyValue {
+        JellyValue::Unicode(s.into())
+    }
+
+    type Record = (String, Vec<JellyValue>, Vec<(String, JellyValue)>);
+
+    /// Records the calls it gets
+    #[derive(Default)]
+    struct Echo {
+        calls: RefCell<Vec<Record>>,
+    }
+
+    impl Referenceable for Echo {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            let record = (call.method.clone(), call.args.clone(), call.kwargs.clone());
+            self.calls.borrow_mut().push(record);
+            match call.method.as_str() {
+                "remote_echo" => Reply::value(call.args.first().cloned().unwrap_or(JellyValue::None)),
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    #[test]
+    fn version() {
+        let broker = Broker::new();
+        assert_eq!(
+            broker.take_outgoing(),
+            vec![Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)])]
+        );
+        assert!(!broker.is_connected());
+        let version = PBMessage::Version(5).build();
+        assert_eq!(broker.receive(&version), Err(BrokerError::VersionMismatch(5)));
+        assert!(broker.is_closed());
+        assert_eq!(broker.receive(&version), Err(BrokerError::Closed));
+
+        let (client, server) = connected();
+        assert!(client.is_connected() && server.is_connected());
+    }
+
+    /// Whose calls are events
+    struct Events;
+
+    impl Referenceable for Events {
+        fn dispatch(&self) -> Dispatch {
+            Dispatch::Event
+        }
+    }
+
+    /// The call that `from` just sent to `to`
+    fn incoming_call(from: &Broker, to: &Broker) -> IncomingCall {
+        match pump_events(from, to).pop() {
+            Some(Ok(Some(BrokerEvent::Call(call)))) => call,
+            other => panic!("Expected a call, got {:?}", other),
+        }
+    }
+
+    /// The failure that `from` just sent to `to`, for a request of
+    /// `send_message()`
+    fn failure_event(from: &Broker, to: &Broker) -> (i64, RemoteError) {
+        match pump_events(from, to).pop() {
+            Some(Ok(Some(BrokerEvent::Error { request_id, failure }))) => {
+                (request_id, RemoteError::from_failure(&failure).unwrap())
+            }
+            other => panic!("Expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn call() {
+        let (client, server) = connected();
+        let root: Rc<dyn Referenceable> = Rc::new(Events);
+        server.set_root(root.clone());
+
+        let mut answer = client.root_object().call_remote(
+            "echo",
+            &[JellyValue::Int(3)],
+            &[("verbose", JellyValue::Bool(true))],
+        );
+        let call = incoming_call(&client, &server);
+        assert_eq!(
+            call,
+            IncomingCall {
+                request_id: 1,
+                object_id: ObjectId::root(),
+                method: "echo".into(),
+                args: vec![JellyValue::Int(3)],
+                kwargs: vec![("verbose".into(), JellyValue::Bool(true))],
+                answer_required: true,
+            }
+        );
+        let object = server.local_object(&call.object_id).unwrap();
+        assert_eq!(puid(&object), puid(&root));
+        assert_eq!(answer.try_take(), None);
+        server.answer(call.request_id, &call.args[0]).unwrap();
+        assert_eq!(pump_events(&server, &client), vec![Ok(None)]);
+        assert_eq!(answer.try_take(), Some(Ok(JellyValue::Int(3))));
+
+        // no more waiting for that answer
+        server.answer(call.request_id, &call.args[0]).unwrap();
+        assert_eq!(pump_events(&server, &client), vec![Err(BrokerError::UnknownRequest(1))]);
+    }
+
+    #[test]
+    fn send_message() {
+        let (client, server) = connected();
+        server.set_root(Rc::new(Events));
+
+        let request_id =
+            client.send_message(ObjectId::root(), "echo", &[JellyValue::Int(3)], &[], true).unwrap();
+        assert_eq!(request_id, 1);
+        let call = incoming_call(&client, &server);
+        server.answer(call.request_id, &call.args[0]).unwrap();
+        assert_eq!(
+            pump_events(&server, &client),
+            vec![Ok(Some(BrokerEvent::Answer {
+                request_id: 1,
+                result: JellyValue::Int(3),
+            }))]
+        );
+
+        client.send_message(ObjectId::root(), "fail", &[], &[], true).unwrap();
+        let call = incoming_call(&client, &server);
+        server.error(call.request_id, &unicode("failed")).unwrap();
+        assert_eq!(
+            pump_events(&server, &client),
+            vec![Ok(Some(BrokerEvent::Error {
+                request_id: 2,
+                failure: unicode("failed"),
+            }))]
+        );
+    }
+
+    #[test]
+    fn no_answer() {
+        let (client, server) = connected();
+        server.set_root(Rc::new(Events));
+        client.root_object().call_remote_no_answer("shutdown", &[], &[]).unwrap();
+        let call = incoming_call(&client, &server);
+        assert_eq!(call.method, "shutdown");
+        assert!(!call.answer_required);
+        assert!(client.state.borrow().waiting_for_answers.is_empty());
+    }
+
+    #[test]
+    fn dispatch() {
+        let (client, server) = connected();
+        let root = Rc::new(Echo::default());
+        server.set_root(root.clone());
+
+        let mut answer = client.root_object().call_remote(
+            "echo",
+            &[JellyValue::Int(3)],
+            &[("verbose", JellyValue::Bool(true))],
+        );
+        assert_eq!(pump_events(&client, &server), vec![Ok(None)]);
+        assert_eq!(
+            *root.calls.borrow(),
+            vec![(
+                "remote_echo".to_owned(),
+                vec![JellyValue::Int(3)],
+                vec![("verbose".to_owned(), JellyValue::Bool(true))],
+            )]
+        );
+        assert_eq!(answer.try_take(), None);
+        let outgoing = server.take_outgoing();
+        assert_eq!(
+            outgoing,
+            vec![PBMessage::Answer {
+                request_id: 1,
+                result: Element::Integer(3),
+            }.build()]
+        );
+        client.receive(&outgoing[0]).unwrap();
+        assert_eq!(answer.try_take(), Some(Ok(JellyValue::Int(3))));
+
+        // no more waiting for that answer
+        assert_eq!(client.receive(&outgoing[0]), Err(BrokerError::UnknownRequest(1)));
+    }
+
+    #[test]
+    fn dispatch_no_answer() {
+        let (client, server) = connected();
+        let root = Rc::new(Echo::default());
+        server.set_root(root.clone());
+        client.root_object().call_remote_no_answer("shutdown", &[], &[]).unwrap();
+        assert!(client.state.borrow().waiting_for_answers.is_empty());
+        pump_events(&client, &server);
+        assert_eq!(root.calls.borrow()[0].0, "remote_shutdown");
+        // even if failed
+        assert!(server.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn connection_lost() {
+        let (client, server) = connected();
+        let mut answer = client.root_object().call_remote("echo", &[], &[]);
+        client.connection_lost();
+        assert_eq!(answer.try_take(), Some(Err(CallError::ConnectionLost)));
+        let mut answer = client.root_object().call_remote("echo", &[], &[]);
+        assert_eq!(answer.try_take(), Some(Err(CallError::DeadReference)));
+        assert_eq!(
+            client.root_object().call_remote_no_answer("echo", &[], &[]),
+            Err(BrokerError::Closed)
+        );
+        drop(server);
+    }
+
+    #[test]
+    fn errors() {
+        let (client, server) = connected();
+        client.send_message(ObjectId::root(), "echo", &[], &[], true).unwrap();
+        // no answer expected, even for errors
+        client.send_message(ObjectId::Number(3), "echo", &[], &[], false).unwrap();
+        assert_eq!(pump_events(&client, &server), vec![Ok(None), Ok(None)]);
+        let (request_id, err) = failure_event(&server, &client);
+        assert_eq!(request_id, 1);
+        assert_eq!(err.remote_type, "twisted.spread.pb.Error");
+        assert_eq!(err.value, "Invalid Object ID");
+
+        // Twisted would not let that one through
+        server.set_root(Rc::new(Events));
+        let module = JellyValue::Module("os".into());
+        client.send_message(ObjectId::root(), "echo", &[module], &[], true).unwrap();
+        assert_eq!(pump_events(&client, &server), vec![Ok(None)]);
+        assert_eq!(
+            failure_event(&server, &client),
+            (3, RemoteError::pb_error("insecure jelly: Type not allowed: module"))
+        );
+
+        // nor would it leak containers referring to themselves
+        let args = [JellyValue::list(vec![])];
+        if let JellyValue::List(ref l) = args[0] {
+            l.borrow_mut().push(args[0].clone());
+        }
+        client.send_message(ObjectId::root(), "echo", &args, &[], true).unwrap();
+        if let JellyValue::List(ref l) = args[0] {
+            l.borrow_mut().clear();
+        }
+        assert_eq!(pump_events(&client, &server), vec![Ok(None)]);
+        assert_eq!(
+            failure_event(&server, &client),
+            (4, RemoteError::pb_error("insecure jelly: Reference 1 to an unfinished container"))
+        );
+
+        client.receive(&Element::List(vec![Element::String(b"frobnicate".to_vec())])).unwrap();
+        assert_eq!(
+            pump_events(&client, &server),
+            vec![Ok(Some(BrokerEvent::NotUnderstood(b"frobnicate".to_vec())))]
+        );
+        assert!(!server.is_closed());
+        assert_eq!(server.send_message(ObjectId::root(), "echo", &[], &[], true), Ok(1));
+    }
+
+    #[test]
+    fn dispatch_errors() {
+        let (client, server) = connected();
+        let mut answer = client.root_object().call_remote("echo", &[], &[]);
+        // no answer expected, even for errors
+        let unknown = RemoteReference::new(client.clone(), ObjectId::Number(3));
+        unknown.call_remote_no_answer("echo", &[], &[]).unwrap();
+        assert_eq!(pump_events(&client, &server), vec![Ok(None), Ok(None)]);
+        assert_eq!(pump_events(&server, &client), vec![Ok(None)]);
+        assert_eq!(remote_error(answer.try_take()), RemoteError::pb_error("Invalid Object ID"));
+
+        server.set_root(Rc::new(Echo::default()));
+        let mut answer = client.root_object().call_remote("frobnicate", &[], &[]);
+        pump_events(&client, &server);
+        pump_events(&server, &client);
+        let err = remote_error(answer.try_take());
+        assert_eq!(err.remote_type, "twisted.spread.pb.NoSuchMethod");
+        assert_eq!(err.value, "No such method: remote_frobnicate");
+        assert_eq!(
+            err.parents,
+            vec![
+                "twisted.spread.pb.NoSuchMethod",
+                "builtins.AttributeError",
+                "builtins.Exception",
+                "builtins.BaseException",
+                "builtins.object",
+            ]
+        );
+
+        // Twisted would not let that one through
+        let module = JellyValue::Module("os".into());
+        let mut answer = client.root_object().call_remote("echo", &[module], &[]);
+        assert_eq!(pump_events(&client, &server), vec![Ok(None)]);
+        pump_events(&server, &client);
+        assert_eq!(
+            remote_error(answer.try_take()),
+            RemoteError::pb_error("insecure jelly: Type not allowed: module")
+        );
+
+        client.receive(&Element::List(vec![Element::String(b"frobnicate".to_vec())])).unwrap();
+        assert_eq!(
+            pump_events(&client, &server),
+            vec![Ok(Some(BrokerEvent::NotUnderstood(b"frobnicate".to_vec())))]
+        );
+        // which is no reason to drop the connection
+        assert!(!server.is_closed());
+        assert_eq!(server.root_object().call_remote_no_answer("echo", &[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn local_references() {
+        let server = Broker::new();
+        let object: Rc<dyn Referenceable> = Rc::new(Echo::default());
+        let luid = server.register_reference(object.clone());
+        assert_eq!(luid, 1);
+        assert_eq!(server.register_reference(object.clone()), 1);
+        assert_eq!(server.register_reference(Rc::new(Echo::default())), 2);
+
+        let decref = PBMessage::DecRef(1).build();
+        assert_eq!(server.receive(&decref), Ok(None));
+        assert!(server.local_object(&ObjectId::Number(1)).is_some());
+        assert_eq!(server.receive(&decref), Ok(None));
+        assert!(server.local_object(&ObjectId::Number(1)).is_none());
+        // a new registration gets a new luid
+        assert_eq!(server.register_reference(object), 3);
+        assert_eq!(
+            server.receive(&decref),
+            Err(BrokerError::UnknownObject(ObjectId::Number(1)))
+        );
+    }
+
+    #[test]
+    fn remote_references() {
+        let (client, server) = connected();
+        let mut answer = client.root_object().call_remote("getChild", &[], &[]);
+        pump_events(&client, &server);
+        let remote = Element::List(vec![Element::Extension(PB::Remote), Element::Integer(1)]);
+        let result = Element::List(vec![Element::Extension(PB::List), remote.clone(), remote]);
+        client.receive(&PBMessage::Answer {
+            request_id: 1,
+            result,
+        }.build()).unwrap();
+        let child = client.received_remote(1);
+        assert_eq!(client.remote_reference_count(1), 3);
+        assert_eq!(
+            answer.try_take(),
+            Some(Ok(JellyValue::list(vec![
+                JellyValue::Remote(child.clone()),
+                JellyValue::Remote(child.clone()),
+            ])))
+        );
+        // given back early
+        client.decref(1);
+        assert_eq!(client.remote_reference_count(1), 2);
+        drop(child);
+        assert_eq!(client.remote_reference_count(1), 0);
+        assert_eq!(client.take_outgoing(), vec![PBMessage::DecRef(1).build(); 3]);
+
+        // sent back, it's a local object for the peer
+        let elt = jelly(&JellyValue::Remote(client.received_remote(1)));
+        assert_eq!(
+            elt,
+            Element::List(vec![Element::Extension(PB::Local), Element::Integer(1)])
+        );
+        let elt = jelly(&JellyValue::Remote(client.root_object()));
+        assert_eq!(
+            elt,
+            Element::List(vec![Element::Extension(PB::Local), Element::String(b"root".to_vec())])
+        );
+    }
+
+    #[derive(Debug)]
+    struct Change {
+        who: JellyValue,
+    }
+
+    impl RemoteCopy for Change {
+        fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError> {
+            match state.get("who") {
+                Some(who) => Ok(Change { who }),
+                None => Err(JellyError::Malformed("Change without author".into())),
+            }
+        }
+    }
+
+    #[test]
+    fn copies() {
+        let (client, server) = connected();
+        let change = Element::List(vec![
+            Element::String(b"buildbot.changes.changes.Change".to_vec()),
+            Element::List(vec![
+                Element::Extension(PB::Dictionary),
+                Element::List(vec![
+                    Element::String(b"who".to_vec()),
+                    Element::String(b"alice".to_vec()),
+                ]),
+            ]),
+        ]);
+        let answer = PBMessage::Answer {
+            request_id: 1,
+            result: change,
+        }.build();
+
+        // not allowed unless registered
+        let _answer = client.root_object().call_remote("getChange", &[], &[]);
+        assert_eq!(
+            client.receive(&answer),
+            Err(BrokerError::Jelly(JellyError::Insecure(
+                "Module not allowed: buildbot.changes.changes".into()
+            )))
+        );
+
+        let client = Broker::new();
+        client.receive(&PBMessage::Version(6).build()).unwrap();
+        client.set_unjellyable_for_class::<Change>("buildbot.changes.changes.Change");
+        let mut result = client.root_object().call_remote("getChange", &[], &[]);
+        client.receive(&answer).unwrap();
+        match result.try_take() {
+            Some(Ok(JellyValue::Copied(ref copy))) => assert_eq!(
+                copy.downcast_ref::<Change>().map(|c| &c.who),
+                Some(&JellyValue::Bytes(b"alice".to_vec()))
+            ),
+            other => panic!("Expected a change, got {:?}", other),
+        }
+        drop(server);
+    }
+}
@@ -0,0 +1,604 @@
+//! Letting clients log in, as Twisted's cred `Portal` behind `pb.PBServerFactory`
+//!
+//! A `PortalRoot` is published as the root object. It challenges the clients
+//! logging in, and hands over their credentials to the `Portal`, which asks
+//! its checkers for the avatar id, and its `Realm` for the avatar.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::super::jelly::JellyValue;
+use super::login::respond;
+use super::{Referenceable, RemoteCall, RemoteError, Reply};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CredError {
+    /// Wrong username or password, as Twisted's `UnauthorizedLogin`
+    UnauthorizedLogin,
+    /// No checker for this kind of credentials, as Twisted's
+    /// `UnhandledCredentials`
+    UnhandledCredentials,
+}
+
+impl fmt::Display for CredError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CredError::UnauthorizedLogin => write!(f, "unauthorized login"),
+            CredError::UnhandledCredentials => write!(f, "unhandled credentials"),
+        }
+    }
+}
+
+impl error::Error for CredError {}
+
+/// Sent as the exceptions of `twisted.cred.error`, which Twisted clients check
+impl From<CredError> for RemoteError {
+    fn from(err: CredError) -> Self {
+        let (remote_type, bases): (_, &[_]) = match err {
+            CredError::UnauthorizedLogin => (
+                "twisted.cred.error.UnauthorizedLogin",
+                &["twisted.cred.error.LoginFailed", "twisted.cred.error.Unauthorized"],
+            ),
+            CredError::UnhandledCredentials => (
+                "twisted.cred.error.UnhandledCredentials",
+                &["twisted.cred.error.LoginFailed"],
+            ),
+        };
+        let mut remote = RemoteError::new(remote_type, &err.to_string());
+        for (i, base) in bases.iter().enumerate() {
+            remote.parents.insert(i + 1, base.to_string());
+        }
+        remote
+    }
+}
+
+/// What a client logging in proves who it is with
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ClientCredentials {
+    Anonymous,
+    Challenged(ChallengeResponse),
+}
+
+/// Username with the response to a challenge, as Twisted's
+/// `_PortalAuthChallenger`
+///
+/// The password itself is never sent, it can only be checked against.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChallengeResponse {
+    pub username: Vec<u8>,
+    pub challenge: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+impl ChallengeResponse {
+    pub fn check_password(&self, password: &[u8]) -> bool {
+        respond(&self.challenge, password) == self.response
+    }
+
+    /// Check against the MD5 digest of the password
+    pub fn check_md5_password(&self, md5_password: &[u8]) -> bool {
+        let mut ctx = md5::Context::new();
+        ctx.consume(md5_password);
+        ctx.consume(&self.challenge);
+        ctx.compute().0[..] == self.response[..]
+    }
+}
+
+/// Who a client is, once its credentials are checked
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum AvatarId {
+    /// As Twisted's `checkers.ANONYMOUS`
+    Anonymous,
+    User(Vec<u8>),
+}
+
+/// Checks some kinds of credentials, as Twisted's `ICredentialsChecker`
+pub trait CredentialsChecker {
+    /// `true` if `credentials` are of a kind that this checks
+    fn handles(&self, credentials: &ClientCredentials) -> bool;
+
+    fn request_avatar_id(&self, credentials: &ClientCredentials) -> Result<AvatarId, CredError>;
+}
+
+/// Gives the avatars, as Twisted's `IRealm` for `IPerspective`
+pub trait Realm {
+    /// The avatar of `avatar_id`, for a client calling back through `mind`,
+    /// usually `JellyValue::Remote` or `JellyValue::None`
+    fn request_avatar(
+        &self,
+        avatar_id: &AvatarId,
+        mind: JellyValue,
+    ) -> Result<Rc<dyn Referenceable>, CredError>;
+
+    /// The client of `avatar` went away, as the logout function of Twisted's
+    /// `requestAvatar`
+    fn logout(&self, _avatar_id: &AvatarId, _avatar: &Rc<dyn Referenceable>) {}
+}
+
+/// Checkers and realm, as Twisted's `Portal`
+pub struct Portal {
+    realm: Rc<dyn Realm>,
+    checkers: Vec<Rc<dyn CredentialsChecker>>,
+}
+
+impl fmt::Debug for Portal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Portal({} checkers)", self.checkers.len())
+    }
+}
+
+impl Portal {
+    pub fn new(realm: Rc<dyn Realm>) -> Self {
+        Portal {
+            realm,
+            checkers: Vec::new(),
+        }
+    }
+
+    /// Checkers are consulted in their order of registration.
+    pub fn register_checker<C: CredentialsChecker + 'static>(&mut self, checker: C) -> &mut Self {
+        self.checkers.push(Rc::new(checker));
+        self
+    }
+
+    pub fn realm(&self) -> &Rc<dyn Realm> {
+        &self.realm
+    }
+
+    /// The avatar for `credentials`, as Twisted's `Portal.login`
+    pub fn login(
+        &self,
+        credentials: &ClientCredentials,
+        mind: JellyValue,
+    ) -> Result<(AvatarId, Rc<dyn Referenceable>), CredError> {
+        let checker = match self.checkers.iter().find(|c| c.handles(credentials)) {
+            Some(checker) => checker,
+            None => return Err(CredError::UnhandledCredentials),
+        };
+        let avatar_id = checker.request_avatar_id(credentials)?;
+        let avatar = self.realm.request_avatar(&avatar_id, mind)?;
+        Ok((avatar_id, avatar))
+    }
+}
+
+/// Users and their passwords, as Twisted's
+/// `InMemoryUsernamePasswordDatabaseDontUse`
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryUsernamePasswordDatabase {
+    users: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl InMemoryUsernamePasswordDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(&mut self, username: &[u8], password: &[u8]) -> &mut Self {
+        self.users.retain(|(u, _)| u != username);
+        self.users.push((username.to_vec(), password.to_vec()));
+        self
+    }
+}
+
+impl CredentialsChecker for InMemoryUsernamePasswordDatabase {
+    fn handles(&self, credentials: &ClientCredentials) -> bool {
+        matches!(*credentials, ClientCredentials::Challenged(_))
+    }
+
+    fn request_avatar_id(&self, credentials: &ClientCredentials) -> Result<AvatarId, CredError> {
+        let challenged = match *credentials {
+            ClientCredentials::Challenged(ref challenged) => challenged,
+            ClientCredentials::Anonymous => return Err(CredError::UnhandledCredentials),
+        };
+        match self.users.iter().find(|(u, _)| *u == challenged.username) {
+            Some((username, password)) if challenged.check_password(password) => {
+                Ok(AvatarId::User(username.clone()))
+            }
+            _ => Err(CredError::UnauthorizedLogin),
+        }
+    }
+}
+
+/// Users and their passwords in a file, as Twisted's `FilePasswordDB`
+///
+/// Each line holds the fields of a user, separated by the delimiter, the
+/// username and the password being at given positions. Lines with less
+/// fields are ignored. Passwords are in clear, as challenges can't be checked
+/// otherwise. The file is read at each login.
+#[derive(Debug, Clone)]
+pub struct FilePasswordDB {
+    path: PathBuf,
+    delimiter: u8,
+    username_field: usize,
+    password_field: usize,
+    case_sensitive: bool,
+}
+
+impl FilePasswordDB {
+    /// `username:password` lines, case sensitive
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FilePasswordDB {
+            path: path.as_ref().to_path_buf(),
+            delimiter: b':',
+            username_field: 0,
+            password_field: 1,
+            case_sensitive: true,
+        }
+    }
+
+    pub fn delimiter(&mut self, delimiter: u8) -> &mut Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn fields(&mut self, username_field: usize, password_field: usize) -> &mut Self {
+        self.username_field = username_field;
+        self.password_field = password_field;
+        self
+    }
+
+    /// If not, usernames are compared in lowercase, which is also how they
+    /// end up in avatar ids.
+    pub fn case_sensitive(&mut self, case_sensitive: bool) -> &mut Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// The password of `username`, read from the file
+    pub fn get_user(&self, username: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CredError> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(_) => return Err(CredError::UnauthorizedLogin),
+        };
+        let username = self.normalize(username);
+        for line in contents.split(|&b| b == b'\n') {
+            let line = match line.iter().rposition(|b| !b.is_ascii_whitespace()) {
+                Some(end) => &line[..=end],
+                None => continue,
+            };
+            let parts: Vec<&[u8]> = line.split(|&b| b == self.delimiter).collect();
+            if self.username_field >= parts.len() || self.password_field >= parts.len() {
+                continue;
+            }
+            if self.normalize(parts[self.username_field]) == username {
+                return Ok((username, parts[self.password_field].to_vec()));
+            }
+        }
+        Err(CredError::UnauthorizedLogin)
+    }
+
+    fn normalize(&self, username: &[u8]) -> Vec<u8> {
+        if self.case_sensitive {
+            username.to_vec()
+        } else {
+            username.to_ascii_lowercase()
+        }
+    }
+}
+
+impl CredentialsChecker for FilePasswordDB {
+    fn handles(&self, credentials: &ClientCredentials) -> bool {
+        matches!(*credentials, ClientCredentials::Challenged(_))
+    }
+
+    fn request_avatar_id(&self, credentials: &ClientCredentials) -> Result<AvatarId, CredError> {
+        let challenged = match *credentials {
+            ClientCredentials::Challenged(ref challenged) => challenged,
+            ClientCredentials::Anonymous => return Err(CredError::UnhandledCredentials),
+        };
+        let (username, password) = self.get_user(&challenged.username)?;
+        if challenged.check_password(&password) {
+            Ok(AvatarId::User(username))
+        } else {
+            Err(CredError::UnauthorizedLogin)
+        }
+    }
+}
+
+/// Lets anonymous clients in, as Twisted's `AllowAnonymousAccess`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAnonymousAccess;
+
+impl CredentialsChecker for AllowAnonymousAccess {
+    fn handles(&self, credentials: &ClientCredentials) -> bool {
+        *credentials == ClientCredentials::Anonymous
+    }
+
+    fn request_avatar_id(&self, _credentials: &ClientCredentials) -> Result<AvatarId, CredError> {
+        Ok(AvatarId::Anonymous)
+    }
+}
+
+/// A new challenge, as Twisted's `pb.challenge`, i.e., an MD5 digest of
+/// something unpredictable enough
+fn challenge() -> Vec<u8> {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    md5::compute(hasher.finish().to_le_bytes()).0.to_vec()
+}
+
+/// Root object of a server with a portal, as Twisted's `_PortalWrapper`
+#[derive(Debug)]
+pub struct PortalRoot {
+    portal: Rc<Portal>,
+}
+
+impl PortalRoot {
+    pub fn new(portal: Rc<Portal>) -> Self {
+        PortalRoot { portal }
+    }
+}
+
+/// Log in, the avatar being logged out once the client no longer refers to
+/// it, or with the loss of the connection, whichever comes first.
+fn login(
+    portal: &Rc<Portal>,
+    call: &RemoteCall,
+    credentials: ClientCredentials,
+    mind: JellyValue,
+) -> Reply {
+    match portal.login(&credentials, mind) {
+        Ok((avatar_id, avatar)) => {
+            let realm = portal.realm().clone();
+            let logged_in = avatar.clone();
+            let logout: Box<dyn FnOnce()> = Box::new(move || realm.logout(&avatar_id, &logged_in));
+            // as Twisted's `maybeLogout`
+            let logout = Rc::new(Cell::new(Some(logout)));
+            let maybe_logout = move || {
+                if let Some(logout) = logout.take() {
+                    logout();
+                }
+            };
+            call.broker.notify_on_local_cleanup(&avatar, maybe_logout.clone());
+            call.broker.notify_on_disconnect(maybe_logout);
+            Reply::value(JellyValue::Local(avatar))
+        }
+        Err(err) => Reply::failure(RemoteError::from(err).into()),
+    }
+}
+
+impl Referenceable for PortalRoot {
+    fn remote_message_received(&self, call: RemoteCall) -> Reply {
+        match (call.method.as_str(), &call.args[..]) {
+            ("remote_login", [JellyValue::Bytes(username)]) => {
+                let challenger = Challenger {
+                    portal: self.portal.clone(),
+                    username: username.clone(),
+                    challenge: challenge(),
+                };
+                Reply::value(JellyValue::tuple(vec![
+                    JellyValue::Bytes(challenger.challenge.clone()),
+                    JellyValue::Local(Rc::new(challenger)),
+                ]))
+            }
+            ("remote_loginAnonymous", [mind]) => {
+                let mind = mind.clone();
+                login(&self.portal, &call, ClientCredentials::Anonymous, mind)
+            }
+            _ => Reply::no_such_method(&call),
+        }
+    }
+}
+
+/// Waits for the response to the challenge, as Twisted's
+/// `_PortalAuthChallenger`
+struct Challenger {
+    portal: Rc<Portal>,
+    username: Vec<u8>,
+    challenge: Vec<u8>,
+}
+
+impl Referenceable for Challenger {
+    fn remote_message_received(&self, call: RemoteCall) -> Reply {
+        match (call.method.as_str(), &call.args[..]) {
+            ("remote_respond", [JellyValue::Bytes(response), mind]) => {
+                let credentials = ClientCredentials::Challenged(ChallengeResponse {
+                    username: self.username.clone(),
+                    challenge: self.challenge.clone(),
+                    response: response.clone(),
+                });
+                let mind = mind.clone();
+                login(&self.portal, &call, credentials, mind)
+            }
+            _ => Reply::no_such_method(&call),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use super::super::{Broker, Credentials, LoginError};
+    use super::super::testing::{connected, pump, run};
+    use super::super::{Dispatch, RemoteReference};
+
+    /// The exception that the login failed with
+    fn failed(res: Result<RemoteReference, LoginError>) -> RemoteError {
+        match res {
+            Err(LoginError::Call(ref err)) => err.remote_error().unwrap(),
+            other => panic!("Expected a failed call, got {:?}", other),
+        }
+    }
+
+    struct Avatar(AvatarId);
+
+    impl Referenceable for Avatar {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match (call.method.as_str(), &self.0) {
+                ("perspective_whoami", AvatarId::User(name)) => {
+                    Reply::value(JellyValue::Bytes(name.clone()))
+                }
+                ("perspective_whoami", AvatarId::Anonymous) => Reply::value(JellyValue::None),
+                _ => Reply::no_such_method(&call),
+            }
+        }
+
+        fn dispatch(&self) -> Dispatch {
+            Dispatch::Perspective
+        }
+    }
+
+    /// Keeps track of the minds and of the logouts
+    #[derive(Default)]
+    struct Workers {
+        minds: RefCell<Vec<JellyValue>>,
+        logouts: RefCell<Vec<AvatarId>>,
+    }
+
+    impl Realm for Workers {
+        fn request_avatar(
+            &self,
+            avatar_id: &AvatarId,
+            mind: JellyValue,
+        ) -> Result<Rc<dyn Referenceable>, CredError> {
+            self.minds.borrow_mut().push(mind);
+            Ok(Rc::new(Avatar(avatar_id.clone())))
+        }
+
+        fn logout(&self, avatar_id: &AvatarId, _avatar: &Rc<dyn Referenceable>) {
+            self.logouts.borrow_mut().push(avatar_id.clone());
+        }
+    }
+
+    fn server(server: &Broker, portal: Portal) {
+        server.set_root(Rc::new(PortalRoot::new(Rc::new(portal))));
+    }
+
+    fn whoami(client: &Broker, server: &Broker, avatar: &RemoteReference) -> JellyValue {
+        let mut answer = avatar.call_remote("whoami", &[], &[]);
+        pump(client, server);
+        pump(server, client);
+        answer.try_take().unwrap().unwrap()
+    }
+
+    #[test]
+    fn login() {
+        let (client, broker) = connected();
+        let realm = Rc::new(Workers::default());
+        let mut portal = Portal::new(realm.clone());
+        let mut users = InMemoryUsernamePasswordDatabase::new();
+        users.add_user(b"worker", b"pass").add_user(b"admin", b"secret");
+        portal.register_checker(users);
+        server(&broker, portal);
+
+        let credentials = Credentials::username_password(b"worker", b"pass");
+        let avatar = run(&client, &broker, client.login(&credentials, None)).unwrap();
+        assert_eq!(whoami(&client, &broker, &avatar), JellyValue::Bytes(b"worker".to_vec()));
+        assert_eq!(*realm.minds.borrow(), vec![JellyValue::None]);
+
+        for &(username, password) in &[(&b"worker"[..], &b"secret"[..]), (b"nobody", b"pass")] {
+            let credentials = Credentials::username_password(username, password);
+            let err = failed(run(&client, &broker, client.login(&credentials, None)));
+            assert_eq!(err, RemoteError::from(CredError::UnauthorizedLogin));
+            assert!(err.is_instance_of("twisted.cred.error.LoginFailed"));
+        }
+        // no anonymous access without its checker
+        let err = failed(run(&client, &broker, client.login(&Credentials::Anonymous, None)));
+        assert_eq!(err.remote_type, "twisted.cred.error.UnhandledCredentials");
+        assert_eq!(err.check(&["UnauthorizedLogin", "LoginFailed"]), Some("LoginFailed"));
+
+        assert!(realm.logouts.borrow().is_empty());
+        // logged out once no longer used
+        let credentials = Credentials::username_password(b"admin", b"secret");
+        let admin = run(&client, &broker, client.login(&credentials, None)).unwrap();
+        drop(admin);
+        pump(&client, &broker);
+        assert_eq!(*realm.logouts.borrow(), vec![AvatarId::User(b"admin".to_vec())]);
+        broker.connection_lost();
+        assert_eq!(
+            *realm.logouts.borrow(),
+            vec![AvatarId::User(b"admin".to_vec()), AvatarId::User(b"worker".to_vec())]
+        );
+    }
+
+    #[test]
+    fn anonymous() {
+        let (client, broker) = connected();
+        let realm = Rc::new(Workers::default());
+        let mut portal = Portal::new(realm.clone());
+        portal.register_checker(AllowAnonymousAccess);
+        server(&broker, portal);
+
+        let mind: Rc<dyn Referenceable> = Rc::new(Avatar(AvatarId::Anonymous));
+        let login = client.login(&Credentials::Anonymous, Some(mind));
+        let avatar = run(&client, &broker, login).unwrap();
+        assert_eq!(whoami(&client, &broker, &avatar), JellyValue::None);
+        match realm.minds.borrow()[0] {
+            JellyValue::Remote(ref mind) => assert!(mind.broker().is_connected()),
+            ref other => panic!("Expected the mind, got {:?}", other),
+        }
+        broker.connection_lost();
+        assert_eq!(*realm.logouts.borrow(), vec![AvatarId::Anonymous]);
+    }
+
+    #[test]
+    fn challenge_response() {
+        let challenged = ChallengeResponse {
+            username: b"worker".to_vec(),
+            challenge: challenge(),
+            response: respond(b"challenge", b"pass"),
+        };
+        assert!(!challenged.check_password(b"pass"));
+        let challenged = ChallengeResponse {
+            challenge: b"challenge".to_vec(),
+            ..challenged
+        };
+        assert!(challenged.check_password(b"pass"));
+        assert!(!challenged.check_password(b"secret"));
+        assert!(challenged.check_md5_password(&md5::compute(b"pass").0));
+        assert_ne!(challenge(), challenge());
+    }
+
+    #[test]
+    fn file_password_db() {
+        let name = format!("twisted_banana_{}.passwd", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, "# users\nworker:pass\nAdmin:secret:admin \nbroken\n\n").unwrap();
+        let challenged = |username: &[u8], password: &[u8]| {
+            ClientCredentials::Challenged(ChallengeResponse {
+                username: username.to_vec(),
+                challenge: b"challenge".to_vec(),
+                response: respond(b"challenge", password),
+            })
+        };
+
+        let mut db = FilePasswordDB::new(&path);
+        assert!(db.handles(&challenged(b"worker", b"pass")));
+        assert!(!db.handles(&ClientCredentials::Anonymous));
+        assert_eq!(
+            db.request_avatar_id(&challenged(b"worker", b"pass")),
+            Ok(AvatarId::User(b"worker".to_vec()))
+        );
+        assert_eq!(
+            db.request_avatar_id(&challenged(b"worker", b"secret")),
+            Err(CredError::UnauthorizedLogin)
+        );
+        assert_eq!(
+            db.request_avatar_id(&challenged(b"admin", b"secret")),
+            Err(CredError::UnauthorizedLogin)
+        );
+        db.case_sensitive(false);
+        assert_eq!(
+            db.request_avatar_id(&challenged(b"ADMIN", b"secret")),
+            Ok(AvatarId::User(b"admin".to_vec()))
+        );
+        // trailing whitespace is stripped
+        db.fields(2, 1);
+        assert_eq!(
+            db.request_avatar_id(&challenged(b"admin", b"secret")),
+            Ok(AvatarId::User(b"admin".to_vec()))
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(db.get_user(b"admin"), Err(CredError::UnauthorizedLogin));
+    }
+}
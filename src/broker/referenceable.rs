@@ -0,0 +1,260 @@
+//! Local objects that the peer can call, as Twisted's `Referenceable`
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use super::super::jelly::JellyValue;
+use super::{Broker, RemoteError};
+
+/// How the methods of a local object are named, as in Twisted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Dispatch {
+    /// `remote_<method>`, as `Referenceable`
+    Remote,
+    /// `view_<method>`, with the perspective the object was sent through,
+    /// as `Viewable`
+    View,
+    /// `perspective_<method>`, as `Avatar`
+    Perspective,
+    /// `<method>`, as a `BrokerEvent::Call` that the application answers
+    /// with `Broker::answer()` or `Broker::error()`
+    Event,
+}
+
+impl Dispatch {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Dispatch::Remote => "remote_",
+            Dispatch::View => "view_",
+            Dispatch::Perspective => "perspective_",
+            Dispatch::Event => "",
+        }
+    }
+}
+
+/// Call from the peer, already unjellied
+#[derive(Debug)]
+pub struct RemoteCall {
+    pub broker: Broker,
+    /// Method name, with its prefix, e.g., `remote_print`
+    pub method: String,
+    pub args: Vec<JellyValue>,
+    /// Keyword arguments, in their order of appearance
+    pub kwargs: Vec<(String, JellyValue)>,
+    /// For `view_` methods, the avatar through which the object was sent
+    pub perspective: Option<Rc<dyn Referenceable>>,
+}
+
+/// Result of a method, or the failure to send to the peer
+pub type ReplyResult = Result<JellyValue, JellyValue>;
+
+/// What a method gives back, right away or not
+pub enum Reply {
+    Now(ReplyResult),
+    /// The broker polls it in `Broker::poll_replies()`
+    Later(Pin<Box<dyn Future<Output = ReplyResult>>>),
+}
+
+impl Reply {
+    pub fn value(value: JellyValue) -> Self {
+        Reply::Now(Ok(value))
+    }
+
+    pub fn failure(failure: JellyValue) -> Self {
+        Reply::Now(Err(failure))
+    }
+
+    pub fn later<F: Future<Output = ReplyResult> + 'static>(fut: F) -> Self {
+        Reply::Later(Box::pin(fut))
+    }
+
+    /// As Twisted raises `pb.NoSuchMethod`
+    pub fn no_such_method(call: &RemoteCall) -> Self {
+        Reply::failure(RemoteError::no_such_method(&call.method).into())
+    }
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reply::Now(ref res) => write!(f, "Now({:?})", res),
+            Reply::Later(_) => write!(f, "Later"),
+        }
+    }
+}
+
+/// Object whose methods the peer can call
+///
+/// Such objects are published with `Broker::set_root()`, or sent to the
+/// peer as `JellyValue::Local` in arguments and results, which registers
+/// them with the broker under a local id.
+pub trait Referenceable {
+    /// Handle a call, matching on `call.method`. Unknown methods should be
+    /// answered with `Reply::no_such_method()`, as all of them are by
+    /// default.
+    ///
+    /// This isn't called for `Dispatch::Event` objects.
+    fn remote_message_received(&self, call: RemoteCall) -> Reply {
+        Reply::no_such_method(&call)
+    }
+
+    fn dispatch(&self) -> Dispatch {
+        Dispatch::Remote
+    }
+}
+
+impl fmt::Debug for dyn Referenceable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{} at {:p}>", self.dispatch().prefix(), self as *const dyn Referenceable)
+    }
+}
+
+/// Identity of a local object, as Python's `id()`
+pub(crate) fn puid(object: &Rc<dyn Referenceable>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::task::{Context, Poll, Waker};
+    use super::super::super::ObjectId;
+    use super::super::testing::{connected, pump};
+    use super::super::RemoteReference;
+
+    fn remote(value: Option<Result<JellyValue, super::super::CallError>>) -> RemoteReference {
+        match value {
+            Some(Ok(JellyValue::Remote(reference))) => reference,
+            other => panic!("Expected a remote reference, got {:?}", other),
+        }
+    }
+
+    /// Result to be given later on
+    #[derive(Default, Clone)]
+    struct Slot(Rc<RefCell<Option<ReplyResult>>>);
+
+    impl Future for Slot {
+        type Output = ReplyResult;
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<ReplyResult> {
+            match self.0.borrow_mut().take() {
+                Some(res) => Poll::Ready(res),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    /// Root object of a buildbot-like master
+    #[derive(Default)]
+    struct Master {
+        slot: Slot,
+        avatar: Rc<Avatar>,
+    }
+
+    impl Referenceable for Master {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "remote_getAvatar" => Reply::value(JellyValue::Local(self.avatar.clone())),
+                "remote_later" => Reply::later(self.slot.clone()),
+                "remote_same" => Reply::value(call.args[0].clone()),
+                _ => Reply::no_such_method(&call),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Avatar {
+        builder: Rc<Builder>,
+    }
+
+    impl Referenceable for Avatar {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "perspective_getBuilder" => Reply::value(JellyValue::Local(self.builder.clone())),
+                _ => Reply::no_such_method(&call),
+            }
+        }
+
+        fn dispatch(&self) -> Dispatch {
+            Dispatch::Perspective
+        }
+    }
+
+    #[derive(Default)]
+    struct Builder;
+
+    impl Referenceable for Builder {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            match call.method.as_str() {
+                "view_whoami" => match call.perspective {
+                    Some(ref p) => Reply::value(JellyValue::Local(p.clone())),
+                    None => Reply::value(JellyValue::None),
+                },
+                _ => Reply::no_such_method(&call),
+            }
+        }
+
+        fn dispatch(&self) -> Dispatch {
+            Dispatch::View
+        }
+    }
+
+    #[test]
+    fn async_reply() {
+        let (client, server) = connected();
+        let master = Rc::new(Master::default());
+        server.set_root(master.clone());
+        let mut answer = client.root_object().call_remote("later", &[], &[]);
+        pump(&client, &server);
+        assert_eq!(server.pending_replies(), 1);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        server.poll_replies(&mut cx);
+        assert!(server.take_outgoing().is_empty());
+        *master.slot.0.borrow_mut() = Some(Ok(JellyValue::Int(42)));
+        server.poll_replies(&mut cx);
+        assert_eq!(server.pending_replies(), 0);
+        pump(&server, &client);
+        assert_eq!(answer.try_take(), Some(Ok(JellyValue::Int(42))));
+    }
+
+    #[test]
+    fn export() {
+        let (client, server) = connected();
+        let master = Rc::new(Master::default());
+        server.set_root(master.clone());
+
+        let mut answer = client.root_object().call_remote("getAvatar", &[], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        let avatar = remote(answer.try_take());
+        assert_eq!(avatar.object_id(), &ObjectId::Number(1));
+
+        let mut answer = avatar.call_remote("getBuilder", &[], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        let builder = remote(answer.try_take());
+
+        // the builder is seen through the avatar
+        let mut answer = builder.call_remote("whoami", &[], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        assert_eq!(remote(answer.try_take()).object_id(), &ObjectId::Number(1));
+        // which was registered twice
+        assert_eq!(server.state.borrow().local_objects[&ObjectId::Number(1)].refcount, 2);
+
+        // sent back, it's our own object
+        let mut answer =
+            client.root_object().call_remote("same", &[JellyValue::Remote(avatar.clone())], &[]);
+        pump(&client, &server);
+        pump(&server, &client);
+        assert_eq!(answer.try_take(), Some(Ok(JellyValue::Remote(avatar.clone()))));
+        assert_eq!(client.remote_reference_count(1), 3);
+        match server.local_object(&ObjectId::Number(1)) {
+            Some(ref object) => assert_eq!(puid(object), puid(&(master.avatar.clone() as Rc<dyn Referenceable>))),
+            None => panic!("Avatar should still be registered"),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! References to the objects of the peer
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use super::super::jelly::JellyValue;
+use super::super::ObjectId;
+use super::{Broker, BrokerError, CallFuture, Target};
+
+type DisconnectCallback = Box<dyn FnOnce(&RemoteReference)>;
+
+/// Shared by the clones of a reference
+struct Inner {
+    broker: Broker,
+    object_id: ObjectId,
+    on_disconnect: RefCell<Vec<DisconnectCallback>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let ObjectId::Number(luid) = self.object_id {
+            self.broker.release_remote(luid);
+        }
+    }
+}
+
+/// Object of the peer, whose methods can be called, as Twisted's
+/// `RemoteReference`
+///
+/// Clones refer to the same object. References received several times for
+/// the same id are the same handle, and the peer is told that the object is
+/// no longer used when the last clone is dropped.
+#[derive(Clone)]
+pub struct RemoteReference {
+    inner: Rc<Inner>,
+}
+
+/// Reference that doesn't keep the peer's object alive
+#[derive(Debug, Clone)]
+pub(crate) struct WeakReference(Weak<Inner>);
+
+impl WeakReference {
+    pub(crate) fn upgrade(&self) -> Option<RemoteReference> {
+        self.0.upgrade().map(|inner| RemoteReference { inner })
+    }
+}
+
+impl RemoteReference {
+    pub(crate) fn new(broker: Broker, object_id: ObjectId) -> Self {
+        RemoteReference {
+            inner: Rc::new(Inner {
+                broker,
+                object_id,
+                on_disconnect: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakReference {
+        WeakReference(Rc::downgrade(&self.inner))
+    }
+
+    pub fn object_id(&self) -> &ObjectId {
+        &self.inner.object_id
+    }
+
+    pub fn broker(&self) -> &Broker {
+        &self.inner.broker
+    }
+
+    /// Call a remote method, i.e., `remote_<method>` on a Twisted
+    /// `Referenceable`.
+    pub fn call_remote(
+        &self,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+    ) -> CallFuture {
+        let target = Target::object(self.inner.object_id.clone());
+        self.inner.broker.call_remote(target, method, args, kwargs)
+    }
+
+    /// Call a remote method without waiting for any answer, as with Twisted's
+    /// `pbanswer=False`. Errors are ignored by the peer.
+    pub fn call_remote_no_answer(
+        &self,
+        method: &str,
+        args: &[JellyValue],
+        kwargs: &[(&str, JellyValue)],
+    ) -> Result<(), BrokerError> {
+        let target = Target::object(self.inner.object_id.clone());
+        self.inner.broker.send_call(target, method, args, kwargs, None).map(|_| ())
+    }
+
+    /// Have `callback` called when the connection is lost, or right away if
+    /// it already is.
+    ///
+    /// The callbacks don't keep the reference alive.
+    pub fn notify_on_disconnect<F: FnOnce(&RemoteReference) + 'static>(&self, callback: F) {
+        if self.inner.broker.is_closed() {
+            return callback(self);
+        }
+        let first = {
+            let mut on_disconnect = self.inner.on_disconnect.borrow_mut();
+            on_disconnect.push(Box::new(callback));
+            on_disconnect.len() == 1
+        };
+        if first {
+            self.inner.broker.notify_reference_on_disconnect(self.downgrade());
+        }
+    }
+
+    /// Run the callbacks of `notify_on_disconnect()`
+    pub(crate) fn disconnected(&self) {
+        let callbacks = self.inner.on_disconnect.replace(Vec::new());
+        for callback in callbacks {
+            callback(self);
+        }
+    }
+}
+
+impl PartialEq for RemoteReference {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.inner.broker.state, &other.inner.broker.state) &&
+            self.inner.object_id == other.inner.object_id
+    }
+}
+
+impl fmt::Debug for RemoteReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RemoteReference({:?})", self.inner.object_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{Element, PBMessage, PerspectiveBroker, PB};
+    use super::super::testing::connected_broker;
+
+    fn remote(luid: i64) -> PerspectiveBroker {
+        Element::List(vec![Element::Extension(PB::Remote), Element::integer(luid as i128)])
+    }
+
+    /// Receive the answer to a call, as `result`
+    fn receive(broker: &Broker, request_id: i64, result: PerspectiveBroker) -> RemoteReference {
+        let mut answer = broker.root_object().call_remote("get", &[], &[]);
+        broker.receive(&PBMessage::Answer { request_id, result }.build()).unwrap();
+        match answer.try_take() {
+            Some(Ok(JellyValue::Remote(reference))) => reference,
+            other => panic!("Expected a remote reference, got {:?}", other),
+        }
+    }
+
+
+    #[test]
+    fn decref_on_drop() {
+        let broker = connected_broker();
+        let first = receive(&broker, 1, remote(4));
+        let second = receive(&broker, 2, remote(4));
+        assert_eq!(first, second);
+        assert!(Rc::ptr_eq(&first.inner, &second.inner));
+        assert_eq!(broker.remote_reference_count(4), 2);
+        broker.take_outgoing();
+
+        let clone = first.clone();
+        drop(first);
+        drop(second);
+        assert!(broker.take_outgoing().is_empty());
+        drop(clone);
+        // one for each time the peer sent it
+        assert_eq!(
+            broker.take_outgoing(),
+            vec![PBMessage::DecRef(4).build(), PBMessage::DecRef(4).build()]
+        );
+        assert_eq!(broker.remote_reference_count(4), 0);
+
+        // received again, it's a new handle
+        let third = receive(&broker, 3, remote(4));
+        assert_eq!(broker.remote_reference_count(4), 1);
+        broker.take_outgoing();
+        broker.connection_lost();
+        drop(third);
+        assert!(broker.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn dropped_while_in_use() {
+        let broker = connected_broker();
+        let first = receive(&broker, 1, remote(4));
+        let second = receive(&broker, 2, remote(4));
+        let mut answer = broker.root_object().call_remote("get", &[], &[]);
+        broker.take_outgoing();
+        let state = broker.state.borrow_mut();
+        drop(first);
+        drop(second);
+        drop(state);
+        // received again before the decrefs were sent
+        broker.receive(&PBMessage::Answer {
+            request_id: 3,
+            result: remote(4),
+        }.build()).unwrap();
+        let third = answer.try_take();
+        assert_eq!(broker.remote_reference_count(4), 1);
+        assert_eq!(
+            broker.take_outgoing(),
+            vec![PBMessage::DecRef(4).build(), PBMessage::DecRef(4).build()]
+        );
+        // or sent with the next elements
+        let state = broker.state.borrow_mut();
+        drop(third);
+        drop(state);
+        assert_eq!(broker.take_outgoing(), vec![PBMessage::DecRef(4).build()]);
+    }
+
+    #[test]
+    fn notify_on_disconnect() {
+        let broker = connected_broker();
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        let reference = receive(&broker, 1, remote(2));
+        for i in 0..2 {
+            let notified = notified.clone();
+            reference.notify_on_disconnect(move |r| {
+                notified.borrow_mut().push((i, r.object_id().clone()))
+            });
+        }
+        // dropped references are not notified
+        let dropped = receive(&broker, 2, remote(3));
+        let n = notified.clone();
+        dropped.notify_on_disconnect(move |_| n.borrow_mut().push((2, ObjectId::Number(3))));
+        drop(dropped);
+
+        broker.connection_lost();
+        assert_eq!(
+            *notified.borrow(),
+            vec![(0, ObjectId::Number(2)), (1, ObjectId::Number(2))]
+        );
+        let n = notified.clone();
+        reference.notify_on_disconnect(move |_| n.borrow_mut().push((3, ObjectId::Number(2))));
+        assert_eq!(notified.borrow().len(), 3);
+    }
+}
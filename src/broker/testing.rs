@@ -0,0 +1,75 @@
+//! Fixtures shared by the tests of the broker
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use super::super::{PBMessage, PerspectiveBroker};
+use super::{Broker, BrokerError, BrokerEvent, CallResult, LoginError, LoginFuture, RemoteError};
+use super::RemoteReference;
+
+/// Deliver everything that `from` has to send, which must be accepted.
+pub(crate) fn pump(from: &Broker, to: &Broker) {
+    for elt in from.take_outgoing() {
+        // through the wire, to be sure of what Twisted would get
+        let elt = PerspectiveBroker::from_bytes(&elt.encode()).unwrap();
+        to.receive(&elt).unwrap();
+    }
+}
+
+/// Deliver everything that `from` has to send, collecting the events.
+pub(crate) fn pump_events(
+    from: &Broker,
+    to: &Broker,
+) -> Vec<Result<Option<BrokerEvent>, BrokerError>> {
+    from.take_outgoing()
+        .iter()
+        .map(|elt| {
+            let elt = PerspectiveBroker::from_bytes(&elt.encode()).unwrap();
+            to.receive(&elt)
+        })
+        .collect()
+}
+
+/// A client and a server that exchanged their versions
+pub(crate) fn connected() -> (Broker, Broker) {
+    let (client, server) = (Broker::new(), Broker::new());
+    for (from, to) in &[(&client, &server), (&server, &client)] {
+        for elt in from.take_outgoing() {
+            assert_eq!(to.receive(&elt), Ok(Some(BrokerEvent::Connected)));
+        }
+    }
+    (client, server)
+}
+
+/// A broker that got the version of its peer, with nothing left to send
+pub(crate) fn connected_broker() -> Broker {
+    let broker = Broker::new();
+    broker.receive(&PBMessage::Version(6).build()).unwrap();
+    broker.take_outgoing();
+    broker
+}
+
+/// The exception that a call failed with
+pub(crate) fn remote_error(res: Option<CallResult>) -> RemoteError {
+    match res {
+        Some(Err(ref err)) => err.remote_error().unwrap(),
+        other => panic!("Expected a failed call, got {:?}", other),
+    }
+}
+
+/// Exchange messages until the login is over
+pub(crate) fn run(
+    client: &Broker,
+    server: &Broker,
+    mut login: LoginFuture,
+) -> Result<RemoteReference, LoginError> {
+    let mut cx = Context::from_waker(Waker::noop());
+    for _ in 0..10 {
+        if let Poll::Ready(res) = Pin::new(&mut login).poll(&mut cx) {
+            return res;
+        }
+        pump(client, server);
+        pump(server, client);
+    }
+    panic!("Login is stuck");
+}
@@ -0,0 +1,492 @@
+//! Banana connections, starting with the dialect negotiation
+//!
+//! The server begins by sending the list of dialects it supports, and the client
+//! answers with the first one of them it knows of. From then on, elements are decoded
+//! and encoded according to the selected dialect, i.e., extension profile.
+//!
+//! This is sans-IO: bytes read from the transport are given to `receive()`, and those
+//! to write are retrieved with `take_outgoing()`.
+
+use std::any::{Any, TypeId};
+use std::mem;
+use std::sync::Arc;
+use super::registry::{DynElement, DynProfile, ProfileRegistry, StaticProfile};
+use super::{Banana, DecodeError, DynDecoder, Element, LocatedDecodeError, NoneProfile, Profile};
+use super::{PerspectiveBroker, PB};
+
+/// Dialects provided by this crate
+///
+/// Connections take a `ProfileRegistry`, which can also hold other profiles.
+/// `Dialect::registry` makes one out of these.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Dialect {
+    None,
+    PB,
+}
+
+impl Dialect {
+    /// Name of the dialect, as exchanged during negotiation
+    pub fn name(&self) -> &'static [u8] {
+        match *self {
+            Dialect::None => b"none",
+            Dialect::PB => b"pb",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"none" => Some(Dialect::None),
+            b"pb" => Some(Dialect::PB),
+            _ => None,
+        }
+    }
+
+    /// Registry of the given dialects, in the same order of preference
+    pub fn registry(dialects: &[Dialect]) -> ProfileRegistry {
+        let mut registry = ProfileRegistry::new();
+        for dialect in dialects {
+            match *dialect {
+                Dialect::None => registry.register::<NoneProfile>("none"),
+                Dialect::PB => registry.register::<PB>("pb"),
+            }
+        }
+        registry
+    }
+}
+
+/// Dialects in the same order of preference as Twisted's `knownDialects`
+pub const DEFAULT_DIALECTS: [Dialect; 2] = [Dialect::PB, Dialect::None];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ConnectionError {
+    /// Decoding error, located from the start of the connection
+    Decode(LocatedDecodeError),
+    /// The server offered none of the dialects we know (contains the offered ones)
+    NoCommonDialect(Vec<Vec<u8>>),
+    /// The client selected a dialect that we did not offer
+    UnsupportedDialect(Vec<u8>),
+    /// The negotiation message is not even of the expected type
+    InvalidNegotiation(String),
+    /// Attempt to send before the dialect is selected
+    NotReady,
+    /// Attempt to send elements that the selected dialect can't represent
+    WrongDialect(String),
+    /// The connection can't be used after an error, similarly to Twisted
+    /// dropping it.
+    Closed,
+}
+
+impl From<LocatedDecodeError> for ConnectionError {
+    fn from(err: LocatedDecodeError) -> Self {
+        ConnectionError::Decode(err)
+    }
+}
+
+impl From<DecodeError> for ConnectionError {
+    fn from(err: DecodeError) -> Self {
+        ConnectionError::Decode(err.into())
+    }
+}
+
+/// What can come out of a connection
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// The negotiation is over, happens exactly once
+    DialectSelected(String),
+    /// Element received in the selected dialect
+    Element(DynElement),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+    Negotiating,
+    Ready,
+    Closed,
+}
+
+/// Sans-IO Banana connection
+///
+/// The known dialects, in order of preference, are those of the given registry.
+#[derive(Debug)]
+pub struct BananaConnection {
+    role: Role,
+    registry: ProfileRegistry,
+    state: State,
+    /// During negotiation, the profile is 'none', and is switched afterwards.
+    decoder: DynDecoder,
+    outgoing: Vec<u8>,
+}
+
+impl BananaConnection {
+    fn new(role: Role, registry: &ProfileRegistry) -> Self {
+        let negotiation_profile: Arc<dyn DynProfile> =
+            Arc::new(StaticProfile::<NoneProfile>::new("none"));
+        BananaConnection {
+            role,
+            registry: registry.clone(),
+            state: State::Negotiating,
+            decoder: DynDecoder::new(negotiation_profile),
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Client side, the server speaks first
+    pub fn client(registry: &ProfileRegistry) -> Self {
+        Self::new(Role::Client, registry)
+    }
+
+    /// Server side. The offer of dialects is immediately ready to be sent.
+    pub fn server(registry: &ProfileRegistry) -> Self {
+        let mut conn = Self::new(Role::Server, registry);
+        let offer: Banana = Element::List(
+            registry
+                .names()
+                .iter()
+                .map(|name| Element::String(name.as_bytes().to_vec()))
+                .collect(),
+        );
+        offer.encode_in(&mut conn.outgoing);
+        conn
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Name of the selected dialect, if negotiation is over
+    pub fn dialect(&self) -> Option<&str> {
+        match self.state {
+            State::Ready => Some(self.decoder.profile().name()),
+            _ => None,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Give bytes read from the transport
+    pub fn receive(&mut self, bytes: &[u8]) {
+        if self.state != State::Closed {
+            self.decoder.feed(bytes);
+        }
+    }
+
+    /// Bytes to be written to the transport
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        mem::take(&mut self.outgoing)
+    }
+
+    /// Process received bytes, returning `Ok(None)` if more are needed.
+    ///
+    /// Any error is final: the connection should be dropped.
+    pub fn next_event(&mut self) -> Result<Option<Event>, ConnectionError> {
+        let res = self.process();
+        if res.is_err() {
+            self.state = State::Closed;
+        }
+        res
+    }
+
+    fn process(&mut self) -> Result<Option<Event>, ConnectionError> {
+        if self.state == State::Closed {
+            return Err(ConnectionError::Closed);
+        }
+        let elt = match self.decoder.next_element_located()? {
+            None => return Ok(None),
+            Some(elt) => elt,
+        };
+        if self.state == State::Ready {
+            return Ok(Some(Event::Element(elt)));
+        }
+        let profile = match self.role {
+            Role::Client => self.choose(elt)?,
+            Role::Server => self.check_choice(elt)?,
+        };
+        let name = profile.name().to_owned();
+        self.decoder.set_profile(profile);
+        self.state = State::Ready;
+        Ok(Some(Event::DialectSelected(name)))
+    }
+
+    /// Client side: pick the first offered dialect that we know, and answer with it.
+    fn choose(&mut self, offer: DynElement) -> Result<Arc<dyn DynProfile>, ConnectionError> {
+        let offered = match offer {
+            Element::List(l) => l,
+            other => {
+                return Err(ConnectionError::InvalidNegotiation(
+                    format!("Expected a list of dialects, got {}", other),
+                ));
+            }
+        };
+        let mut names = Vec::with_capacity(offered.len());
+        for item in offered {
+            if let Element::String(name) = item {
+                if let Some(profile) = self.lookup(&name) {
+                    let answer: Banana = Element::String(name);
+                    answer.encode_in(&mut self.outgoing);
+                    return Ok(profile);
+                }
+                names.push(name);
+            }
+        }
+        Err(ConnectionError::NoCommonDialect(names))
+    }
+
+    /// Server side: the client must have selected one of the offered dialects
+    fn check_choice(&self, choice: DynElement) -> Result<Arc<dyn DynProfile>, ConnectionError> {
+        match choice {
+            Element::String(name) => {
+                match self.lookup(&name) {
+                    Some(profile) => Ok(profile),
+                    None => Err(ConnectionError::UnsupportedDialect(name)),
+                }
+            }
+            other => Err(ConnectionError::InvalidNegotiation(
+                format!("Expected a dialect name, got {}", other),
+            )),
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<Arc<dyn DynProfile>> {
+        String::from_utf8(name.to_vec())
+            .ok()
+            .and_then(|name| self.registry.get(&name))
+    }
+
+    /// Send an element.
+    ///
+    /// Its profile must be the one of the selected dialect, or none at all.
+    /// Elements with dynamic extensions are checked and encoded against the
+    /// selected dialect, strings going through its vocabulary.
+    pub fn send<P: Profile + 'static>(&mut self, elt: &Element<P>) -> Result<(), ConnectionError> {
+        match self.state {
+            State::Negotiating => return Err(ConnectionError::NotReady),
+            State::Closed => return Err(ConnectionError::Closed),
+            State::Ready => {}
+        }
+        let profile = self.decoder.profile();
+        let wrong_dialect = || ConnectionError::WrongDialect(profile.name().to_owned());
+        let type_id = TypeId::of::<P>();
+        if let Some(elt) = (elt as &dyn Any).downcast_ref::<DynElement>() {
+            if !elt.is_of_dialect(&**profile) {
+                return Err(wrong_dialect());
+            }
+            elt.encode_in_dialect(&**profile, &mut self.outgoing);
+            return Ok(());
+        }
+        if type_id != TypeId::of::<NoneProfile>() && Some(type_id) != profile.profile_type_id() {
+            return Err(wrong_dialect());
+        }
+        elt.encode_in(&mut self.outgoing);
+        Ok(())
+    }
+
+    /// Send an element of the `pb` dialect
+    pub fn send_pb(&mut self, elt: &PerspectiveBroker) -> Result<(), ConnectionError> {
+        self.send(elt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Banana {
+        Element::String(s.as_bytes().to_vec())
+    }
+
+    fn registry(names: &[&str]) -> ProfileRegistry {
+        let mut registry = ProfileRegistry::new();
+        for name in names {
+            match *name {
+                "pb" => registry.register::<PB>("pb"),
+                _ => registry.register::<NoneProfile>(name),
+            }
+        }
+        registry
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut server = BananaConnection::server(&Dialect::registry(&DEFAULT_DIALECTS));
+        let mut client = BananaConnection::client(&Dialect::registry(&DEFAULT_DIALECTS));
+        let offer = server.take_outgoing();
+        assert_eq!(
+            Banana::from_bytes(&offer),
+            Ok(Element::List(vec![string("pb"), string("none")]))
+        );
+        assert_eq!(client.send(&Banana::Integer(1)), Err(ConnectionError::NotReady));
+
+        client.receive(&offer[..3]);
+        assert_eq!(client.next_event(), Ok(None));
+        client.receive(&offer[3..]);
+        assert_eq!(
+            client.next_event(),
+            Ok(Some(Event::DialectSelected("pb".into())))
+        );
+        assert_eq!(client.dialect(), Some("pb"));
+
+        // the client can speak right away, in the same packet
+        let msg: PerspectiveBroker =
+            Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)]);
+        client.send_pb(&msg).unwrap();
+        server.receive(&client.take_outgoing());
+        assert_eq!(
+            server.next_event(),
+            Ok(Some(Event::DialectSelected("pb".into())))
+        );
+        assert_eq!(server.next_event(), Ok(Some(Event::Element(msg.into_dyn()))));
+        assert_eq!(server.next_event(), Ok(None));
+
+        server.send(&string("hello")).unwrap();
+        client.receive(&server.take_outgoing());
+        assert_eq!(
+            client.next_event(),
+            Ok(Some(Event::Element(Element::String(b"hello".to_vec()))))
+        );
+    }
+
+    #[test]
+    fn server_order_wins() {
+        let dialects = [Dialect::None, Dialect::PB];
+        let mut server = BananaConnection::server(&Dialect::registry(&dialects));
+        let mut client = BananaConnection::client(&Dialect::registry(&DEFAULT_DIALECTS));
+        client.receive(&server.take_outgoing());
+        assert_eq!(
+            client.next_event(),
+            Ok(Some(Event::DialectSelected("none".into())))
+        );
+        assert_eq!(Banana::from_bytes(&client.take_outgoing()), Ok(string("none")));
+        assert_eq!(
+            client.send_pb(&Element::Extension(PB::Version)),
+            Err(ConnectionError::WrongDialect("none".into()))
+        );
+    }
+
+    #[test]
+    fn pb_elements_not_in_none_dialect() {
+        let mut server = BananaConnection::server(&Dialect::registry(&[Dialect::None]));
+        server.receive(&string("none").encode());
+        server.receive(&[0x13, 0x87]);
+        assert_eq!(
+            server.next_event(),
+            Ok(Some(Event::DialectSelected("none".into())))
+        );
+        match server.next_event() {
+            Err(ConnectionError::Decode(err)) => {
+                assert_eq!(err.error, DecodeError::UnknownType(0x87));
+                // offsets are counted from the start of the connection
+                assert_eq!(err.offset, 6);
+            }
+            other => panic!("Expected a decoding error, got {:?}", other),
+        }
+        assert!(server.is_closed());
+        assert_eq!(server.next_event(), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn user_dialect() {
+        let mut server = BananaConnection::server(&registry(&["pb", "mine"]));
+        let mut client = BananaConnection::client(&registry(&["mine"]));
+        client.receive(&server.take_outgoing());
+        assert_eq!(
+            client.next_event(),
+            Ok(Some(Event::DialectSelected("mine".into())))
+        );
+        server.receive(&client.take_outgoing());
+        assert_eq!(
+            server.next_event(),
+            Ok(Some(Event::DialectSelected("mine".into())))
+        );
+        assert_eq!(server.dialect(), Some("mine"));
+    }
+
+    #[test]
+    fn dynamic_elements() {
+        let mut server = BananaConnection::server(&ProfileRegistry::default());
+        let mut client = BananaConnection::client(&ProfileRegistry::default());
+        client.receive(&server.take_outgoing());
+        assert!(client.next_event().unwrap().is_some());
+        server.receive(&client.take_outgoing());
+        assert!(server.next_event().unwrap().is_some());
+
+        // strings go through the vocabulary of the dialect
+        let msg: PerspectiveBroker =
+            Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)]);
+        let forwarded: DynElement =
+            Element::List(vec![msg.clone().into_dyn(), Element::String(b"version".to_vec())]);
+        server.send(&forwarded).unwrap();
+        let expected: PerspectiveBroker =
+            Element::List(vec![msg.clone(), Element::Extension(PB::Version)]);
+        assert_eq!(server.take_outgoing(), expected.encode());
+
+        let mut server = BananaConnection::server(&Dialect::registry(&[Dialect::None]));
+        server.receive(&string("none").encode());
+        assert!(server.next_event().unwrap().is_some());
+        server.take_outgoing();
+        assert_eq!(
+            server.send(&Element::List(vec![Element::Integer(1), msg.into_dyn()])),
+            Err(ConnectionError::WrongDialect("none".into()))
+        );
+        assert!(server.take_outgoing().is_empty());
+        server.send(&Element::List(vec![string("version").into_dyn()])).unwrap();
+        assert_eq!(
+            server.take_outgoing(),
+            Element::List(vec![string("version")]).encode()
+        );
+    }
+
+    #[test]
+    fn no_common_dialect() {
+        let mut client = BananaConnection::client(&Dialect::registry(&[Dialect::PB]));
+        let offer: Banana = Element::List(vec![string("none"), Element::Integer(3), string("v2")]);
+        client.receive(&offer.encode());
+        assert_eq!(
+            client.next_event(),
+            Err(ConnectionError::NoCommonDialect(
+                vec![b"none".to_vec(), b"v2".to_vec()],
+            ))
+        );
+        assert!(client.take_outgoing().is_empty());
+
+        let mut client = BananaConnection::client(&Dialect::registry(&[Dialect::PB]));
+        client.receive(&string("pb").encode());
+        assert!(matches!(
+            client.next_event(),
+            Err(ConnectionError::InvalidNegotiation(_))
+        ));
+    }
+
+    #[test]
+    fn unsupported_dialect() {
+        let mut server = BananaConnection::server(&Dialect::registry(&[Dialect::PB]));
+        server.receive(&string("none").encode());
+        assert_eq!(
+            server.next_event(),
+            Err(ConnectionError::UnsupportedDialect(b"none".to_vec()))
+        );
+
+        let mut server = BananaConnection::server(&Dialect::registry(&DEFAULT_DIALECTS));
+        server.receive(&string("jelly").encode());
+        assert_eq!(
+            server.next_event(),
+            Err(ConnectionError::UnsupportedDialect(b"jelly".to_vec()))
+        );
+
+        let mut server = BananaConnection::server(&Dialect::registry(&DEFAULT_DIALECTS));
+        server.receive(&Element::List(vec![string("pb")]).encode());
+        assert!(matches!(
+            server.next_event(),
+            Err(ConnectionError::InvalidNegotiation(_))
+        ));
+        assert_eq!(Dialect::from_name(b"pb"), Some(Dialect::PB));
+        assert_eq!(Dialect::None.name(), b"none");
+    }
+}
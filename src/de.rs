@@ -0,0 +1,488 @@
+//! Serde deserialization from Banana elements
+//!
+//! See the crate documentation for the mapping rules.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::vec;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use super::ser::SerdeError;
+use super::{Banana, Element};
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+/// Deserialize from an already decoded element
+pub fn from_element<T: DeserializeOwned>(elt: Banana) -> Result<T, SerdeError> {
+    T::deserialize(Deserializer(elt))
+}
+
+/// Deserialize from bytes holding exactly one element
+///
+/// The default `DecodeLimits` apply.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SerdeError> {
+    let (elt, rem) = Banana::from_bytes_rem(bytes)?;
+    if !rem.is_empty() {
+        return Err(SerdeError::TrailingBytes(rem.len()));
+    }
+    from_element(elt)
+}
+
+/// What serde calls an unexpected value, for error messages
+fn unexpected(elt: &Banana) -> de::Unexpected<'_> {
+    match *elt {
+        Element::Integer(i) => de::Unexpected::Signed(i as i64),
+        Element::LongInteger(_) => de::Unexpected::Other("long integer"),
+        Element::String(ref s) => de::Unexpected::Bytes(s),
+        Element::Float(f) => de::Unexpected::Float(f),
+        Element::List(_) => de::Unexpected::Seq,
+        Element::Extension(ref p) => match *p {},
+    }
+}
+
+struct Deserializer(Banana);
+
+impl Deserializer {
+    fn invalid<T>(&self, expected: &dyn de::Expected) -> Result<T, SerdeError> {
+        Err(de::Error::invalid_type(unexpected(&self.0), expected))
+    }
+
+    fn integer(&self, expected: &dyn de::Expected) -> Result<i128, SerdeError> {
+        match self.0 {
+            Element::Integer(i) => Ok(i as i128),
+            Element::LongInteger(i) => Ok(i),
+            _ => self.invalid(expected),
+        }
+    }
+
+    fn string(self, expected: &dyn de::Expected) -> Result<String, SerdeError> {
+        match self.0 {
+            Element::String(s) => String::from_utf8(s).map_err(|err| {
+                de::Error::invalid_value(de::Unexpected::Bytes(err.as_bytes()), expected)
+            }),
+            _ => self.invalid(expected),
+        }
+    }
+
+    fn list(self, expected: &dyn de::Expected) -> Result<Vec<Banana>, SerdeError> {
+        match self.0 {
+            Element::List(l) => Ok(l),
+            _ => self.invalid(expected),
+        }
+    }
+}
+
+/// Range-checked integer deserialization
+macro_rules! deserialize_integer {
+    ($method:ident, $ty:ty, $visit:ident) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+            let i = self.integer(&visitor)?;
+            match <$ty>::try_from(i) {
+                Ok(v) => visitor.$visit(v),
+                Err(_) => Err(SerdeError::IntegerOutOfRange(i.to_string())),
+            }
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Element::Integer(i) => visitor.visit_i32(i),
+            Element::LongInteger(i) => visitor.visit_i128(i),
+            Element::Float(f) => visitor.visit_f64(f),
+            Element::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(err) => visitor.visit_byte_buf(err.into_bytes()),
+            },
+            Element::List(l) => visitor.visit_seq(SeqDeserializer(l.into_iter())),
+            Element::Extension(p) => match p {},
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Element::Integer(0) => visitor.visit_bool(false),
+            Element::Integer(1) => visitor.visit_bool(true),
+            _ => self.invalid(&visitor),
+        }
+    }
+
+    deserialize_integer!(deserialize_i8, i8, visit_i8);
+    deserialize_integer!(deserialize_i16, i16, visit_i16);
+    deserialize_integer!(deserialize_i32, i32, visit_i32);
+    deserialize_integer!(deserialize_i64, i64, visit_i64);
+    deserialize_integer!(deserialize_i128, i128, visit_i128);
+    deserialize_integer!(deserialize_u8, u8, visit_u8);
+    deserialize_integer!(deserialize_u16, u16, visit_u16);
+    deserialize_integer!(deserialize_u32, u32, visit_u32);
+    deserialize_integer!(deserialize_u64, u64, visit_u64);
+    deserialize_integer!(deserialize_u128, u128, visit_u128);
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Element::Float(f) => visitor.visit_f64(f),
+            _ => self.invalid(&visitor),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let s = self.string(&visitor)?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => visitor.visit_char(c),
+            _ => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &visitor)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let s = self.string(&visitor)?;
+        visitor.visit_string(s)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Element::String(s) => visitor.visit_byte_buf(s),
+            _ => self.invalid(&visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let mut l = self.list(&visitor)?;
+        match l.len() {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(Deserializer(l.remove(0))),
+            n => Err(de::Error::invalid_length(n, &"an option as a list of 0 or 1 item")),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let l = self.list(&visitor)?;
+        if !l.is_empty() {
+            return Err(de::Error::invalid_length(l.len(), &"an empty list"));
+        }
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let l = self.list(&visitor)?;
+        visitor.visit_seq(SeqDeserializer(l.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let l = self.list(&visitor)?;
+        visitor.visit_map(MapDeserializer {
+            entries: l.into_iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Element::String(_) => visitor.visit_enum(EnumDeserializer {
+                variant: self.0,
+                payload: None,
+            }),
+            Element::List(l) => {
+                let mut items = l.into_iter();
+                match items.next() {
+                    Some(variant) => visitor.visit_enum(EnumDeserializer {
+                        variant,
+                        payload: Some(items),
+                    }),
+                    None => Err(de::Error::invalid_length(0, &"a variant name")),
+                }
+            }
+            _ => self.invalid(&visitor),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqDeserializer(vec::IntoIter<Banana>);
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, SerdeError> {
+        match self.0.next() {
+            Some(elt) => seed.deserialize(Deserializer(elt)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// Reads `[key, value]` lists
+struct MapDeserializer {
+    entries: vec::IntoIter<Banana>,
+    value: Option<Banana>,
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        match self.entries.next() {
+            None => Ok(None),
+            Some(Element::List(mut kv)) => {
+                if kv.len() != 2 {
+                    return Err(de::Error::invalid_length(kv.len(), &"a [key, value] list"));
+                }
+                self.value = kv.pop();
+                seed.deserialize(Deserializer(kv.remove(0))).map(Some)
+            }
+            Some(other) => Err(de::Error::invalid_type(unexpected(&other), &"a [key, value] list")),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Deserializer(value)),
+            None => Err(de::Error::custom("map value without a key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// The variant name, and the remaining items, if it was in a list
+struct EnumDeserializer {
+    variant: Banana,
+    payload: Option<vec::IntoIter<Banana>>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = SerdeError;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantDeserializer), SerdeError> {
+        let name = Deserializer(self.variant).string(&"a variant name")?;
+        let name: de::value::StringDeserializer<SerdeError> = name.into_deserializer();
+        let value = seed.deserialize(name)?;
+        Ok((value, VariantDeserializer(self.payload)))
+    }
+}
+
+struct VariantDeserializer(Option<vec::IntoIter<Banana>>);
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self.0 {
+            Some(ref items) if items.len() != 0 => {
+                Err(de::Error::invalid_length(items.len(), &"no payload for a unit variant"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        let mut items = match self.0 {
+            Some(items) => items,
+            None => return Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"a payload")),
+        };
+        match (items.next(), items.len()) {
+            (Some(elt), 0) => seed.deserialize(Deserializer(elt)),
+            (_, n) => Err(de::Error::invalid_length(n + 1, &"a single payload item")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Some(items) => visitor.visit_seq(SeqDeserializer(items)),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &visitor)),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.tuple_variant(0, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use serde::{Deserialize, Serialize};
+    use super::super::ser::to_bytes;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Step {
+        number: u16,
+        name: String,
+        results: Option<i64>,
+        command: Command,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Command {
+        Stop,
+        Log(String),
+        Move(i32, i32),
+        Rename { from: String, to: String },
+    }
+
+    fn roundtrip<T: Serialize + DeserializeOwned + fmt::Debug + PartialEq>(value: T) {
+        let bytes = to_bytes(&value).unwrap();
+        assert_eq!(from_bytes::<T>(&bytes), Ok(value));
+    }
+
+    #[test]
+    fn roundtrips() {
+        for command in [
+            Command::Stop,
+            Command::Log("up".into()),
+            Command::Move(-1, 1 << 20),
+            Command::Rename { from: "a".into(), to: "b".into() },
+        ] {
+            roundtrip(Step {
+                number: 3,
+                name: "compile".into(),
+                results: Some(1 << 40),
+                command,
+            });
+        }
+        let mut map = BTreeMap::new();
+        map.insert(String::from("one"), vec![1.5, -2.0]);
+        map.insert(String::from("none"), vec![]);
+        roundtrip(map);
+        roundtrip((true, 'é', None::<u8>, u64::MAX, i128::MIN));
+    }
+
+    #[test]
+    fn from_twisted() {
+        // what a Python peer would send for ['Move', 3, -4]
+        let bytes = &[0x03, 0x80, 0x04, 0x82, b'M', b'o', b'v', b'e', 0x03, 0x81, 0x04, 0x83];
+        assert_eq!(from_bytes::<Command>(bytes), Ok(Command::Move(3, -4)));
+        assert_eq!(
+            from_element::<Command>(Element::String(b"Stop".to_vec())),
+            Ok(Command::Stop)
+        );
+        assert!(matches!(
+            from_element::<String>(Element::String(b"\xff".to_vec())),
+            Err(SerdeError::Custom(_))
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            from_element::<u8>(Element::Integer(256)),
+            Err(SerdeError::IntegerOutOfRange("256".into()))
+        );
+        assert_eq!(
+            from_element::<u32>(Element::Integer(-1)),
+            Err(SerdeError::IntegerOutOfRange("-1".into()))
+        );
+        assert!(matches!(
+            from_element::<String>(Element::Integer(1)),
+            Err(SerdeError::Custom(_))
+        ));
+        assert_eq!(
+            from_bytes::<i32>(&[0x01, 0x81, 0x02]),
+            Err(SerdeError::TrailingBytes(1))
+        );
+        assert!(matches!(from_bytes::<i32>(&[0x01]), Err(SerdeError::Decode(_))));
+    }
+}
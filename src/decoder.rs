@@ -0,0 +1,335 @@
+//! Incremental decoding of Banana elements
+//!
+//! Network reads have no reason to align with element boundaries. The `Decoder`
+//! accepts bytes as they come, and yields top-level elements once they are complete.
+//! Similarly to the reference implementation, fully decoded items of unfinished lists
+//! are kept aside, so that only the currently incomplete item needs to stay in the buffer.
+
+use std::sync::Arc;
+use super::banana::{ExtensionDecoding, Head, StaticDecoding};
+use super::registry::{DynElement, DynExtension, DynProfile};
+use super::{Profile, DecodeError, DecodeLimits, Element, LocatedDecodeError};
+
+/// Stateful, push-based decoder of Banana elements
+#[derive(Debug)]
+pub struct Decoder<P: Profile> {
+    /// Received bytes that aren't decoded yet
+    buffer: Vec<u8>,
+    /// Lists being decoded, from outermost to innermost, with their expected lengths
+    stack: Vec<(usize, Vec<Element<P>>)>,
+    limits: DecodeLimits,
+    /// Allocation total for the current top-level element
+    used: usize,
+    /// Position of the buffer start in the whole stream
+    offset: usize,
+}
+
+impl<P: Profile> Default for Decoder<P> {
+    fn default() -> Self {
+        Self::with_limits(DecodeLimits::default())
+    }
+}
+
+impl<P: Profile> Decoder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Decoder {
+            buffer: Vec::new(),
+            stack: Vec::new(),
+            limits,
+            used: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn limits(&self) -> &DecodeLimits {
+        &self.limits
+    }
+
+    /// Append incoming bytes
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// `true` if there is no partially decoded element
+    pub fn is_idle(&self) -> bool {
+        self.buffer.is_empty() && self.stack.is_empty()
+    }
+
+    /// Consume the decoder, returning the bytes that haven't been decoded yet.
+    ///
+    /// This is meant to hand over to another decoder, such as one with a different
+    /// profile. Partially decoded lists are lost, hence this makes sense only right
+    /// after a top-level element has been returned.
+    pub fn into_unprocessed(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Decode next complete top-level element.
+    ///
+    /// Returns `Ok(None)` if more bytes are needed to complete it.
+    /// After an error, the state of the decoder is unspecified: the stream can't
+    /// be trusted any more and the connection should be dropped.
+    pub fn next_element(&mut self) -> Result<Option<Element<P>>, DecodeError> {
+        self.next_element_located().map_err(|err| err.error)
+    }
+
+    /// Same as `next_element`, telling where decoding failed in the stream.
+    pub fn next_element_located(&mut self) -> Result<Option<Element<P>>, LocatedDecodeError> {
+        self.next_element_with(&StaticDecoding)
+    }
+
+    fn next_element_with<X: ExtensionDecoding<P> + ?Sized>(
+        &mut self,
+        ext: &X,
+    ) -> Result<Option<Element<P>>, LocatedDecodeError> {
+        let mut consumed = 0;
+        let res = loop {
+            let rem = &self.buffer[consumed..];
+            let elt = match Element::head_from_bytes(rem, ext, &self.limits, &mut self.used) {
+                Ok((Head::List(list_len), new_rem)) => {
+                    if self.stack.len() >= self.limits.max_depth {
+                        break Err(DecodeError::TooDeep(self.limits.max_depth));
+                    }
+                    consumed += rem.len() - new_rem.len();
+                    if list_len == 0 {
+                        Element::List(Vec::new())
+                    } else {
+                        self.stack.push((list_len, Vec::new()));
+                        continue;
+                    }
+                }
+                Ok((Head::Atom(elt), new_rem)) => {
+                    consumed += rem.len() - new_rem.len();
+                    elt.into_owned()
+                }
+                Err(ref err) if err.is_incomplete() => break Ok(None),
+                Err(err) => break Err(err),
+            };
+            if let Some(top) = self.complete(elt) {
+                self.used = 0;
+                break Ok(Some(top));
+            }
+        };
+        self.buffer.drain(..consumed);
+        self.offset += consumed;
+        res.map_err(|err| {
+            let path = self.stack.iter().map(|level| level.1.len()).collect();
+            LocatedDecodeError::from(err).at(self.offset, path)
+        })
+    }
+
+    /// Append a decoded element to the innermost list being decoded, and close
+    /// all lists that are full as a result.
+    ///
+    /// Returns the top-level element, if that completes it.
+    fn complete(&mut self, elt: Element<P>) -> Option<Element<P>> {
+        let mut elt = elt;
+        while let Some((expected, mut items)) = self.stack.pop() {
+            items.push(elt);
+            if items.len() < expected {
+                self.stack.push((expected, items));
+                return None;
+            }
+            elt = Element::List(items);
+        }
+        Some(elt)
+    }
+}
+
+/// Incremental decoder whose profile can be switched at runtime
+///
+/// Switching is meant to happen between top-level elements, as in the
+/// dialect negotiation.
+#[derive(Debug)]
+pub struct DynDecoder {
+    decoder: Decoder<DynExtension>,
+    profile: Arc<dyn DynProfile>,
+}
+
+impl DynDecoder {
+    pub fn new(profile: Arc<dyn DynProfile>) -> Self {
+        Self::with_limits(profile, DecodeLimits::default())
+    }
+
+    pub fn with_limits(profile: Arc<dyn DynProfile>, limits: DecodeLimits) -> Self {
+        DynDecoder {
+            decoder: Decoder::with_limits(limits),
+            profile,
+        }
+    }
+
+    pub fn profile(&self) -> &Arc<dyn DynProfile> {
+        &self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: Arc<dyn DynProfile>) {
+        self.profile = profile;
+    }
+
+    /// Append incoming bytes
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.decoder.feed(bytes);
+    }
+
+    /// `true` if there is no partially decoded element
+    pub fn is_idle(&self) -> bool {
+        self.decoder.is_idle()
+    }
+
+    /// Decode next complete top-level element with the current profile.
+    ///
+    /// Same semantics as `Decoder::next_element`.
+    pub fn next_element(&mut self) -> Result<Option<DynElement>, DecodeError> {
+        self.next_element_located().map_err(|err| err.error)
+    }
+
+    /// Same as `next_element`, telling where decoding failed in the stream.
+    pub fn next_element_located(&mut self) -> Result<Option<DynElement>, LocatedDecodeError> {
+        self.decoder.next_element_with(&*self.profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Banana, NoneProfile, PerspectiveBroker, PB};
+
+    #[test]
+    fn byte_by_byte() {
+        let bytes: &[u8] = &[
+            2, 0x80, 1, 0x81, 1, 0x80, 5, 0x82, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+        ];
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        for b in &bytes[..bytes.len() - 1] {
+            decoder.feed(&[*b]);
+            assert_eq!(decoder.next_element(), Ok(None));
+            assert!(!decoder.is_idle());
+        }
+        decoder.feed(&bytes[bytes.len() - 1..]);
+        assert_eq!(
+            decoder.next_element(),
+            Ok(Some(Element::List(vec![
+                Element::Integer(1),
+                Element::List(vec![
+                    Element::String(String::from("hello").into_bytes()),
+                ]),
+            ])))
+        );
+        assert!(decoder.is_idle());
+        assert_eq!(decoder.next_element(), Ok(None));
+    }
+
+    #[test]
+    fn several_elements() {
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        decoder.feed(&[0x01, 0x81, 0, 0x80, 0x84, 0x3f, 0xf8, 0, 0]);
+        assert_eq!(decoder.next_element(), Ok(Some(Element::Integer(1))));
+        assert_eq!(decoder.next_element(), Ok(Some(Element::List(vec![]))));
+        assert_eq!(decoder.next_element(), Ok(None));
+        decoder.feed(&[0, 0, 0, 0, 0x03, 0x83]);
+        assert_eq!(decoder.next_element(), Ok(Some(Element::Float(1.5))));
+        assert_eq!(decoder.next_element(), Ok(Some(Element::Integer(-3))));
+        assert_eq!(decoder.next_element(), Ok(None));
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn split_within_string() {
+        let elt: Banana = Element::List(vec![
+            Element::String(vec![b'x'; 300]),
+            Element::List(vec![Element::Integer(-6674)]),
+        ]);
+        let bytes = elt.encode();
+        for split in 0..bytes.len() {
+            let mut decoder = Decoder::new();
+            decoder.feed(&bytes[..split]);
+            assert_eq!(decoder.next_element(), Ok(None));
+            decoder.feed(&bytes[split..]);
+            assert_eq!(decoder.next_element(), Ok(Some(elt.clone())));
+        }
+    }
+
+    #[test]
+    fn with_profile() {
+        let bytes: &[u8] = &[0x02, 0x80, 0x13, 0x87, 0x06, 0x81];
+        let mut decoder: Decoder<PB> = Decoder::new();
+        decoder.feed(&bytes[..3]);
+        assert_eq!(decoder.next_element(), Ok(None));
+        decoder.feed(&bytes[3..]);
+        let expected: PerspectiveBroker =
+            Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)]);
+        assert_eq!(decoder.next_element(), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn limits() {
+        // hostile announced lengths are detected without waiting for the contents
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        decoder.feed(&[0x00, 0x00, 0x40, 0x82, b'a']);
+        assert_eq!(
+            decoder.next_element(),
+            Err(DecodeError::StringTooLong(1 << 20, 640 * 1024))
+        );
+
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        decoder.feed(&[0x01; 64]);
+        assert_eq!(decoder.next_element(), Ok(None));
+        decoder.feed(&[0x01]);
+        assert_eq!(
+            decoder.next_element(),
+            Err(DecodeError::PrefixTooLong(65, 64))
+        );
+
+        let mut decoder: Decoder<NoneProfile> = Decoder::with_limits(DecodeLimits {
+            max_depth: 2,
+            ..DecodeLimits::default()
+        });
+        decoder.feed(&[0x01, 0x80, 0x01, 0x80, 0x00, 0x81]);
+        assert_eq!(
+            decoder.next_element(),
+            Ok(Some(Element::List(vec![Element::List(vec![Element::Integer(0)])])))
+        );
+        decoder.feed(&[0x01, 0x80, 0x01, 0x80, 0x00, 0x80]);
+        assert_eq!(decoder.next_element(), Err(DecodeError::TooDeep(2)));
+
+        // allocation budget is per top-level element
+        let mut decoder: Decoder<NoneProfile> = Decoder::with_limits(DecodeLimits {
+            max_alloc: 5,
+            ..DecodeLimits::default()
+        });
+        decoder.feed(&[0x03, 0x82, b'b', b'a', b'n']);
+        decoder.feed(&[0x03, 0x82, b'b', b'a', b'n']);
+        decoder.feed(&[0x06, 0x82, b'b', b'a', b'n']);
+        let ban = Element::String(String::from("ban").into_bytes());
+        assert_eq!(decoder.next_element(), Ok(Some(ban.clone())));
+        assert_eq!(decoder.next_element(), Ok(Some(ban)));
+        assert_eq!(decoder.next_element(), Err(DecodeError::OverBudget(5)));
+    }
+
+    #[test]
+    fn error() {
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        decoder.feed(&[0x01, 0x80, 0x01, 0xfe]);
+        assert_eq!(decoder.next_element(), Err(DecodeError::UnknownType(0xfe)));
+    }
+
+    #[test]
+    fn error_location() {
+        let mut decoder: Decoder<NoneProfile> = Decoder::new();
+        decoder.feed(&[0x01, 0x81]);
+        assert_eq!(decoder.next_element(), Ok(Some(Element::Integer(1))));
+        for b in &[0x02, 0x80, 0x01, 0x81, 0x02, 0x80, 0x02, 0x81] {
+            decoder.feed(&[*b]);
+            assert_eq!(decoder.next_element(), Ok(None));
+        }
+        decoder.feed(&[0xfe]);
+        let err = decoder.next_element_located().unwrap_err();
+        assert_eq!(err.error, DecodeError::UnknownType(0xfe));
+        assert_eq!(err.offset, 10);
+        assert_eq!(err.path, vec![1, 1]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Borrowed Banana elements, for zero-copy decoding
+//!
+//! Strings are the bulk of most messages (think of buildbot log chunks). Decoding
+//! them as `ElementRef` just points into the input, whereas decoding an `Element`
+//! copies them.
+
+use std::cmp;
+use super::banana::{Head, StaticDecoding};
+use super::{Profile, DecodeError, DecodeLimits, Element, LocatedDecodeError};
+
+/// Borrowed counterpart of `Element`
+///
+/// Lists are decoded eagerly, only strings are borrowed.
+///
+/// `to_owned()` gives back an `Element`, not another `ElementRef` as the
+/// `ToOwned` of `Clone` types would.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ElementRef<'a, P: Profile> {
+    Integer(i32),
+    LongInteger(i128),
+    String(&'a [u8]),
+    Float(f64),
+    List(Vec<ElementRef<'a, P>>),
+    Extension(P),
+}
+
+impl<'a, P: Profile> ElementRef<'a, P> {
+    /// Decode an element, including length/type preamble, and ignore the remainder
+    ///
+    /// The default `DecodeLimits` apply.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        Ok(Self::from_bytes_rem(bytes)?.0)
+    }
+
+    /// Same as `from_bytes`, with explicit limits.
+    pub fn from_bytes_limited(bytes: &'a [u8], limits: &DecodeLimits) -> Result<Self, DecodeError> {
+        Ok(Self::from_bytes_rem_limited(bytes, limits)?.0)
+    }
+
+    /// Decode an element, returning it together with the remaining bytes
+    ///
+    /// The default `DecodeLimits` apply.
+    pub fn from_bytes_rem(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), DecodeError> {
+        Self::from_bytes_rem_limited(bytes, &DecodeLimits::default())
+    }
+
+    /// Same as `from_bytes_rem`, with explicit limits.
+    pub fn from_bytes_rem_limited(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<(Self, &'a [u8]), DecodeError> {
+        Self::from_bytes_rem_located(bytes, limits).map_err(|err| err.error)
+    }
+
+    /// Same as `from_bytes_limited`, telling where decoding failed.
+    pub fn from_bytes_located(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<Self, LocatedDecodeError> {
+        Ok(Self::from_bytes_rem_located(bytes, limits)?.0)
+    }
+
+    /// Same as `from_bytes_rem_limited`, telling where decoding failed.
+    pub fn from_bytes_rem_located(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        let mut used = 0;
+        Self::dec_rem(bytes, limits, 0, &mut used)
+    }
+
+    fn dec_rem(
+        bytes: &'a [u8],
+        limits: &DecodeLimits,
+        depth: usize,
+        used: &mut usize,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        match Element::head_from_bytes(bytes, &StaticDecoding, limits, used)? {
+            (Head::Atom(elt), rem) => Ok((elt, rem)),
+            (Head::List(list_len), items) => {
+                if depth >= limits.max_depth {
+                    return Err(DecodeError::TooDeep(limits.max_depth).into());
+                }
+                let mut resv = Vec::with_capacity(cmp::min(list_len, items.len()));
+                let mut rem = items;
+                for i in 0..list_len {
+                    let item_rem = Self::dec_rem(rem, limits, depth + 1, used)
+                        .map_err(|err| err.within(bytes.len() - rem.len(), i))?;
+                    resv.push(item_rem.0);
+                    rem = item_rem.1;
+                }
+                Ok((ElementRef::List(resv), rem))
+            }
+        }
+    }
+
+    /// Convert into an owned `Element`, copying the strings
+    pub fn into_owned(self) -> Element<P> {
+        match self {
+            ElementRef::Integer(i) => Element::Integer(i),
+            ElementRef::LongInteger(i) => Element::LongInteger(i),
+            ElementRef::String(s) => Element::String(s.into()),
+            ElementRef::Float(f) => Element::Float(f),
+            ElementRef::List(l) => Element::List(l.into_iter().map(Self::into_owned).collect()),
+            ElementRef::Extension(p) => Element::Extension(p),
+        }
+    }
+}
+
+impl<'a, P: Profile + Clone> ElementRef<'a, P> {
+    /// Same as `into_owned`, without consuming `self`
+    pub fn to_owned(&self) -> Element<P> {
+        match *self {
+            ElementRef::Integer(i) => Element::Integer(i),
+            ElementRef::LongInteger(i) => Element::LongInteger(i),
+            ElementRef::String(s) => Element::String(s.into()),
+            ElementRef::Float(f) => Element::Float(f),
+            ElementRef::List(ref l) => Element::List(l.iter().map(Self::to_owned).collect()),
+            ElementRef::Extension(ref p) => Element::Extension(p.clone()),
+        }
+    }
+}
+
+impl<P: Profile> Element<P> {
+    /// Zero-copy decoding, see `ElementRef::from_bytes`
+    pub fn from_bytes_ref<'a>(bytes: &'a [u8]) -> Result<ElementRef<'a, P>, DecodeError> {
+        ElementRef::from_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Banana, NoneProfile, PerspectiveBroker, PB};
+
+    type BananaRef<'a> = ElementRef<'a, NoneProfile>;
+
+    #[test]
+    fn borrowed_strings() {
+        let bytes: &[u8] = &[
+            2, 0x80, 1, 0x81, 1, 0x80, 5, 0x82, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+        ];
+        let elt = BananaRef::from_bytes(bytes).unwrap();
+        assert_eq!(
+            elt,
+            ElementRef::List(vec![
+                ElementRef::Integer(1),
+                ElementRef::List(vec![ElementRef::String(b"hello")]),
+            ])
+        );
+        match elt {
+            ElementRef::List(ref l) => match l[1] {
+                ElementRef::List(ref l) => match l[0] {
+                    ElementRef::String(s) => assert_eq!(s.as_ptr(), bytes[8..].as_ptr()),
+                    _ => panic!("Expected a string"),
+                },
+                _ => panic!("Expected a list"),
+            },
+            _ => panic!("Expected a list"),
+        }
+        assert_eq!(elt.to_owned(), Banana::from_bytes(bytes).unwrap());
+        assert_eq!(Banana::from_bytes_ref(bytes), Ok(elt));
+    }
+
+    #[test]
+    fn remainder_and_errors() {
+        let bytes: &[u8] = &[0x03, 0x82, b'b', b'a', b'n', 0x01, 0x83];
+        assert_eq!(
+            BananaRef::from_bytes_rem(bytes),
+            Ok((ElementRef::String(b"ban"), &bytes[5..]))
+        );
+        let bytes: &[u8] = &[0x04, 0x82, b'b', b'a', b'n'];
+        assert_eq!(BananaRef::from_bytes(bytes), Err(DecodeError::TooShort(4, 3)));
+        let limits = DecodeLimits { max_string_len: 2, ..DecodeLimits::default() };
+        let bytes: &[u8] = &[0x03, 0x82, b'b', b'a', b'n'];
+        assert_eq!(
+            BananaRef::from_bytes_limited(bytes, &limits),
+            Err(DecodeError::StringTooLong(3, 2))
+        );
+    }
+
+    #[test]
+    fn with_profile() {
+        let elt: PerspectiveBroker = Element::List(vec![
+            Element::Extension(PB::Message),
+            Element::LongInteger(1 << 40),
+            Element::Float(1.5),
+            Element::String(String::from("root").into_bytes()),
+        ]);
+        let bytes = elt.encode();
+        let borrowed: ElementRef<PB> = ElementRef::from_bytes(&bytes).unwrap();
+        assert_eq!(borrowed.into_owned(), elt);
+    }
+}
@@ -0,0 +1,332 @@
+//! Instances passed by copy, as Twisted's `pb.Copyable` and `RemoteCopy`
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use super::super::broker::Referenceable;
+use super::{JellyError, JellyValue};
+
+/// Value sent by copy, as an instance of a Python class, as Twisted's
+/// `pb.Copyable`
+///
+/// It is jellied as an instance of `type_to_copy()`, with `state_to_copy()`
+/// for state, which the peer gets as a `RemoteCopy` if it registered the
+/// class, e.g., `[b"buildbot.changes.changes.Change", ["dictionary", ...]]`.
+pub trait Copyable: fmt::Debug {
+    /// Fully qualified name of the class, as Twisted's `getTypeToCopy`
+    fn type_to_copy(&self) -> String;
+
+    /// State of the instance, usually a dictionary of its attributes, as
+    /// Twisted's `getStateToCopy`
+    fn state_to_copy(&self) -> JellyValue;
+
+    /// The class for the peer logged in as `perspective`, as Twisted's
+    /// `getTypeToCopyFor`
+    fn type_to_copy_for(&self, _perspective: Option<&Rc<dyn Referenceable>>) -> String {
+        self.type_to_copy()
+    }
+
+    /// The state for the peer logged in as `perspective`, as Twisted's
+    /// `getStateToCopyFor`
+    fn state_to_copy_for(&self, _perspective: Option<&Rc<dyn Referenceable>>) -> JellyValue {
+        self.state_to_copy()
+    }
+}
+
+/// Local type for the instances of a `pb.Copyable` class, as Twisted's
+/// `RemoteCopy`
+///
+/// Types are registered by the fully qualified name of the class, with
+/// `UnjellyableRegistry::register()`.
+pub trait RemoteCopy: Any + fmt::Debug {
+    /// Build the value from the unjellied state of the instance, usually a
+    /// dictionary of its attributes, as Twisted's `setCopyableState`.
+    fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError>
+    where
+        Self: Sized;
+}
+
+impl dyn RemoteCopy {
+    pub fn downcast_ref<T: RemoteCopy>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref()
+    }
+
+    pub fn is<T: RemoteCopy>(&self) -> bool {
+        (self as &dyn Any).is::<T>()
+    }
+}
+
+type Factory = Rc<dyn Fn(JellyValue) -> Result<Rc<dyn RemoteCopy>, JellyError>>;
+
+/// Local types of the instances, by class, as Twisted's
+/// `unjellyableRegistry`
+///
+/// Instances of other classes are unjellied as `JellyValue::Instance`.
+#[derive(Clone, Default)]
+pub struct UnjellyableRegistry {
+    classes: HashMap<String, Factory>,
+}
+
+impl fmt::Debug for UnjellyableRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.classes.keys()).finish()
+    }
+}
+
+impl UnjellyableRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unjelly the instances of `class` as `T`, as Twisted's
+    /// `setUnjellyableForClass`.
+    ///
+    /// The instances are accepted whatever the `SecurityOptions` in use.
+    pub fn register<T: RemoteCopy>(&mut self, class: &str) -> &mut Self {
+        let factory: Factory =
+            Rc::new(|state| T::set_copyable_state(state).map(|v| Rc::new(v) as Rc<dyn RemoteCopy>));
+        self.classes.insert(class.to_owned(), factory);
+        self
+    }
+
+    pub fn is_registered(&self, class: &str) -> bool {
+        self.classes.contains_key(class)
+    }
+
+    /// The local value for an instance of `class`, if registered
+    pub(crate) fn unjelly(
+        &self,
+        class: &str,
+        state: JellyValue,
+    ) -> Option<Result<JellyValue, JellyError>> {
+        let factory = self.classes.get(class)?;
+        Some(factory(state).map(JellyValue::Copied))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{Element, PerspectiveBroker, PB};
+    use super::super::super::broker::{Referenceable, RemoteCall, Reply};
+    use super::super::{jelly, jelly_with, Invoker, SecurityOptions, Unjellier};
+
+    const PROPERTIES: &str = "buildbot.process.properties.Properties";
+
+    #[derive(Debug, PartialEq)]
+    struct Properties {
+        properties: Vec<(String, JellyValue)>,
+    }
+
+    impl RemoteCopy for Properties {
+        fn set_copyable_state(state: JellyValue) -> Result<Self, JellyError> {
+            let properties = match state.get("properties") {
+                Some(JellyValue::Dict(items)) => items.borrow().clone(),
+                _ => return Err(JellyError::Malformed("Properties without properties".into())),
+            };
+            Ok(Properties {
+                properties: properties
+                    .into_iter()
+                    .map(|(k, v)| match k {
+                        JellyValue::Unicode(k) => (k, v),
+                        k => (format!("{:?}", k), v),
+                    })
+                    .collect(),
+            })
+        }
+    }
+
+    fn s(st: &str) -> PerspectiveBroker {
+        Element::String(st.as_bytes().to_vec())
+    }
+
+    /// `Properties` with a `branch` property, as jellied by Twisted
+    fn properties(state: PerspectiveBroker) -> PerspectiveBroker {
+        Element::List(vec![s(PROPERTIES), state])
+    }
+
+    fn state() -> PerspectiveBroker {
+        let branch = Element::List(vec![
+            Element::Extension(PB::Tuple),
+            Element::List(vec![s("unicode"), s("main")]),
+            Element::List(vec![s("unicode"), s("Scheduler")]),
+        ]);
+        Element::List(vec![
+            Element::Extension(PB::Dictionary),
+            Element::List(vec![
+                s("properties"),
+                Element::List(vec![
+                    Element::Extension(PB::Dictionary),
+                    Element::List(vec![Element::List(vec![s("unicode"), s("branch")]), branch]),
+                ]),
+            ]),
+        ])
+    }
+
+    fn unjellier(registry: &UnjellyableRegistry) -> Unjellier {
+        let mut unjellier = Unjellier::with_taster(SecurityOptions::permissive());
+        unjellier.set_registry(registry.clone());
+        unjellier
+    }
+
+    #[test]
+    fn registered() {
+        let mut registry = UnjellyableRegistry::new();
+        registry.register::<Properties>(PROPERTIES);
+        assert!(registry.is_registered(PROPERTIES));
+
+        let value = unjellier(&registry).unjelly(&properties(state())).unwrap();
+        let copy = match value {
+            JellyValue::Copied(ref copy) => copy.clone(),
+            other => panic!("Expected a copy, got {:?}", other),
+        };
+        assert!(copy.is::<Properties>());
+        let props = copy.downcast_ref::<Properties>().unwrap();
+        assert_eq!(props.properties[0].0, "branch");
+        assert_eq!(
+            props.properties[0].1,
+            JellyValue::tuple(vec![
+                JellyValue::Unicode("main".into()),
+                JellyValue::Unicode("Scheduler".into()),
+            ])
+        );
+        // the same copy wherever it is referred to
+        let elt = Element::List(vec![
+            Element::Extension(PB::List),
+            Element::List(vec![Element::Extension(PB::Reference), Element::Integer(1), properties(state())]),
+            Element::List(vec![Element::Extension(PB::DeReference), Element::Integer(1)]),
+        ]);
+        match unjellier(&registry).unjelly(&elt).unwrap() {
+            JellyValue::List(ref items) => assert_eq!(items.borrow()[0], items.borrow()[1]),
+            other => panic!("Expected a list, got {:?}", other),
+        }
+
+        // registered classes need not be allowed
+        let mut strict = Unjellier::with_taster(SecurityOptions::default());
+        strict.set_registry(registry.clone());
+        assert!(strict.unjelly(&properties(state())).is_ok());
+        assert_eq!(
+            Unjellier::with_taster(SecurityOptions::default()).unjelly(&properties(state())),
+            Err(JellyError::Insecure("Module not allowed: buildbot.process.properties".into()))
+        );
+
+        let invalid = properties(Element::List(vec![Element::Extension(PB::Dictionary)]));
+        assert_eq!(
+            unjellier(&registry).unjelly(&invalid),
+            Err(JellyError::Malformed("Properties without properties".into()))
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        let registry = UnjellyableRegistry::new();
+        match unjellier(&registry).unjelly(&properties(state())).unwrap() {
+            JellyValue::Instance(ref inst) => {
+                assert_eq!(inst.borrow().class, PROPERTIES);
+                assert!(inst.borrow().state.get("properties").is_some());
+            }
+            other => panic!("Expected an instance, got {:?}", other),
+        }
+    }
+
+    /// Buildbot's source stamps, without the patch for other users
+    #[derive(Debug)]
+    struct SourceStamp {
+        branch: &'static str,
+        patch: &'static str,
+    }
+
+    impl Copyable for SourceStamp {
+        fn type_to_copy(&self) -> String {
+            "buildbot.sourcestamp.SourceStamp".into()
+        }
+
+        fn state_to_copy(&self) -> JellyValue {
+            JellyValue::dict(vec![(
+                JellyValue::Bytes(b"branch".to_vec()),
+                JellyValue::Unicode(self.branch.into()),
+            )])
+        }
+
+        fn state_to_copy_for(&self, perspective: Option<&Rc<dyn Referenceable>>) -> JellyValue {
+            let state = self.state_to_copy();
+            if perspective.is_some() {
+                if let JellyValue::Dict(ref items) = state {
+                    items.borrow_mut().push((
+                        JellyValue::Bytes(b"patch".to_vec()),
+                        JellyValue::Unicode(self.patch.into()),
+                    ));
+                }
+            }
+            state
+        }
+    }
+
+    #[derive(Debug)]
+    struct Author;
+
+    impl Referenceable for Author {
+        fn remote_message_received(&self, call: RemoteCall) -> Reply {
+            Reply::no_such_method(&call)
+        }
+    }
+
+    #[derive(Debug)]
+    struct AsAuthor(Rc<dyn Referenceable>);
+
+    impl Invoker for AsAuthor {
+        fn unjelly(
+            &self,
+            _unjellier: &mut Unjellier,
+            _sexp: &[PerspectiveBroker],
+        ) -> Option<Result<JellyValue, JellyError>> {
+            None
+        }
+
+        fn serializing_perspective(&self) -> Option<Rc<dyn Referenceable>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn copyable() {
+        let stamp: Rc<dyn Copyable> = Rc::new(SourceStamp {
+            branch: "main",
+            patch: "--- a/README",
+        });
+        let value = JellyValue::Copyable(stamp.clone());
+        let unicode = |st| Element::List(vec![s("unicode"), s(st)]);
+        let instance = |refid: Option<i64>, patch: bool| {
+            let mut state = vec![
+                Element::Extension(PB::Dictionary),
+                Element::List(vec![s("branch"), unicode("main")]),
+            ];
+            if patch {
+                state.push(Element::List(vec![s("patch"), unicode("--- a/README")]));
+            }
+            let inst = Element::List(vec![s("buildbot.sourcestamp.SourceStamp"), Element::List(state)]);
+            match refid {
+                Some(refid) => Element::List(vec![
+                    Element::Extension(PB::Reference),
+                    Element::integer(refid as i128),
+                    inst,
+                ]),
+                None => inst,
+            }
+        };
+        assert_eq!(jelly(&value), instance(None, false));
+        assert_eq!(jelly_with(&value, &AsAuthor(Rc::new(Author))), instance(None, true));
+
+        // preserved, as any instance
+        let elt = jelly(&JellyValue::list(vec![value.clone(), JellyValue::Copyable(stamp)]));
+        assert_eq!(
+            elt,
+            Element::List(vec![
+                Element::Extension(PB::List),
+                instance(Some(1), false),
+                Element::List(vec![Element::Extension(PB::DeReference), Element::Integer(1)]),
+            ])
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! From `JellyValue` to PB elements
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use super::{Copyable, Invoker, JellyValue};
+use super::super::{Element, ObjectId, PerspectiveBroker, PB};
+
+/// Jelly a value, as Twisted's `jelly()` would.
+///
+/// Containers met more than once are wrapped in a `Reference` at their first
+/// occurrence and replaced by a `DeReference` afterwards. Reference ids are
+/// allocated in the same order as Twisted does, that is upon the second
+/// occurrence, so that the output is identical.
+///
+/// Local and cacheable objects need a broker, they are jellied as
+/// unpersistable here.
+/// `Copyable` values are jellied as instances, for no perspective.
+pub fn jelly(value: &JellyValue) -> PerspectiveBroker {
+    Jellier::default().jelly(value)
+}
+
+/// Jelly a value, for the broker acting as `invoker`
+pub fn jelly_with(value: &JellyValue, invoker: &dyn Invoker) -> PerspectiveBroker {
+    Jellier {
+        invoker: Some(invoker),
+        ..Jellier::default()
+    }.jelly(value)
+}
+
+#[derive(Debug, Default)]
+struct Jellier<'a> {
+    /// Containers, with their reference id if met more than once
+    seen: HashMap<usize, Option<i128>>,
+    last_refid: i128,
+    /// Referenced containers already emitted
+    emitted: HashSet<usize>,
+    /// Instances for the `Copyable` values, by their address
+    copies: HashMap<usize, JellyValue>,
+    invoker: Option<&'a dyn Invoker>,
+}
+
+fn sexp(head: PB, mut items: Vec<PerspectiveBroker>) -> PerspectiveBroker {
+    items.insert(0, Element::Extension(head));
+    Element::List(items)
+}
+
+fn string(s: &[u8]) -> PerspectiveBroker {
+    Element::String(s.to_vec())
+}
+
+impl<'a> Jellier<'a> {
+    fn jelly(&mut self, value: &JellyValue) -> PerspectiveBroker {
+        self.scan(value);
+        self.emit(value)
+    }
+
+    /// First pass, allocating the reference ids, in traversal order.
+    fn scan(&mut self, value: &JellyValue) {
+        if let Some(ptr) = value.container_ptr() {
+            if let Some(refid) = self.seen.get_mut(&ptr) {
+                if refid.is_none() {
+                    self.last_refid += 1;
+                    *refid = Some(self.last_refid);
+                }
+                return;
+            }
+            self.seen.insert(ptr, None);
+        }
+        match *value {
+            JellyValue::List(ref items) | JellyValue::Tuple(ref items) => {
+                for item in items.borrow().iter() {
+                    self.scan(item);
+                }
+            }
+            JellyValue::Dict(ref items) => {
+                for (k, v) in items.borrow().iter() {
+                    self.scan(k);
+                    self.scan(v);
+                }
+            }
+            JellyValue::Instance(ref inst) => self.scan(&inst.borrow().state),
+            JellyValue::Persistent(ref id) => self.scan(id),
+            JellyValue::Copyable(ref copyable) => {
+                let inst = self.copy(copyable);
+                self.scan(&inst);
+            }
+            _ => {}
+        }
+    }
+
+    /// The instance to send for `copyable`, the same for each occurrence, as
+    /// Twisted preserves them
+    fn copy(&mut self, copyable: &Rc<dyn Copyable>) -> JellyValue {
+        let ptr = Rc::as_ptr(copyable) as *const () as usize;
+        if let Some(inst) = self.copies.get(&ptr) {
+            return inst.clone();
+        }
+        let perspective = self.invoker.and_then(|invoker| invoker.serializing_perspective());
+        let inst = JellyValue::instance(
+            &copyable.type_to_copy_for(perspective.as_ref()),
+            copyable.state_to_copy_for(perspective.as_ref()),
+        );
+        self.copies.insert(ptr, inst.clone());
+        inst
+    }
+
+    /// Second pass, with the same traversal order.
+    fn emit(&mut self, value: &JellyValue) -> PerspectiveBroker {
+        let refid = value.container_ptr().and_then(|ptr| {
+            self.seen.get(&ptr).and_then(|refid| refid.map(|refid| (ptr, refid)))
+        });
+        match refid {
+            Some((ptr, refid)) => {
+                if !self.emitted.insert(ptr) {
+                    return sexp(PB::DeReference, vec![Element::integer(refid)]);
+                }
+                let contents = self.emit_unreferenced(value);
+                sexp(PB::Reference, vec![Element::integer(refid), contents])
+            }
+            None => self.emit_unreferenced(value),
+        }
+    }
+
+    fn emit_unreferenced(&mut self, value: &JellyValue) -> PerspectiveBroker {
+        match *value {
+            JellyValue::None => sexp(PB::None, vec![]),
+            JellyValue::Bool(b) => Element::List(vec![
+                string(b"boolean"),
+                string(if b { b"true" } else { b"false" }),
+            ]),
+            JellyValue::Int(i) => Element::integer(i),
+            JellyValue::Float(f) => Element::Float(f),
+            JellyValue::Bytes(ref b) => Element::String(b.clone()),
+            JellyValue::Unicode(ref s) => {
+                Element::List(vec![string(b"unicode"), string(s.as_bytes())])
+            }
+            JellyValue::Class(ref name) => sexp(PB::Class, vec![string(name.as_bytes())]),
+            JellyValue::Module(ref name) => sexp(PB::Module, vec![string(name.as_bytes())]),
+            JellyValue::Function(ref name) => sexp(PB::Function, vec![string(name.as_bytes())]),
+            JellyValue::List(ref items) => {
+                let items = items.borrow().iter().map(|item| self.emit(item)).collect();
+                sexp(PB::List, items)
+            }
+            JellyValue::Tuple(ref items) => {
+                let items = items.borrow().iter().map(|item| self.emit(item)).collect();
+                sexp(PB::Tuple, items)
+            }
+            JellyValue::Dict(ref items) => {
+                let items = items
+                    .borrow()
+                    .iter()
+                    .map(|(k, v)| Element::List(vec![self.emit(k), self.emit(v)]))
+                    .collect();
+                sexp(PB::Dictionary, items)
+            }
+            JellyValue::Instance(ref inst) => {
+                let inst = inst.borrow();
+                Element::List(vec![string(inst.class.as_bytes()), self.emit(&inst.state)])
+            }
+            JellyValue::Persistent(ref id) => sexp(PB::Persistent, vec![self.emit(id)]),
+            JellyValue::Unpersistable(ref reason) => {
+                sexp(PB::UnPersistable, vec![string(reason.as_bytes())])
+            }
+            // going back to its owner
+            JellyValue::Remote(ref remote) => match *remote.object_id() {
+                ObjectId::Number(luid) => sexp(PB::Local, vec![Element::integer(luid as i128)]),
+                ObjectId::Name(ref name) => sexp(PB::Local, vec![string(name)]),
+            },
+            JellyValue::Local(_) | JellyValue::Cacheable(_) => {
+                match self.invoker.and_then(|invoker| invoker.jelly(value)) {
+                    Some(elt) => elt,
+                    None => sexp(
+                        PB::UnPersistable,
+                        vec![string(b"Reference can't be jellied without a broker")],
+                    ),
+                }
+            }
+            JellyValue::Cached(ref cache) => {
+                sexp(PB::LCache, vec![Element::integer(cache.luid() as i128)])
+            }
+            JellyValue::Copyable(ref copyable) => {
+                let inst = self.copy(copyable);
+                self.emit(&inst)
+            }
+            JellyValue::Copied(_) => sexp(
+                PB::UnPersistable,
+                vec![string(b"Copies of remote instances can't be jellied")],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::unjelly;
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn unicode(s: &str) -> JellyValue {
+        JellyValue::Unicode(s.into())
+    }
+
+    // The expected bytes are those of Twisted's `Banana.sendEncoded(jelly(obj))`,
+    // with the PB vocabulary.
+
+    #[test]
+    fn golden_basic() {
+        // [1, "a", 2**40, None]
+        let value = JellyValue::list(vec![
+            JellyValue::Int(1),
+            unicode("a"),
+            JellyValue::Int(1 << 40),
+            JellyValue::None,
+        ]);
+        let expected = concat(&[
+            &[0x05, 0x80, 0x08, 0x87, 0x01, 0x81],
+            &[0x02, 0x80, 0x07, 0x82],
+            b"unicode",
+            &[0x01, 0x82, b'a'],
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x85],
+            &[0x01, 0x80, 0x01, 0x87],
+        ]);
+        assert_eq!(jelly(&value).encode(), expected);
+
+        // buildbot.Node instance, with state {"ok": True, "ratio": 0.5}
+        let value = JellyValue::instance(
+            "buildbot.Node",
+            JellyValue::dict(vec![
+                (unicode("ok"), JellyValue::Bool(true)),
+                (unicode("ratio"), JellyValue::Float(0.5)),
+            ]),
+        );
+        let expected = concat(&[
+            &[0x02, 0x80, 0x0d, 0x82],
+            b"buildbot.Node",
+            &[0x03, 0x80, 0x05, 0x87],
+            &[0x02, 0x80, 0x02, 0x80, 0x07, 0x82],
+            b"unicode",
+            &[0x02, 0x82],
+            b"ok",
+            &[0x02, 0x80, 0x07, 0x82],
+            b"boolean",
+            &[0x04, 0x82],
+            b"true",
+            &[0x02, 0x80, 0x02, 0x80, 0x07, 0x82],
+            b"unicode",
+            &[0x05, 0x82],
+            b"ratio",
+            &[0x84, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0],
+        ]);
+        assert_eq!(jelly(&value).encode(), expected);
+    }
+
+    #[test]
+    fn golden_references() {
+        // l = []; l.append(l)
+        let l = JellyValue::list(vec![]);
+        if let JellyValue::List(ref items) = l {
+            items.borrow_mut().push(l.clone());
+        }
+        let expected: &[u8] = &[
+            0x03, 0x80, 0x04, 0x87, 0x01, 0x81, // reference 1
+            0x02, 0x80, 0x08, 0x87, // list
+            0x02, 0x80, 0x03, 0x87, 0x01, 0x81, // dereference 1
+        ];
+        let elt = jelly(&l);
+        assert_eq!(elt.encode(), expected);
+        assert_eq!(unjelly(&elt), Ok(l.clone()));
+        if let JellyValue::List(ref items) = l {
+            items.borrow_mut().clear();
+        }
+
+        // a = (1,); b = []; {"k": [a, b, a, b], b"x": b}
+        let a = JellyValue::tuple(vec![JellyValue::Int(1)]);
+        let b = JellyValue::list(vec![]);
+        let value = JellyValue::dict(vec![
+            (unicode("k"), JellyValue::list(vec![a.clone(), b.clone(), a.clone(), b.clone()])),
+            (JellyValue::Bytes(b"x".to_vec()), b.clone()),
+        ]);
+        let expected = concat(&[
+            &[0x03, 0x80, 0x05, 0x87, 0x02, 0x80, 0x02, 0x80, 0x07, 0x82],
+            b"unicode",
+            &[0x01, 0x82, b'k'],
+            &[0x05, 0x80, 0x08, 0x87],
+            &[0x03, 0x80, 0x04, 0x87, 0x01, 0x81, 0x02, 0x80, 0x0b, 0x87, 0x01, 0x81],
+            &[0x03, 0x80, 0x04, 0x87, 0x02, 0x81, 0x01, 0x80, 0x08, 0x87],
+            &[0x02, 0x80, 0x03, 0x87, 0x01, 0x81],
+            &[0x02, 0x80, 0x03, 0x87, 0x02, 0x81],
+            &[0x02, 0x80, 0x01, 0x82, b'x', 0x02, 0x80, 0x03, 0x87, 0x02, 0x81],
+        ]);
+        let elt = jelly(&value);
+        assert_eq!(elt.encode(), expected);
+        assert_eq!(unjelly(&elt), Ok(value));
+    }
+
+    #[test]
+    fn vocabulary_heads() {
+        let value = JellyValue::tuple(vec![
+            JellyValue::Class("buildbot.Step".into()),
+            JellyValue::Module("os".into()),
+            JellyValue::Function("os.getcwd".into()),
+        ]);
+        assert_eq!(
+            jelly(&value),
+            Element::List(vec![
+                Element::Extension(PB::Tuple),
+                Element::List(vec![Element::Extension(PB::Class), string(b"buildbot.Step")]),
+                Element::List(vec![Element::Extension(PB::Module), string(b"os")]),
+                Element::List(vec![Element::Extension(PB::Function), string(b"os.getcwd")]),
+            ])
+        );
+        assert_eq!(unjelly(&jelly(&value)), Ok(value));
+    }
+
+    #[test]
+    fn unpersistable() {
+        let value = JellyValue::list(vec![JellyValue::Unpersistable("file objects".into())]);
+        let elt = jelly(&value);
+        assert_eq!(
+            elt,
+            Element::List(vec![
+                Element::Extension(PB::List),
+                Element::List(vec![Element::Extension(PB::UnPersistable), string(b"file objects")]),
+            ])
+        );
+        assert_eq!(unjelly(&elt), Ok(value));
+    }
+}
@@ -0,0 +1,321 @@
+//! Jelly, the object serialization of Perspective Broker
+//!
+//! Jelly expresses Python objects as s-expressions, i.e., lists whose first
+//! item is a type name, such as `["tuple", 1, 2]`. In PB messages, the type
+//! names are usually abbreviated with the vocabulary.
+//!
+//! Unjellied objects are represented by `JellyValue`. Containers are shared,
+//! so that references within the s-expressions are preserved, including cycles
+//! when the `SecurityOptions` allow them.
+
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+use super::broker::{CacheReference, Cacheable, Referenceable, RemoteReference};
+
+mod copy;
+mod jellier;
+mod security;
+mod unjellier;
+
+pub use self::copy::{Copyable, RemoteCopy, UnjellyableRegistry};
+pub use self::jellier::{jelly, jelly_with};
+pub use self::security::SecurityOptions;
+pub use self::unjellier::{unjelly, Invoker, Unjellier};
+
+/// A mutable container that can be referenced several times
+pub type Shared<T> = Rc<RefCell<T>>;
+
+/// Unjellied Python object
+#[derive(Clone)]
+pub enum JellyValue {
+    None,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Unicode(String),
+    List(Shared<Vec<JellyValue>>),
+    Tuple(Shared<Vec<JellyValue>>),
+    /// Items are kept in their order of appearance
+    Dict(Shared<Vec<(JellyValue, JellyValue)>>),
+    Instance(Shared<Instance>),
+    /// Reference to a class, by its fully qualified name
+    Class(String),
+    Module(String),
+    Function(String),
+    /// Object known by an identifier, to be resolved by the application
+    Persistent(Box<JellyValue>),
+    /// What the peer could not jelly, with the reason
+    Unpersistable(String),
+    /// Object of the peer of a broker
+    Remote(RemoteReference),
+    /// Object that the peer of a broker can call
+    Local(Rc<dyn Referenceable>),
+    /// Instance of a class registered with `UnjellyableRegistry`
+    Copied(Rc<dyn RemoteCopy>),
+    /// Value to be sent as an instance
+    Copyable(Rc<dyn Copyable>),
+    /// Object to be copied by the peer of a broker, and kept up to date
+    Cacheable(Rc<dyn Cacheable>),
+    /// Copy of an object of the peer of a broker
+    Cached(CacheReference),
+}
+
+/// Instance of a Python class, with its state (typically a dictionary)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instance {
+    /// Fully qualified name of the class
+    pub class: String,
+    pub state: JellyValue,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum JellyError {
+    /// The s-expression doesn't have the expected structure
+    Malformed(String),
+    /// Jelly type that we don't know of
+    UnsupportedType(String),
+    /// Dereference to an unknown reference id
+    UnknownReference(i128),
+    /// Rejected by the `SecurityOptions` in use
+    Insecure(String),
+}
+
+impl fmt::Display for JellyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JellyError::Malformed(ref msg) => write!(f, "malformed jelly: {}", msg),
+            JellyError::UnsupportedType(ref t) => write!(f, "unsupported jelly type {:?}", t),
+            JellyError::UnknownReference(id) => write!(f, "unknown jelly reference {}", id),
+            JellyError::Insecure(ref msg) => write!(f, "insecure jelly: {}", msg),
+        }
+    }
+}
+
+impl error::Error for JellyError {}
+
+fn shared<T>(v: T) -> Shared<T> {
+    Rc::new(RefCell::new(v))
+}
+
+impl JellyValue {
+    pub fn list(items: Vec<JellyValue>) -> Self {
+        JellyValue::List(shared(items))
+    }
+
+    pub fn tuple(items: Vec<JellyValue>) -> Self {
+        JellyValue::Tuple(shared(items))
+    }
+
+    pub fn dict(items: Vec<(JellyValue, JellyValue)>) -> Self {
+        JellyValue::Dict(shared(items))
+    }
+
+    pub fn instance(class: &str, state: JellyValue) -> Self {
+        JellyValue::Instance(shared(Instance {
+            class: class.to_owned(),
+            state,
+        }))
+    }
+
+    /// Item of a dictionary with a string key, such as an attribute in the
+    /// state of an instance
+    pub fn get(&self, key: &str) -> Option<JellyValue> {
+        let items = match *self {
+            JellyValue::Dict(ref items) => items.borrow(),
+            _ => return None,
+        };
+        items
+            .iter()
+            .find(|&(k, _)| match *k {
+                JellyValue::Bytes(ref b) => b == key.as_bytes(),
+                JellyValue::Unicode(ref u) => u == key,
+                _ => false,
+            })
+            .map(|(_, v)| v.clone())
+    }
+
+    /// Address of the shared container, if this is one
+    fn container_ptr(&self) -> Option<usize> {
+        match *self {
+            JellyValue::List(ref rc) | JellyValue::Tuple(ref rc) => Some(Rc::as_ptr(rc) as usize),
+            JellyValue::Dict(ref rc) => Some(Rc::as_ptr(rc) as usize),
+            JellyValue::Instance(ref rc) => Some(Rc::as_ptr(rc) as usize),
+            _ => None,
+        }
+    }
+
+    /// `true` if both are the very same container (Python's `is`)
+    pub fn is(&self, other: &JellyValue) -> bool {
+        match (self.container_ptr(), other.container_ptr()) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Structural equality, assuming the pairs in `stack` to be equal,
+    /// which makes it terminate on cyclic graphs.
+    fn eq_graph(&self, other: &JellyValue, stack: &mut Vec<(usize, usize)>) -> bool {
+        if let (Some(a), Some(b)) = (self.container_ptr(), other.container_ptr()) {
+            if a == b || stack.contains(&(a, b)) {
+                return true;
+            }
+            stack.push((a, b));
+        }
+        let res = match (self, other) {
+            (JellyValue::None, JellyValue::None) => true,
+            (JellyValue::Bool(a), JellyValue::Bool(b)) => a == b,
+            (JellyValue::Int(a), JellyValue::Int(b)) => a == b,
+            (JellyValue::Float(a), JellyValue::Float(b)) => a == b,
+            (JellyValue::Bytes(a), JellyValue::Bytes(b)) => a == b,
+            (JellyValue::Unicode(a), JellyValue::Unicode(b)) |
+            (JellyValue::Class(a), JellyValue::Class(b)) |
+            (JellyValue::Module(a), JellyValue::Module(b)) |
+            (JellyValue::Function(a), JellyValue::Function(b)) |
+            (JellyValue::Unpersistable(a), JellyValue::Unpersistable(b)) => a == b,
+            (JellyValue::List(a), JellyValue::List(b)) |
+            (JellyValue::Tuple(a), JellyValue::Tuple(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.eq_graph(y, stack))
+            }
+            (JellyValue::Dict(a), JellyValue::Dict(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() &&
+                    a.iter().zip(b.iter()).all(|(x, y)| {
+                        x.0.eq_graph(&y.0, stack) && x.1.eq_graph(&y.1, stack)
+                    })
+            }
+            (JellyValue::Instance(a), JellyValue::Instance(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.class == b.class && a.state.eq_graph(&b.state, stack)
+            }
+            (JellyValue::Persistent(a), JellyValue::Persistent(b)) => a.eq_graph(b, stack),
+            (JellyValue::Remote(a), JellyValue::Remote(b)) => a == b,
+            (JellyValue::Local(a), JellyValue::Local(b)) => {
+                Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+            }
+            (JellyValue::Copied(a), JellyValue::Copied(b)) => {
+                Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+            }
+            (JellyValue::Copyable(a), JellyValue::Copyable(b)) => {
+                Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+            }
+            (JellyValue::Cacheable(a), JellyValue::Cacheable(b)) => {
+                Rc::as_ptr(a) as *const () == Rc::as_ptr(b) as *const ()
+            }
+            (JellyValue::Cached(a), JellyValue::Cached(b)) => a == b,
+            _ => false,
+        };
+        if self.container_ptr().is_some() && other.container_ptr().is_some() {
+            stack.pop();
+        }
+        res
+    }
+
+    /// Debug formatting, with containers being formatted in `stack` elided.
+    fn fmt_graph(&self, f: &mut fmt::Formatter, stack: &mut Vec<usize>) -> fmt::Result {
+        if let Some(ptr) = self.container_ptr() {
+            if stack.contains(&ptr) {
+                return write!(f, "<cycle>");
+            }
+            stack.push(ptr);
+        }
+        match *self {
+            JellyValue::None => write!(f, "None")?,
+            JellyValue::Bool(b) => write!(f, "Bool({})", b)?,
+            JellyValue::Int(i) => write!(f, "Int({})", i)?,
+            JellyValue::Float(fl) => write!(f, "Float({:?})", fl)?,
+            JellyValue::Bytes(ref b) => write!(f, "Bytes({:?})", String::from_utf8_lossy(b))?,
+            JellyValue::Unicode(ref s) => write!(f, "Unicode({:?})", s)?,
+            JellyValue::Class(ref s) => write!(f, "Class({:?})", s)?,
+            JellyValue::Module(ref s) => write!(f, "Module({:?})", s)?,
+            JellyValue::Function(ref s) => write!(f, "Function({:?})", s)?,
+            JellyValue::Unpersistable(ref s) => write!(f, "Unpersistable({:?})", s)?,
+            JellyValue::Remote(ref r) => write!(f, "Remote({:?})", r.object_id())?,
+            JellyValue::Local(ref obj) => write!(f, "Local({:?})", obj)?,
+            JellyValue::Copied(ref copy) => write!(f, "Copied({:?})", copy)?,
+            JellyValue::Copyable(ref copy) => write!(f, "Copyable({:?})", copy)?,
+            JellyValue::Cacheable(ref object) => write!(f, "Cacheable({:?})", object)?,
+            JellyValue::Cached(ref cache) => write!(f, "Cached({:?})", cache)?,
+            JellyValue::List(ref l) | JellyValue::Tuple(ref l) => {
+                let name = if let JellyValue::List(_) = *self { "List" } else { "Tuple" };
+                write!(f, "{}([", name)?;
+                for (i, item) in l.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_graph(f, stack)?;
+                }
+                write!(f, "])")?;
+            }
+            JellyValue::Dict(ref d) => {
+                write!(f, "Dict({{")?;
+                for (i, (k, v)) in d.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    k.fmt_graph(f, stack)?;
+                    write!(f, ": ")?;
+                    v.fmt_graph(f, stack)?;
+                }
+                write!(f, "}})")?;
+            }
+            JellyValue::Instance(ref inst) => {
+                let inst = inst.borrow();
+                write!(f, "Instance({:?}, ", inst.class)?;
+                inst.state.fmt_graph(f, stack)?;
+                write!(f, ")")?;
+            }
+            JellyValue::Persistent(ref id) => {
+                write!(f, "Persistent(")?;
+                id.fmt_graph(f, stack)?;
+                write!(f, ")")?;
+            }
+        }
+        if self.container_ptr().is_some() {
+            stack.pop();
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for JellyValue {
+    fn eq(&self, other: &JellyValue) -> bool {
+        self.eq_graph(other, &mut Vec::new())
+    }
+}
+
+impl fmt::Debug for JellyValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_graph(f, &mut Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles() {
+        let a = JellyValue::list(vec![JellyValue::Int(1)]);
+        if let JellyValue::List(ref l) = a {
+            l.borrow_mut().push(a.clone());
+        }
+        let b = JellyValue::list(vec![JellyValue::Int(1)]);
+        if let JellyValue::List(ref l) = b {
+            l.borrow_mut().push(b.clone());
+        }
+        assert!(!a.is(&b));
+        assert_eq!(a, b);
+        assert_eq!(format!("{:?}", a), "List([Int(1), <cycle>])");
+        assert_ne!(a, JellyValue::list(vec![JellyValue::Int(1), JellyValue::None]));
+        // break the cycles, for the sake of memory checkers
+        for v in &[a, b] {
+            if let JellyValue::List(ref l) = *v {
+                l.borrow_mut().clear();
+            }
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Restrictions on what peers may send, as Twisted's `jelly.SecurityOptions`
+
+use std::collections::HashSet;
+use super::JellyError;
+
+/// Types allowed by Twisted's `SecurityOptions` out of the box
+const IMMUTABLE_TYPES: &[&str] = &[
+    "None", "bool", "boolean", "string", "str", "int", "float", "datetime", "time", "date",
+    "timedelta", "NoneType", "unicode", "decimal", "set", "frozenset",
+];
+
+/// Twisted's `SecurityOptions.basicTypes`
+const BASIC_TYPES: &[&str] = &[
+    "dictionary", "list", "tuple", "reference", "dereference", "unpersistable", "persistent",
+    "long_int", "long", "dict",
+];
+
+/// The taster consulted while unjellying
+///
+/// Jelly type names (the heads of s-expressions) must be allowed, except for
+/// instances, whose classes and modules are checked instead, by their fully
+/// qualified names. Classes, modules and functions are checked by module.
+/// The default is what Perspective Broker uses, i.e., basic types only.
+///
+/// Unlike Twisted, containers referring to themselves are rejected unless
+/// `allow_cycles` is used: they are unjellied as `Rc` cycles, which are
+/// never freed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SecurityOptions {
+    allow_all: bool,
+    allowed_types: HashSet<String>,
+    allowed_modules: HashSet<String>,
+    allowed_classes: HashSet<String>,
+    allow_cycles: bool,
+    /// Maximum number of references in an object graph
+    pub max_references: usize,
+    /// Maximum nesting of s-expressions
+    pub max_depth: usize,
+}
+
+impl Default for SecurityOptions {
+    fn default() -> Self {
+        let mut options = Self::immutable_only();
+        options.allow_basic_types();
+        options
+    }
+}
+
+/// Module part of a fully qualified name
+fn module_of(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(idx) => &name[..idx],
+        None => "",
+    }
+}
+
+fn insecure<T>(msg: String) -> Result<T, JellyError> {
+    Err(JellyError::Insecure(msg))
+}
+
+impl SecurityOptions {
+    /// Only immutable values, as Twisted's `SecurityOptions()`
+    pub fn immutable_only() -> Self {
+        SecurityOptions {
+            allow_all: false,
+            allowed_types: IMMUTABLE_TYPES.iter().map(|t| t.to_string()).collect(),
+            allowed_modules: HashSet::new(),
+            allowed_classes: HashSet::new(),
+            allow_cycles: false,
+            max_references: 1 << 16,
+            max_depth: 256,
+        }
+    }
+
+    /// No restrictions at all, as Twisted's `DummySecurityOptions`.
+    ///
+    /// To be used with trusted input only.
+    pub fn permissive() -> Self {
+        SecurityOptions {
+            allow_all: true,
+            max_references: usize::MAX,
+            max_depth: usize::MAX,
+            ..Self::immutable_only()
+        }
+    }
+
+    /// Allow containers and references
+    pub fn allow_basic_types(&mut self) -> &mut Self {
+        self.allow_types(BASIC_TYPES)
+    }
+
+    pub fn allow_types(&mut self, types: &[&str]) -> &mut Self {
+        self.allowed_types.extend(types.iter().map(|t| t.to_string()));
+        self
+    }
+
+    pub fn allow_modules(&mut self, modules: &[&str]) -> &mut Self {
+        self.allowed_modules.extend(modules.iter().map(|m| m.to_string()));
+        self
+    }
+
+    /// Allow instances of the given classes, by their fully qualified names.
+    ///
+    /// As in Twisted, this allows the basic types and their modules, as well
+    /// as class and module references.
+    pub fn allow_instances_of(&mut self, classes: &[&str]) -> &mut Self {
+        self.allow_basic_types();
+        self.allow_types(&["instance", "class", "classobj", "module"]);
+        for class in classes {
+            self.allowed_modules.insert(module_of(class).to_owned());
+            self.allowed_classes.insert(class.to_string());
+        }
+        self
+    }
+
+    /// Allow containers to refer to themselves, as Twisted does.
+    ///
+    /// The values they are unjellied as leak, unless their cycles are broken.
+    pub fn allow_cycles(&mut self) -> &mut Self {
+        self.allow_cycles = true;
+        self
+    }
+
+    pub fn is_type_allowed(&self, type_name: &str) -> bool {
+        self.allow_all || self.allowed_types.contains(type_name) || type_name.contains('.')
+    }
+
+    pub fn is_module_allowed(&self, module: &str) -> bool {
+        self.allow_all || self.allowed_modules.contains(module)
+    }
+
+    pub fn is_class_allowed(&self, class: &str) -> bool {
+        self.allow_all || self.allowed_classes.contains(class)
+    }
+
+    pub fn are_cycles_allowed(&self) -> bool {
+        self.allow_all || self.allow_cycles
+    }
+
+    pub(crate) fn check_type(&self, type_name: &str) -> Result<(), JellyError> {
+        if !self.is_type_allowed(type_name) {
+            return insecure(format!("Type not allowed: {}", type_name));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_module(&self, module: &str) -> Result<(), JellyError> {
+        if !self.is_module_allowed(module) {
+            return insecure(format!("Module not allowed: {}", module));
+        }
+        Ok(())
+    }
+
+    /// Check the module of a class or function
+    pub(crate) fn check_module_of(&self, name: &str) -> Result<(), JellyError> {
+        self.check_module(module_of(name))
+    }
+
+    pub(crate) fn check_class(&self, class: &str) -> Result<(), JellyError> {
+        self.check_module_of(class)?;
+        if !self.is_class_allowed(class) {
+            return insecure(format!("Class not allowed: {}", class));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_cycle(&self, refid: i128) -> Result<(), JellyError> {
+        if !self.are_cycles_allowed() {
+            return insecure(format!("Reference {} to an unfinished container", refid));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::*;
+    use super::super::{JellyValue, Unjellier};
+    use super::super::super::{Element, PerspectiveBroker, PB};
+
+    fn s(st: &str) -> PerspectiveBroker {
+        Element::String(st.as_bytes().to_vec())
+    }
+
+    fn sexp(head: PB, args: Vec<PerspectiveBroker>) -> PerspectiveBroker {
+        let mut items = vec![Element::Extension(head)];
+        items.extend(args);
+        Element::List(items)
+    }
+
+    fn taste(taster: &SecurityOptions, elt: &PerspectiveBroker) -> Result<JellyValue, JellyError> {
+        Unjellier::with_taster(taster.clone()).unjelly(elt)
+    }
+
+    fn is_insecure(res: Result<JellyValue, JellyError>) -> bool {
+        matches!(res, Err(JellyError::Insecure(_)))
+    }
+
+    #[test]
+    fn types() {
+        let list = sexp(PB::List, vec![Element::Integer(1)]);
+        assert!(is_insecure(taste(&SecurityOptions::immutable_only(), &list)));
+        assert!(taste(&SecurityOptions::default(), &list).is_ok());
+        assert!(taste(&SecurityOptions::permissive(), &list).is_ok());
+
+        let none = sexp(PB::None, vec![]);
+        assert_eq!(taste(&SecurityOptions::immutable_only(), &none), Ok(JellyValue::None));
+
+        for elt in &[
+            sexp(PB::Class, vec![s("os.Popen")]),
+            sexp(PB::Module, vec![s("os")]),
+            sexp(PB::Function, vec![s("os.system")]),
+        ] {
+            assert!(is_insecure(taste(&SecurityOptions::default(), elt)), "{}", elt);
+            assert!(taste(&SecurityOptions::permissive(), elt).is_ok(), "{}", elt);
+        }
+
+        let mut taster = SecurityOptions::default();
+        taster.allow_types(&["function"]).allow_modules(&["os"]);
+        assert!(taste(&taster, &sexp(PB::Function, vec![s("os.getcwd")])).is_ok());
+        assert!(is_insecure(taste(&taster, &sexp(PB::Function, vec![s("sys.exit")]))));
+    }
+
+    #[test]
+    fn persistent() {
+        let elt = sexp(PB::Persistent, vec![s("42")]);
+        assert_eq!(
+            taste(&SecurityOptions::default(), &elt),
+            Ok(JellyValue::Persistent(Box::new(JellyValue::Bytes(b"42".to_vec()))))
+        );
+        assert!(is_insecure(taste(&SecurityOptions::immutable_only(), &elt)));
+    }
+
+    #[test]
+    fn instances() {
+        let node = Element::List(vec![
+            s("buildbot.Node"),
+            sexp(PB::Dictionary, vec![]),
+        ]);
+        assert!(is_insecure(taste(&SecurityOptions::default(), &node)));
+
+        let mut taster = SecurityOptions::default();
+        taster.allow_instances_of(&["buildbot.Node"]);
+        assert!(taste(&taster, &node).is_ok());
+        assert!(taste(&taster, &sexp(PB::Class, vec![s("buildbot.Node")])).is_ok());
+        assert!(taste(&taster, &sexp(PB::Module, vec![s("buildbot")])).is_ok());
+
+        // same module, other class
+        let step = Element::List(vec![s("buildbot.Step"), sexp(PB::Dictionary, vec![])]);
+        assert!(is_insecure(taste(&taster, &step)));
+        // also when referenced
+        let elt = sexp(PB::Reference, vec![Element::Integer(1), step]);
+        assert!(is_insecure(taste(&taster, &elt)));
+
+        // the state of an instance is a dictionary, whatever the starting point
+        let mut taster = SecurityOptions::immutable_only();
+        taster.allow_instances_of(&["buildbot.Node"]);
+        assert!(taste(&taster, &node).is_ok());
+        assert!(taste(&taster, &sexp(PB::List, vec![Element::Integer(1)])).is_ok());
+    }
+
+    #[test]
+    fn cycles() {
+        // l = []; l.append(l)
+        let elt = sexp(PB::Reference, vec![
+            Element::Integer(1),
+            sexp(PB::List, vec![sexp(PB::DeReference, vec![Element::Integer(1)])]),
+        ]);
+        assert!(is_insecure(taste(&SecurityOptions::default(), &elt)));
+
+        let mut taster = SecurityOptions::default();
+        taster.allow_cycles();
+        let list = match taste(&taster, &elt) {
+            Ok(JellyValue::List(list)) => list,
+            other => panic!("Expected a list, got {:?}", other),
+        };
+        // still referred to by itself once dropped
+        assert_eq!(Rc::strong_count(&list), 2);
+        list.borrow_mut().clear();
+        assert_eq!(Rc::strong_count(&list), 1);
+
+        // shared containers are no cycles
+        let elt = sexp(PB::List, vec![
+            sexp(PB::Reference, vec![Element::Integer(1), sexp(PB::List, vec![])]),
+            sexp(PB::DeReference, vec![Element::Integer(1)]),
+        ]);
+        let shared = match taste(&SecurityOptions::default(), &elt) {
+            Ok(JellyValue::List(list)) => match list.borrow()[0] {
+                JellyValue::List(ref shared) => shared.clone(),
+                ref other => panic!("Expected a list, got {:?}", other),
+            },
+            other => panic!("Expected a list, got {:?}", other),
+        };
+        assert_eq!(Rc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let mut elt = sexp(PB::None, vec![]);
+        for _ in 0..10 {
+            elt = sexp(PB::List, vec![elt]);
+        }
+        let mut taster = SecurityOptions {
+            max_depth: 11,
+            ..SecurityOptions::default()
+        };
+        assert!(taste(&taster, &elt).is_ok());
+        taster.max_depth = 10;
+        assert!(is_insecure(taste(&taster, &elt)));
+
+        let elt = sexp(PB::List, (1..4).map(|i| {
+            sexp(PB::Reference, vec![Element::Integer(i), sexp(PB::List, vec![])])
+        }).collect());
+        taster.max_references = 3;
+        assert!(taste(&taster, &elt).is_ok());
+        taster.max_references = 2;
+        assert!(is_insecure(taste(&taster, &elt)));
+    }
+}
@@ -0,0 +1,495 @@
+//! From PB elements to `JellyValue`
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+use std::str;
+use super::{Instance, JellyError, JellyValue, SecurityOptions, UnjellyableRegistry};
+use super::super::broker::Referenceable;
+use super::super::{Element, PerspectiveBroker};
+
+/// Unjelly a complete s-expression, such as a PB message argument.
+///
+/// This is not restricted in any way, see `Unjellier::with_taster` for
+/// untrusted input.
+pub fn unjelly(elt: &PerspectiveBroker) -> Result<JellyValue, JellyError> {
+    Unjellier::new().unjelly(elt)
+}
+
+/// Stateful unjellying, keeping track of references
+///
+/// Reference ids are scoped by the unjellier, the one-shot `unjelly` is what
+/// Twisted does for each object.
+#[derive(Debug)]
+pub struct Unjellier {
+    references: HashMap<i128, JellyValue>,
+    /// References to the containers being filled
+    unfinished: HashSet<i128>,
+    taster: SecurityOptions,
+    invoker: Option<Rc<dyn Invoker>>,
+    registry: UnjellyableRegistry,
+    /// Current nesting of s-expressions
+    depth: usize,
+}
+
+/// (Un)jellying of the types that only make sense within a broker, as the
+/// `invoker` of Twisted's jelly.
+pub trait Invoker: fmt::Debug {
+    /// Unjelly an s-expression, or return `None` to let it be unjellied as
+    /// usual.
+    ///
+    /// This is consulted after the type is checked by the taster.
+    fn unjelly(
+        &self,
+        unjellier: &mut Unjellier,
+        sexp: &[PerspectiveBroker],
+    ) -> Option<Result<JellyValue, JellyError>>;
+
+    /// Jelly a local object, or return `None` if it can't be.
+    fn jelly(&self, _value: &JellyValue) -> Option<PerspectiveBroker> {
+        None
+    }
+
+    /// The perspective for which `Copyable` values are jellied
+    fn serializing_perspective(&self) -> Option<Rc<dyn Referenceable>> {
+        None
+    }
+}
+
+impl Default for Unjellier {
+    fn default() -> Self {
+        Self::with_taster(SecurityOptions::permissive())
+    }
+}
+
+/// Type name of an s-expression, or string atom, possibly abbreviated.
+fn atom(elt: &PerspectiveBroker) -> Option<&[u8]> {
+    match *elt {
+        Element::String(ref s) => Some(s),
+        Element::Extension(pb) => Some(pb.token()),
+        _ => None,
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, JellyError> {
+    match str::from_utf8(bytes) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(_) => Err(JellyError::Malformed(format!("Invalid UTF-8 in {:?}", bytes))),
+    }
+}
+
+fn malformed<T>(sexp: &[PerspectiveBroker], expected: &str) -> Result<T, JellyError> {
+    let sexp: Vec<String> = sexp.iter().map(|e| e.to_string()).collect();
+    Err(JellyError::Malformed(format!("expected {}, got [{}]", expected, sexp.join(", "))))
+}
+
+impl Unjellier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_taster(taster: SecurityOptions) -> Self {
+        Unjellier {
+            references: HashMap::new(),
+            unfinished: HashSet::new(),
+            taster,
+            invoker: None,
+            registry: UnjellyableRegistry::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn with_invoker(taster: SecurityOptions, invoker: Rc<dyn Invoker>) -> Self {
+        Unjellier {
+            invoker: Some(invoker),
+            ..Self::with_taster(taster)
+        }
+    }
+
+    pub fn taster(&self) -> &SecurityOptions {
+        &self.taster
+    }
+
+    /// Unjelly the instances of the registered classes as their local types.
+    pub fn set_registry(&mut self, registry: UnjellyableRegistry) -> &mut Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn unjelly(&mut self, elt: &PerspectiveBroker) -> Result<JellyValue, JellyError> {
+        match *elt {
+            Element::Integer(i) => Ok(JellyValue::Int(i as i128)),
+            Element::LongInteger(i) => Ok(JellyValue::Int(i)),
+            Element::Float(f) => Ok(JellyValue::Float(f)),
+            Element::String(ref s) => Ok(JellyValue::Bytes(s.clone())),
+            Element::Extension(pb) => Ok(JellyValue::Bytes(pb.token().to_vec())),
+            Element::List(ref sexp) => self.nested(sexp, None),
+        }
+    }
+
+    /// Unjelly an s-expression, registering it under `refid`, if any.
+    fn nested(
+        &mut self,
+        sexp: &[PerspectiveBroker],
+        refid: Option<i128>,
+    ) -> Result<JellyValue, JellyError> {
+        if self.depth >= self.taster.max_depth {
+            return Err(JellyError::Insecure(
+                format!("Nesting exceeds {}", self.taster.max_depth),
+            ));
+        }
+        self.depth += 1;
+        let res = self.unjelly_sexp(sexp, refid);
+        self.depth -= 1;
+        res
+    }
+
+    fn unjelly_sexp(
+        &mut self,
+        sexp: &[PerspectiveBroker],
+        refid: Option<i128>,
+    ) -> Result<JellyValue, JellyError> {
+        let type_name = match sexp.first().and_then(atom) {
+            Some(t) => t,
+            None => return malformed(sexp, "an s-expression"),
+        };
+        self.taster.check_type(&String::from_utf8_lossy(type_name))?;
+        if let Some(invoker) = self.invoker.clone() {
+            if let Some(value) = invoker.unjelly(self, sexp) {
+                let value = value?;
+                if let Some(refid) = refid {
+                    self.register(refid, &value)?;
+                }
+                return Ok(value);
+            }
+        }
+        let args = &sexp[1..];
+        let value = match (type_name, args) {
+            (b"None", []) => JellyValue::None,
+            (b"boolean", [b]) => match atom(b) {
+                Some(b"true") => JellyValue::Bool(true),
+                Some(b"false") => JellyValue::Bool(false),
+                _ => return malformed(sexp, "a boolean"),
+            },
+            (b"unicode", [Element::String(s)]) => JellyValue::Unicode(utf8(s)?),
+            (b"class", [name]) | (b"module", [name]) | (b"function", [name]) => {
+                let name = match atom(name) {
+                    Some(name) => utf8(name)?,
+                    None => return malformed(sexp, "a name"),
+                };
+                match type_name {
+                    b"class" => {
+                        self.taster.check_class(&name)?;
+                        JellyValue::Class(name)
+                    }
+                    b"module" => {
+                        self.taster.check_module(&name)?;
+                        JellyValue::Module(name)
+                    }
+                    _ => {
+                        self.taster.check_module_of(&name)?;
+                        JellyValue::Function(name)
+                    }
+                }
+            }
+            (b"persistent", [id]) => JellyValue::Persistent(Box::new(self.unjelly(id)?)),
+            (b"unpersistable", [reason]) => match atom(reason) {
+                Some(reason) => JellyValue::Unpersistable(String::from_utf8_lossy(reason).into()),
+                None => return malformed(sexp, "a reason"),
+            },
+            (b"reference", [inner_refid, obj]) => {
+                let inner_refid = Self::refid(inner_refid, sexp)?;
+                match *obj {
+                    Element::List(ref obj) => self.nested(obj, Some(inner_refid))?,
+                    _ => {
+                        let value = self.unjelly(obj)?;
+                        self.register(inner_refid, &value)?;
+                        value
+                    }
+                }
+            }
+            (b"dereference", [refid]) => {
+                let refid = Self::refid(refid, sexp)?;
+                if self.unfinished.contains(&refid) {
+                    self.taster.check_cycle(refid)?;
+                }
+                match self.references.get(&refid) {
+                    Some(v) => v.clone(),
+                    None => return Err(JellyError::UnknownReference(refid)),
+                }
+            }
+            (b"None", _) | (b"boolean", _) | (b"unicode", _) | (b"class", _) |
+            (b"module", _) | (b"function", _) | (b"persistent", _) | (b"unpersistable", _) |
+            (b"reference", _) | (b"dereference", _) => {
+                return malformed(sexp, "valid arguments");
+            }
+            _ => {
+                // containers are registered before their contents get unjellied,
+                // so that they can refer to themselves.
+                let shell = self.shell(sexp)?;
+                if let Some(refid) = refid {
+                    self.register(refid, &shell)?;
+                    self.unfinished.insert(refid);
+                }
+                let filled = self.fill(&shell, args);
+                if let Some(refid) = refid {
+                    self.unfinished.remove(&refid);
+                }
+                filled?;
+                let value = match self.copy(&shell) {
+                    Some(value) => value?,
+                    None => return Ok(shell),
+                };
+                // references within the state still see the instance
+                if let Some(refid) = refid {
+                    self.references.insert(refid, value.clone());
+                }
+                return Ok(value);
+            }
+        };
+        if let Some(refid) = refid {
+            self.register(refid, &value)?;
+        }
+        Ok(value)
+    }
+
+    fn refid(elt: &PerspectiveBroker, sexp: &[PerspectiveBroker]) -> Result<i128, JellyError> {
+        match *elt {
+            Element::Integer(i) => Ok(i as i128),
+            Element::LongInteger(i) => Ok(i),
+            _ => malformed(sexp, "a reference id"),
+        }
+    }
+
+    fn register(&mut self, refid: i128, value: &JellyValue) -> Result<(), JellyError> {
+        if self.references.contains_key(&refid) {
+            return Err(JellyError::Malformed(format!("Duplicate reference id {}", refid)));
+        }
+        if self.references.len() >= self.taster.max_references {
+            return Err(JellyError::Insecure(
+                format!("More than {} references", self.taster.max_references),
+            ));
+        }
+        self.references.insert(refid, value.clone());
+        Ok(())
+    }
+
+    /// Empty container for s-expressions of lists, tuples, dictionaries and
+    /// instances.
+    fn shell(&self, sexp: &[PerspectiveBroker]) -> Result<JellyValue, JellyError> {
+        let type_name = match sexp.first().and_then(atom) {
+            Some(t) => t,
+            None => return malformed(sexp, "an s-expression"),
+        };
+        let len = sexp.len() - 1;
+        Ok(match type_name {
+            b"list" => JellyValue::list(Vec::with_capacity(len)),
+            b"tuple" => JellyValue::tuple(Vec::with_capacity(len)),
+            b"dictionary" => JellyValue::dict(Vec::with_capacity(len)),
+            // instances are jellied as their fully qualified class name and their state
+            class if class.contains(&b'.') => {
+                if len != 1 {
+                    return malformed(sexp, "an instance state");
+                }
+                let class = utf8(class)?;
+                // as Twisted's `setUnjellyableForClass`, registering allows
+                if !self.registry.is_registered(&class) {
+                    self.taster.check_class(&class)?;
+                }
+                JellyValue::instance(&class, JellyValue::None)
+            }
+            other => return Err(JellyError::UnsupportedType(String::from_utf8_lossy(other).into())),
+        })
+    }
+
+    /// The local value of an instance of a registered class
+    fn copy(&self, shell: &JellyValue) -> Option<Result<JellyValue, JellyError>> {
+        let inst = match *shell {
+            JellyValue::Instance(ref inst) => inst.borrow(),
+            _ => return None,
+        };
+        self.registry.unjelly(&inst.class, inst.state.clone())
+    }
+
+    fn fill(&mut self, shell: &JellyValue, args: &[PerspectiveBroker]) -> Result<(), JellyError> {
+        match *shell {
+            JellyValue::List(ref items) | JellyValue::Tuple(ref items) => {
+                for arg in args {
+                    let item = self.unjelly(arg)?;
+                    items.borrow_mut().push(item);
+                }
+            }
+            JellyValue::Dict(ref items) => {
+                for arg in args {
+                    let (k, v) = match *arg {
+                        Element::List(ref kv) if kv.len() == 2 => (&kv[0], &kv[1]),
+                        _ => return malformed(args, "[key, value] pairs"),
+                    };
+                    let k = self.unjelly(k)?;
+                    let v = self.unjelly(v)?;
+                    items.borrow_mut().push((k, v));
+                }
+            }
+            JellyValue::Instance(ref inst) => {
+                let state = self.unjelly(&args[0])?;
+                let mut inst = inst.borrow_mut();
+                *inst = Instance {
+                    class: inst.class.clone(),
+                    state,
+                };
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::PB;
+
+    fn s(st: &str) -> PerspectiveBroker {
+        Element::String(st.as_bytes().to_vec())
+    }
+
+    fn bytes(st: &str) -> JellyValue {
+        JellyValue::Bytes(st.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn basic_types() {
+        // login arguments from the `pb_session` test
+        let elt = Element::List(vec![Element::Extension(PB::Tuple), s("antares2")]);
+        assert_eq!(unjelly(&elt), Ok(JellyValue::tuple(vec![bytes("antares2")])));
+        let elt = Element::List(vec![Element::Extension(PB::Dictionary)]);
+        assert_eq!(unjelly(&elt), Ok(JellyValue::dict(vec![])));
+
+        let elt = Element::List(vec![
+            Element::Extension(PB::Dictionary),
+            Element::List(vec![
+                Element::List(vec![s("unicode"), s("caf\u{e9}")]),
+                Element::List(vec![Element::Extension(PB::None)]),
+            ]),
+            Element::List(vec![
+                Element::LongInteger(1 << 40),
+                Element::List(vec![
+                    s("list"),
+                    Element::Float(0.5),
+                    Element::List(vec![s("boolean"), s("true")]),
+                    Element::List(vec![Element::Extension(PB::Class), s("buildbot.Step")]),
+                    Element::List(vec![s("module"), s("os.path")]),
+                    Element::List(vec![Element::Extension(PB::Function), s("os.getcwd")]),
+                    Element::Extension(PB::Copy),
+                ]),
+            ]),
+        ]);
+        assert_eq!(
+            unjelly(&elt),
+            Ok(JellyValue::dict(vec![
+                (JellyValue::Unicode("caf\u{e9}".into()), JellyValue::None),
+                (
+                    JellyValue::Int(1 << 40),
+                    JellyValue::list(vec![
+                        JellyValue::Float(0.5),
+                        JellyValue::Bool(true),
+                        JellyValue::Class("buildbot.Step".into()),
+                        JellyValue::Module("os.path".into()),
+                        JellyValue::Function("os.getcwd".into()),
+                        bytes("copy"),
+                    ]),
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn references() {
+        // a = [1]; [a, a, (a,)]
+        let elt = Element::List(vec![
+            s("list"),
+            Element::List(vec![
+                Element::Extension(PB::Reference),
+                Element::Integer(1),
+                Element::List(vec![s("list"), Element::Integer(1)]),
+            ]),
+            Element::List(vec![Element::Extension(PB::DeReference), Element::Integer(1)]),
+            Element::List(vec![
+                Element::Extension(PB::Tuple),
+                Element::List(vec![Element::Extension(PB::DeReference), Element::Integer(1)]),
+            ]),
+        ]);
+        let value = unjelly(&elt).unwrap();
+        let items = match value {
+            JellyValue::List(ref l) => l.borrow().clone(),
+            _ => panic!("Expected a list"),
+        };
+        assert!(items[0].is(&items[1]));
+        match items[2] {
+            JellyValue::Tuple(ref t) => assert!(t.borrow()[0].is(&items[0])),
+            _ => panic!("Expected a tuple"),
+        }
+    }
+
+    #[test]
+    fn cycles() {
+        // class Node: ...; n = Node(); n.parent = n
+        let elt = Element::List(vec![
+            Element::Extension(PB::Reference),
+            Element::Integer(1),
+            Element::List(vec![
+                s("buildbot.Node"),
+                Element::List(vec![
+                    Element::Extension(PB::Dictionary),
+                    Element::List(vec![
+                        Element::List(vec![s("unicode"), s("parent")]),
+                        Element::List(vec![
+                            Element::Extension(PB::DeReference),
+                            Element::Integer(1),
+                        ]),
+                    ]),
+                ]),
+            ]),
+        ]);
+        let node = unjelly(&elt).unwrap();
+        match node {
+            JellyValue::Instance(ref inst) => {
+                let inst = inst.borrow();
+                assert_eq!(inst.class, "buildbot.Node");
+                match inst.state {
+                    JellyValue::Dict(ref d) => assert!(d.borrow()[0].1.is(&node)),
+                    _ => panic!("Expected a dict"),
+                }
+            }
+            _ => panic!("Expected an instance"),
+        }
+        assert_eq!(
+            format!("{:?}", node),
+            "Instance(\"buildbot.Node\", Dict({Unicode(\"parent\"): <cycle>}))"
+        );
+        if let JellyValue::Instance(ref inst) = node {
+            inst.borrow_mut().state = JellyValue::None;
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let deref = Element::List(vec![Element::Extension(PB::DeReference), Element::Integer(3)]);
+        assert_eq!(unjelly(&deref), Err(JellyError::UnknownReference(3)));
+        assert_eq!(
+            unjelly(&Element::List(vec![s("frobnicate"), Element::Integer(1)])),
+            Err(JellyError::UnsupportedType("frobnicate".into()))
+        );
+        for elt in &[
+            Element::List(vec![]),
+            Element::List(vec![Element::Integer(1)]),
+            Element::List(vec![s("unicode"), s("\u{e9}"), s("x")]),
+            Element::List(vec![s("unicode"), Element::String(vec![0xff])]),
+            Element::List(vec![s("dictionary"), Element::List(vec![s("k")])]),
+            Element::List(vec![s("buildbot.Node")]),
+            Element::List(vec![s("boolean"), s("maybe")]),
+        ] {
+            assert!(matches!(unjelly(elt), Err(JellyError::Malformed(_))), "{}", elt);
+        }
+    }
+}
@@ -3,9 +3,91 @@
 //! It provides the plain Banana and the Perspective Broker message protocols.
 //! The ultimate goal of this lib is to provide helpers for interoperability between
 //! Rust and Twisted applications.
+//!
+//! The `jelly` module handles the object serialization of Perspective Broker,
+//! and the `broker` module the Perspective Broker itself, in a sans-IO way.
+//!
+//! Decoding never panics, whatever the input: this is checked by the fuzzing
+//! targets in the `fuzz` directory, whose corpus is replayed in the tests.
+//!
+//! With the `serde` feature (on by default), `to_bytes` and `from_bytes` convert
+//! between Rust values and bare Banana elements, with the following rules:
+//!
+//! - integers become `Element::Integer`, or `Element::LongInteger` outside of
+//!   the `i32` range. Deserialization checks the range of the target type.
+//!   Booleans are the integers 0 and 1, as Python would send them.
+//! - `f32` and `f64` become `Element::Float`
+//! - text, chars and byte strings become `Element::String` (text must be UTF-8)
+//! - sequences, tuples and structs become lists of their items or fields,
+//!   in declaration order. Field names aren't sent.
+//! - maps become lists of `[key, value]` lists
+//! - options become lists of zero or one item, unit is the empty list
+//! - newtype structs are transparent
+//! - unit enum variants become their name, other variants become a list of
+//!   their name followed by their fields.
+
+#[cfg(feature = "serde")]
+extern crate serde;
 
 mod banana;
+pub mod broker;
+mod connection;
+#[cfg(feature = "serde")]
+mod de;
+mod decoder;
+mod element_ref;
+pub mod jelly;
+mod message;
 mod pb;
+mod registry;
+#[cfg(feature = "serde")]
+mod ser;
+
+pub use banana::{Profile, DecodeError, LocatedDecodeError, DecodeLimits, Banana, Element, NoneProfile, SIZE_LIMIT};
+pub use connection::{BananaConnection, ConnectionError, Dialect, Event, Role, DEFAULT_DIALECTS};
+#[cfg(feature = "serde")]
+pub use de::{from_bytes, from_element};
+pub use decoder::{Decoder, DynDecoder};
+pub use element_ref::ElementRef;
+pub use message::{Call, MessageError, ObjectId, PBMessage};
+pub use pb::{PerspectiveBroker, PB, VOCABULARY};
+pub use registry::{DynElement, DynExtension, DynProfile, ExtensionValue, ProfileRegistry,
+                   StaticProfile};
+#[cfg(feature = "serde")]
+pub use ser::{to_bytes, to_element, SerdeError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
 
-pub use banana::{Profile, DecodeError, Banana, Element, NoneProfile};
-pub use pb::{PerspectiveBroker, PB};
+    /// Replay the fuzzing corpus (see `fuzz/`), so that regressions are
+    /// caught without a fuzzing toolchain.
+    #[test]
+    fn fuzz_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz").join("corpus");
+        let mut count = 0;
+        for target in fs::read_dir(corpus).unwrap() {
+            for entry in fs::read_dir(target.unwrap().path()).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                let _ = Banana::from_bytes(&data);
+                let _ = Banana::from_bytes_rem(&data);
+                if let Ok(elt) = PerspectiveBroker::from_bytes(&data) {
+                    let encoded = elt.encode();
+                    let decoded = PerspectiveBroker::from_bytes(&encoded).unwrap();
+                    assert_eq!(decoded.encode(), encoded);
+                }
+                for chunk_size in &[1, 7, 1024] {
+                    let mut decoder: Decoder<PB> = Decoder::new();
+                    for chunk in data.chunks(*chunk_size) {
+                        decoder.feed(chunk);
+                        while let Ok(Some(_)) = decoder.next_element() {}
+                    }
+                }
+                count += 1;
+            }
+        }
+        assert!(count > 0);
+    }
+}
@@ -0,0 +1,427 @@
+//! Perspective Broker protocol messages
+//!
+//! These are the top-level s-expressions exchanged by brokers once the
+//! dialect is negotiated, as handled by the `proto_*` methods of Twisted's
+//! `pb.Broker`. Arguments, keyword arguments, results and failures are kept
+//! jellied, since unjellying them needs the state of the broker.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use super::{Element, PerspectiveBroker, PB};
+
+/// Identifier of an object published by a broker
+///
+/// The root object is known by name, the others by number.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum ObjectId {
+    Name(Vec<u8>),
+    Number(i64),
+}
+
+impl ObjectId {
+    pub fn root() -> Self {
+        ObjectId::Name(b"root".to_vec())
+    }
+}
+
+/// Remote method call, as in `message` and `cachemessage`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Call {
+    pub request_id: i64,
+    pub object_id: ObjectId,
+    pub method: Vec<u8>,
+    /// If `false`, the peer sends no answer, even in case of error
+    pub answer_required: bool,
+    /// Jellied tuple of positional arguments
+    pub args: PerspectiveBroker,
+    /// Jellied dictionary of keyword arguments
+    pub kwargs: PerspectiveBroker,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PBMessage {
+    /// Protocol version, sent by both sides upon connection
+    Version(i64),
+    /// Call on a `Referenceable`
+    Message(Call),
+    /// Call on a `Cacheable`, through its remote cache
+    CacheMessage(Call),
+    Answer {
+        request_id: i64,
+        result: PerspectiveBroker,
+    },
+    Error {
+        request_id: i64,
+        /// Jellied failure, or a mere string
+        failure: PerspectiveBroker,
+    },
+    /// The peer dropped its last reference to one of our objects
+    DecRef(i64),
+    /// The peer dropped its remote cache of one of our `Cacheable`
+    DeCache(i64),
+    /// The peer should drop its remote cache, which was never sent
+    UnCache(i64),
+    /// Reply to an unknown command, with that command
+    DidNotUnderstand(Vec<u8>),
+}
+
+/// Not in the vocabulary, hence always sent as a string
+const DID_NOT_UNDERSTAND: &[u8] = b"didNotUnderstand";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MessageError {
+    /// The frame is not a list starting with a command name
+    NotACommand(String),
+    UnknownCommand(Vec<u8>),
+    /// Wrong number of arguments for the command: expected, actual
+    Arity(&'static str, usize, usize),
+    /// Argument of the command, at the given position (from 0), isn't of
+    /// the expected type.
+    InvalidArgument {
+        command: &'static str,
+        position: usize,
+        expected: &'static str,
+        got: String,
+    },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::NotACommand(ref frame) => write!(f, "not a PB command: {}", frame),
+            MessageError::UnknownCommand(ref cmd) => {
+                write!(f, "unknown PB command {:?}", String::from_utf8_lossy(cmd))
+            }
+            MessageError::Arity(cmd, expected, got) => write!(
+                f,
+                "PB command {:?} takes {} arguments (got {})",
+                cmd,
+                expected,
+                got
+            ),
+            MessageError::InvalidArgument {
+                command,
+                position,
+                expected,
+                ref got,
+            } => write!(
+                f,
+                "argument {} of PB command {:?} should be {} (got {})",
+                position,
+                command,
+                expected,
+                got
+            ),
+        }
+    }
+}
+
+impl error::Error for MessageError {}
+
+/// Arguments of a command, for typed extraction with precise errors
+struct Args<'a> {
+    command: &'static str,
+    args: &'a [PerspectiveBroker],
+}
+
+impl<'a> Args<'a> {
+    fn new(
+        command: &'static str,
+        args: &'a [PerspectiveBroker],
+        arity: usize,
+    ) -> Result<Self, MessageError> {
+        if args.len() != arity {
+            return Err(MessageError::Arity(command, arity, args.len()));
+        }
+        Ok(Args { command, args })
+    }
+
+    fn invalid<T>(&self, position: usize, expected: &'static str) -> Result<T, MessageError> {
+        Err(MessageError::InvalidArgument {
+            command: self.command,
+            position,
+            expected,
+            got: self.args[position].to_string(),
+        })
+    }
+
+    fn integer(&self, position: usize) -> Result<i64, MessageError> {
+        match self.args[position] {
+            Element::Integer(i) => Ok(i as i64),
+            Element::LongInteger(i) => match i64::try_from(i) {
+                Ok(i) => Ok(i),
+                Err(_) => self.invalid(position, "a 64 bits integer"),
+            },
+            _ => self.invalid(position, "an integer"),
+        }
+    }
+
+    fn string(&self, position: usize) -> Result<Vec<u8>, MessageError> {
+        match self.args[position] {
+            Element::String(ref s) => Ok(s.clone()),
+            Element::Extension(pb) => Ok(pb.token().to_vec()),
+            _ => self.invalid(position, "a string"),
+        }
+    }
+
+    fn object_id(&self, position: usize) -> Result<ObjectId, MessageError> {
+        match self.args[position] {
+            Element::String(ref s) => Ok(ObjectId::Name(s.clone())),
+            Element::Integer(_) | Element::LongInteger(_) => {
+                Ok(ObjectId::Number(self.integer(position)?))
+            }
+            _ => self.invalid(position, "an object id"),
+        }
+    }
+
+    fn boolean(&self, position: usize) -> Result<bool, MessageError> {
+        match self.args[position] {
+            Element::Integer(0) => Ok(false),
+            Element::Integer(1) => Ok(true),
+            _ => self.invalid(position, "0 or 1"),
+        }
+    }
+
+    fn call(&self) -> Result<Call, MessageError> {
+        Ok(Call {
+            request_id: self.integer(0)?,
+            object_id: self.object_id(1)?,
+            method: self.string(2)?,
+            answer_required: self.boolean(3)?,
+            args: self.args[4].clone(),
+            kwargs: self.args[5].clone(),
+        })
+    }
+}
+
+fn command(head: PB, mut args: Vec<PerspectiveBroker>) -> PerspectiveBroker {
+    args.insert(0, Element::Extension(head));
+    Element::List(args)
+}
+
+fn call(head: PB, call: &Call) -> PerspectiveBroker {
+    command(
+        head,
+        vec![
+            Element::integer(call.request_id as i128),
+            match call.object_id {
+                ObjectId::Name(ref name) => Element::String(name.clone()),
+                ObjectId::Number(id) => Element::integer(id as i128),
+            },
+            Element::String(call.method.clone()),
+            Element::Integer(call.answer_required as i32),
+            call.args.clone(),
+            call.kwargs.clone(),
+        ],
+    )
+}
+
+impl PBMessage {
+    /// Interpret a received element.
+    ///
+    /// The command can be abbreviated by the vocabulary or not.
+    pub fn parse(elt: &PerspectiveBroker) -> Result<Self, MessageError> {
+        let (cmd, args) = match *elt {
+            Element::List(ref l) if !l.is_empty() => (&l[0], &l[1..]),
+            _ => return Err(MessageError::NotACommand(elt.to_string())),
+        };
+        let cmd = match *cmd {
+            Element::Extension(pb) => pb.token(),
+            Element::String(ref s) => s,
+            _ => return Err(MessageError::NotACommand(elt.to_string())),
+        };
+        if cmd == DID_NOT_UNDERSTAND {
+            let args = Args::new("didNotUnderstand", args, 1)?;
+            return Ok(PBMessage::DidNotUnderstand(args.string(0)?));
+        }
+        let pb = match PB::from_token(cmd) {
+            Some(pb) => pb,
+            None => return Err(MessageError::UnknownCommand(cmd.to_vec())),
+        };
+        Ok(match pb {
+            PB::Version => PBMessage::Version(Args::new("version", args, 1)?.integer(0)?),
+            PB::Message => PBMessage::Message(Args::new("message", args, 6)?.call()?),
+            PB::CacheMessage => {
+                PBMessage::CacheMessage(Args::new("cachemessage", args, 6)?.call()?)
+            }
+            PB::Answer => {
+                let args = Args::new("answer", args, 2)?;
+                PBMessage::Answer {
+                    request_id: args.integer(0)?,
+                    result: args.args[1].clone(),
+                }
+            }
+            PB::Error => {
+                let args = Args::new("error", args, 2)?;
+                PBMessage::Error {
+                    request_id: args.integer(0)?,
+                    failure: args.args[1].clone(),
+                }
+            }
+            PB::DecRef => PBMessage::DecRef(Args::new("decref", args, 1)?.integer(0)?),
+            PB::DeCache => PBMessage::DeCache(Args::new("decache", args, 1)?.integer(0)?),
+            PB::UnCache => PBMessage::UnCache(Args::new("uncache", args, 1)?.integer(0)?),
+            _ => return Err(MessageError::UnknownCommand(cmd.to_vec())),
+        })
+    }
+
+    /// The element to send, with the command abbreviated
+    pub fn build(&self) -> PerspectiveBroker {
+        match *self {
+            PBMessage::Version(v) => command(PB::Version, vec![Element::integer(v as i128)]),
+            PBMessage::Message(ref c) => call(PB::Message, c),
+            PBMessage::CacheMessage(ref c) => call(PB::CacheMessage, c),
+            PBMessage::Answer {
+                request_id,
+                ref result,
+            } => command(PB::Answer, vec![Element::integer(request_id as i128), result.clone()]),
+            PBMessage::Error {
+                request_id,
+                ref failure,
+            } => command(PB::Error, vec![Element::integer(request_id as i128), failure.clone()]),
+            PBMessage::DecRef(id) => command(PB::DecRef, vec![Element::integer(id as i128)]),
+            PBMessage::DeCache(id) => command(PB::DeCache, vec![Element::integer(id as i128)]),
+            PBMessage::UnCache(id) => command(PB::UnCache, vec![Element::integer(id as i128)]),
+            PBMessage::DidNotUnderstand(ref cmd) => Element::List(vec![
+                Element::String(DID_NOT_UNDERSTAND.to_vec()),
+                Element::String(cmd.clone()),
+            ]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(st: &str) -> PerspectiveBroker {
+        Element::String(st.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn login_call() {
+        // the login message from the `pb_session` test
+        let elt = Element::List(vec![
+            Element::Extension(PB::Message),
+            Element::Integer(1),
+            s("root"),
+            Element::Extension(PB::Login),
+            Element::Integer(1),
+            Element::List(vec![Element::Extension(PB::Tuple), s("antares2")]),
+            Element::List(vec![Element::Extension(PB::Dictionary)]),
+        ]);
+        let msg = PBMessage::parse(&elt).unwrap();
+        assert_eq!(
+            msg,
+            PBMessage::Message(Call {
+                request_id: 1,
+                object_id: ObjectId::root(),
+                method: b"login".to_vec(),
+                answer_required: true,
+                args: Element::List(vec![Element::Extension(PB::Tuple), s("antares2")]),
+                kwargs: Element::List(vec![Element::Extension(PB::Dictionary)]),
+            })
+        );
+        // the method name gets abbreviated upon encoding only
+        assert_eq!(msg.build().encode(), elt.encode());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let none = Element::List(vec![Element::Extension(PB::None)]);
+        for msg in &[
+            PBMessage::Version(6),
+            PBMessage::CacheMessage(Call {
+                request_id: 1 << 40,
+                object_id: ObjectId::Number(3),
+                method: b"frobnicate".to_vec(),
+                answer_required: false,
+                args: Element::List(vec![Element::Extension(PB::Tuple)]),
+                kwargs: Element::List(vec![Element::Extension(PB::Dictionary)]),
+            }),
+            PBMessage::Answer {
+                request_id: 2,
+                result: none.clone(),
+            },
+            PBMessage::Error {
+                request_id: 2,
+                failure: s("boom"),
+            },
+            PBMessage::DecRef(-1),
+            PBMessage::DeCache(4),
+            PBMessage::UnCache(5),
+            PBMessage::DidNotUnderstand(b"frobnicate".to_vec()),
+        ] {
+            let bytes = msg.build().encode();
+            assert_eq!(PBMessage::parse(&PerspectiveBroker::from_bytes(&bytes).unwrap()).as_ref(), Ok(msg));
+        }
+        assert_eq!(
+            PBMessage::Version(6).build(),
+            Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)])
+        );
+        // as Twisted would receive it, after lowering the vocabulary
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![s("decref"), Element::Integer(7)])),
+            Ok(PBMessage::DecRef(7))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            PBMessage::parse(&Element::Integer(6)),
+            Err(MessageError::NotACommand("6".into()))
+        );
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![])),
+            Err(MessageError::NotACommand("[]".into()))
+        );
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![s("frobnicate")])),
+            Err(MessageError::UnknownCommand(b"frobnicate".to_vec()))
+        );
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![Element::Extension(PB::Tuple)])),
+            Err(MessageError::UnknownCommand(b"tuple".to_vec()))
+        );
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![Element::Extension(PB::Version)])),
+            Err(MessageError::Arity("version", 1, 0))
+        );
+        let err = PBMessage::parse(&Element::List(vec![
+            Element::Extension(PB::Message),
+            Element::Integer(1),
+            s("root"),
+            s("login"),
+            Element::Integer(2),
+            Element::Integer(0),
+            Element::Integer(0),
+        ])).unwrap_err();
+        assert_eq!(
+            err,
+            MessageError::InvalidArgument {
+                command: "message",
+                position: 3,
+                expected: "0 or 1",
+                got: "2".into(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "argument 3 of PB command \"message\" should be 0 or 1 (got 2)"
+        );
+        assert_eq!(
+            PBMessage::parse(&Element::List(vec![
+                Element::Extension(PB::DecRef),
+                Element::LongInteger(1 << 70),
+            ])),
+            Err(MessageError::InvalidArgument {
+                command: "decref",
+                position: 0,
+                expected: "a 64 bits integer",
+                got: (1i128 << 70).to_string(),
+            })
+        );
+    }
+}
@@ -2,19 +2,22 @@
 /// According to the specifications, this is an extension profile of the Banana protocol
 
 use std::fmt;
-use super::{Profile, DecodeError, Element};
+use std::io::{self, Write};
+use super::{Banana, Profile, DecodeError, Element};
 
 pub type PerspectiveBroker = Element<PB>;
 
 /// Perspective Broker (PB) extension profile
-#[derive(Debug, PartialEq, Clone)]
+///
+/// These are the vocabulary tokens, see `VOCABULARY` for their codes.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum PB {
-    None, // 0x01
-    Class, // 0x02
-    DeReference, // 0x03
-    Reference, // 0x04
-    Dictionary, // 0x05
-    Function, // 0x06, etc.
+    None,
+    Class,
+    DeReference,
+    Reference,
+    Dictionary,
+    Function,
     Instance,
     List,
     Module,
@@ -29,7 +32,7 @@ pub enum PB {
     LCache,
     Version,
     Login,
-    Password, // 0x15
+    Password,
     Challenge,
     LoggedIn,
     NotLoggedIn,
@@ -39,7 +42,76 @@ pub enum PB {
     Error,
     DecRef,
     DeCache,
-    UnCache, // 0x1f
+    UnCache,
+}
+
+/// The PB vocabulary, as in Twisted's `outgoingVocabulary`.
+///
+/// Each token is at the position given by its code minus one.
+pub const VOCABULARY: [(PB, &[u8]); 31] = [
+    // Jelly data types
+    (PB::None, b"None"), // 0x01
+    (PB::Class, b"class"),
+    (PB::DeReference, b"dereference"),
+    (PB::Reference, b"reference"),
+    (PB::Dictionary, b"dictionary"), // 0x05
+    (PB::Function, b"function"),
+    (PB::Instance, b"instance"),
+    (PB::List, b"list"),
+    (PB::Module, b"module"),
+    (PB::Persistent, b"persistent"), // 0x0a
+    (PB::Tuple, b"tuple"),
+    (PB::UnPersistable, b"unpersistable"),
+    // PB data types
+    (PB::Copy, b"copy"),
+    (PB::Cache, b"cache"),
+    (PB::Cached, b"cached"), // 0x0f
+    (PB::Remote, b"remote"),
+    (PB::Local, b"local"),
+    (PB::LCache, b"lcache"),
+    // PB protocol messages
+    (PB::Version, b"version"),
+    (PB::Login, b"login"), // 0x14
+    (PB::Password, b"password"),
+    (PB::Challenge, b"challenge"),
+    (PB::LoggedIn, b"logged_in"),
+    (PB::NotLoggedIn, b"not_logged_in"),
+    (PB::CacheMessage, b"cachemessage"), // 0x19
+    (PB::Message, b"message"),
+    (PB::Answer, b"answer"),
+    (PB::Error, b"error"),
+    (PB::DecRef, b"decref"),
+    (PB::DeCache, b"decache"), // 0x1e
+    (PB::UnCache, b"uncache"),
+];
+
+impl PB {
+    /// Short identifier used on the wire
+    pub fn code(self) -> u8 {
+        match VOCABULARY.iter().position(|&(pb, _)| pb == self) {
+            Some(idx) => idx as u8 + 1,
+            None => unreachable!(),
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        (code as usize)
+            .checked_sub(1)
+            .and_then(|idx| VOCABULARY.get(idx))
+            .map(|&(pb, _)| pb)
+    }
+
+    /// The string that this token stands for
+    pub fn token(self) -> &'static [u8] {
+        VOCABULARY[self.code() as usize - 1].1
+    }
+
+    pub fn from_token(token: &[u8]) -> Option<Self> {
+        VOCABULARY
+            .iter()
+            .find(|&&(_, t)| t == token)
+            .map(|&(pb, _)| pb)
+    }
 }
 
 impl Profile for PB {
@@ -58,85 +130,56 @@ impl Profile for PB {
             )));
         }
         Ok((
-            match preamble[0] {
-                0x01 => PB::None,
-                0x02 => PB::Class,
-                0x03 => PB::DeReference,
-                0x04 => PB::Reference,
-                0x05 => PB::Dictionary,
-                0x06 => PB::Function,
-                0x07 => PB::Instance,
-                0x08 => PB::List,
-                0x09 => PB::Module,
-                0x0a => PB::Persistent,
-                0x0b => PB::Tuple,
-                0x0c => PB::UnPersistable,
-                0x0d => PB::Copy,
-                0x0e => PB::Cache,
-                0x0f => PB::Cached,
-                0x10 => PB::Remote,
-                0x11 => PB::Local,
-                0x12 => PB::LCache,
-                0x13 => PB::Version,
-                0x14 => PB::Login,
-                0x15 => PB::Password,
-                0x16 => PB::Challenge,
-                0x17 => PB::LoggedIn,
-                0x18 => PB::NotLoggedIn,
-                0x19 => PB::CacheMessage,
-                0x1a => PB::Message,
-                0x1b => PB::Answer,
-                0x1c => PB::Error,
-                0x1d => PB::DecRef,
-                0x1e => PB::DeCache,
-                0x1f => PB::UnCache,
-                other => {
+            match PB::from_code(preamble[0]) {
+                Some(pb) => pb,
+                None => {
                     return Err(DecodeError::Invalid(
-                        format!("Unknown PB short identifier 0x{:x}", other),
+                        format!("Unknown PB short identifier 0x{:x}", preamble[0]),
                     ));
                 }
             },
-            &full_msg[2..],
+            match full_msg.get(2..) {
+                Some(rem) => rem,
+                None => return Err(DecodeError::TooShort(2, full_msg.len())),
+            },
         ))
 
     }
 
     fn encode(&self, v: &mut Vec<u8>) {
-        v.push(match *self {
-            PB::None => 0x01,
-            PB::Class => 0x02,
-            PB::DeReference => 0x03,
-            PB::Reference => 0x04,
-            PB::Dictionary => 0x05,
-            PB::Function => 0x06,
-            PB::Instance => 0x07,
-            PB::List => 0x08,
-            PB::Module => 0x09,
-            PB::Persistent => 0x0a,
-            PB::Tuple => 0x0b,
-            PB::UnPersistable => 0x0c,
-            PB::Copy => 0x0b,
-            PB::Cache => 0x0e,
-            PB::Cached => 0x0f,
-            PB::Remote => 0x10,
-            PB::Local => 0x11,
-            PB::LCache => 0x12,
-            PB::Version => 0x13,
-            PB::Login => 0x14,
-            PB::Password => 0x15,
-            PB::Challenge => 0x16,
-            PB::LoggedIn => 0x17,
-            PB::NotLoggedIn => 0x18,
-            PB::CacheMessage => 0x19,
-            PB::Message => 0x1a,
-            PB::Answer => 0x1b,
-            PB::Error => 0x1c,
-            PB::DecRef => 0x1d,
-            PB::DeCache => 0x1e,
-            PB::UnCache => 0x1f,
-        });
+        v.push(self.code());
         v.push(0x87);
     }
+
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&[self.code(), 0x87])
+    }
+
+    fn compress(string: &[u8]) -> Option<Self> {
+        PB::from_token(string)
+    }
+}
+
+impl Element<PB> {
+    /// Replace all vocabulary tokens by the strings they stand for.
+    ///
+    /// This is what Twisted does upon reception.
+    pub fn lower_vocabulary(self) -> Banana {
+        match self {
+            Element::Extension(pb) => Element::String(pb.token().to_vec()),
+            Element::List(l) => {
+                Element::List(l.into_iter().map(Element::lower_vocabulary).collect())
+            }
+            Element::Integer(i) => Element::Integer(i),
+            Element::LongInteger(i) => Element::LongInteger(i),
+            Element::String(s) => Element::String(s),
+            Element::Float(f) => Element::Float(f),
+        }
+    }
 }
 
 impl fmt::Display for PB {
@@ -224,10 +267,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn vocabulary() {
+        for code in 1..32 {
+            let pb = PB::from_code(code).unwrap();
+            assert_eq!(pb.code(), code);
+            assert_eq!(PB::from_token(pb.token()), Some(pb));
+            let elt: PerspectiveBroker = Element::Extension(pb);
+            assert_eq!(elt.encode(), vec![code, 0x87]);
+            assert_eq!(PerspectiveBroker::from_bytes(&[code, 0x87]), Ok(elt));
+        }
+        assert_eq!(PB::from_code(0), None);
+        assert_eq!(PB::from_code(32), None);
+        assert_eq!(PB::Copy.code(), 0x0d);
+        assert_eq!(PB::Version.token(), b"version");
+        assert_eq!(PB::from_token(b"logged_in"), Some(PB::LoggedIn));
+        assert_eq!(PB::from_token(b"root"), None);
+    }
+
+    #[test]
+    fn compression() {
+        let elt: PerspectiveBroker = Element::List(vec![
+            Element::String(b"message".to_vec()),
+            Element::String(b"root".to_vec()),
+            Element::String(b"None".to_vec()),
+        ]);
+        let bytes = elt.encode();
+        assert_eq!(
+            bytes,
+            vec![0x03, 0x80, 0x1a, 0x87, 0x04, 0x82, b'r', b'o', b'o', b't', 0x01, 0x87]
+        );
+        assert_eq!(elt.encoded_len(), bytes.len());
+        let mut written = Vec::new();
+        elt.encode_to(&mut written).unwrap();
+        assert_eq!(written, bytes);
+
+        let decoded = PerspectiveBroker::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            Element::List(vec![
+                Element::Extension(PB::Message),
+                Element::String(b"root".to_vec()),
+                Element::Extension(PB::None),
+            ])
+        );
+        assert_eq!(
+            decoded.lower_vocabulary(),
+            Element::List(vec![
+                Element::String(b"message".to_vec()),
+                Element::String(b"root".to_vec()),
+                Element::String(b"None".to_vec()),
+            ])
+        );
+
+        // no compression in the 'none' dialect
+        let elt: Banana = Element::String(b"message".to_vec());
+        assert_eq!(elt.encode()[..2], [0x07, 0x82]);
+    }
+
     #[test]
     fn basic_encode() {
         let elt: PerspectiveBroker = Element::Extension(PB::Dictionary);
         assert_eq!(elt.encode(), vec![5, 0x87]);
+        assert_eq!(elt.encoded_len(), 2);
     }
 
 }
@@ -0,0 +1,454 @@
+//! Runtime selection of extension profiles
+//!
+//! `Element<P>` fixes the profile at compile time, yet the dialect of a connection
+//! is known only after negotiation. Here profiles are objects, registered under their
+//! dialect names, and extension elements are decoded as `DynExtension` values, that can
+//! be downcast to the static profile types.
+
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use super::banana::ExtensionDecoding;
+use super::{DecodeError, DecodeLimits, Element, LocatedDecodeError, NoneProfile, Profile, PB};
+
+/// Object-safe counterpart of `Profile`
+pub trait DynProfile: Send + Sync {
+    /// Dialect name, as used in negotiations
+    fn name(&self) -> &str;
+
+    /// Same as `Profile::decode`
+    fn decode_dyn<'a>(
+        &self,
+        delimiter: u8,
+        preamble: &'a [u8],
+        full_msg: &'a [u8],
+    ) -> Result<(DynExtension, &'a [u8]), DecodeError>;
+
+    /// `TypeId` of the static profile whose elements can be sent in this dialect,
+    /// if any.
+    fn profile_type_id(&self) -> Option<TypeId> {
+        None
+    }
+
+    /// Same as `Profile::compress`
+    fn compress_dyn(&self, _string: &[u8]) -> Option<DynExtension> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn DynProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DynProfile({:?})", self.name())
+    }
+}
+
+/// What static profiles need to be used at runtime
+pub trait ExtensionValue: Any + Send + Sync + fmt::Debug + fmt::Display {
+    fn encode(&self, v: &mut Vec<u8>);
+    fn encoded_len(&self) -> usize;
+    fn encode_to(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn clone_box(&self) -> Box<dyn ExtensionValue>;
+    fn eq_dyn(&self, other: &dyn ExtensionValue) -> bool;
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<P> ExtensionValue for P
+where
+    P: Profile + Clone + PartialEq + Send + Sync + fmt::Debug + fmt::Display + 'static,
+{
+    fn encode(&self, v: &mut Vec<u8>) {
+        Profile::encode(self, v)
+    }
+
+    fn encoded_len(&self) -> usize {
+        Profile::encoded_len(self)
+    }
+
+    fn encode_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        Profile::encode_to(self, w)
+    }
+
+    fn clone_box(&self) -> Box<dyn ExtensionValue> {
+        Box::new(self.clone())
+    }
+
+    fn eq_dyn(&self, other: &dyn ExtensionValue) -> bool {
+        match other.as_any().downcast_ref::<P>() {
+            Some(other) => self == other,
+            None => false,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// Extension element whose profile is known at runtime only
+pub struct DynExtension(Box<dyn ExtensionValue>);
+
+/// Element of a runtime selected profile
+pub type DynElement = Element<DynExtension>;
+
+impl DynExtension {
+    pub fn new<P: ExtensionValue>(value: P) -> Self {
+        DynExtension(Box::new(value))
+    }
+
+    /// `TypeId` of the static profile of the value
+    pub fn profile_type_id(&self) -> TypeId {
+        self.0.as_any().type_id()
+    }
+
+    pub fn downcast_ref<P: ExtensionValue>(&self) -> Option<&P> {
+        self.0.as_any().downcast_ref()
+    }
+
+    pub fn downcast<P: ExtensionValue>(self) -> Result<P, Self> {
+        if self.downcast_ref::<P>().is_none() {
+            return Err(self);
+        }
+        match self.0.into_any().downcast() {
+            Ok(p) => Ok(*p),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl Clone for DynExtension {
+    fn clone(&self) -> Self {
+        DynExtension(self.0.clone_box())
+    }
+}
+
+impl PartialEq for DynExtension {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_dyn(&*other.0)
+    }
+}
+
+impl fmt::Debug for DynExtension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for DynExtension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+impl Profile for DynExtension {
+    /// Dynamic extensions can be decoded only with a `DynProfile`, such as
+    /// through `DynElement::from_bytes_rem_dyn` or `DynDecoder`.
+    fn decode<'a>(
+        delimiter: u8,
+        _p: &'a [u8],
+        _f: &'a [u8],
+    ) -> Result<(Self, &'a [u8]), DecodeError> {
+        Err(DecodeError::UnknownType(delimiter))
+    }
+
+    fn encode(&self, v: &mut Vec<u8>) {
+        self.0.encode(v)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.encoded_len()
+    }
+
+    fn encode_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.0.encode_to(w)
+    }
+}
+
+impl<'p> ExtensionDecoding<DynExtension> for dyn DynProfile + 'p {
+    fn decode_ext<'a>(
+        &self,
+        delimiter: u8,
+        preamble: &'a [u8],
+        full_msg: &'a [u8],
+    ) -> Result<(DynExtension, &'a [u8]), DecodeError> {
+        self.decode_dyn(delimiter, preamble, full_msg)
+    }
+}
+
+impl Element<DynExtension> {
+    /// Decode an element with given profile, returning it with the remaining bytes.
+    pub fn from_bytes_rem_dyn<'a>(
+        bytes: &'a [u8],
+        profile: &dyn DynProfile,
+        limits: &DecodeLimits,
+    ) -> Result<(Self, &'a [u8]), DecodeError> {
+        Self::from_bytes_rem_dyn_located(bytes, profile, limits).map_err(|err| err.error)
+    }
+
+    /// Same as `from_bytes_rem_dyn`, telling where decoding failed.
+    pub fn from_bytes_rem_dyn_located<'a>(
+        bytes: &'a [u8],
+        profile: &dyn DynProfile,
+        limits: &DecodeLimits,
+    ) -> Result<(Self, &'a [u8]), LocatedDecodeError> {
+        let mut used = 0;
+        Self::dec_rem(bytes, profile, limits, 0, &mut used)
+    }
+
+    /// `true` if all extension elements are of the profile of the given dialect
+    pub fn is_of_dialect(&self, profile: &dyn DynProfile) -> bool {
+        match *self {
+            Element::Extension(ref ext) => Some(ext.profile_type_id()) == profile.profile_type_id(),
+            Element::List(ref l) => l.iter().all(|elt| elt.is_of_dialect(profile)),
+            _ => true,
+        }
+    }
+
+    /// Same as `encode_in`, with the vocabulary of the given dialect
+    pub fn encode_in_dialect(&self, profile: &dyn DynProfile, v: &mut Vec<u8>) {
+        match *self {
+            Element::String(ref s) => match profile.compress_dyn(s) {
+                Some(ext) => Profile::encode(&ext, v),
+                None => self.encode_in(v),
+            },
+            Element::List(ref l) => {
+                Self::enc_header(v, l.len() as u128, 0x80);
+                for elt in l {
+                    elt.encode_in_dialect(profile, v);
+                }
+            }
+            _ => self.encode_in(v),
+        }
+    }
+
+    /// Convert to an element of the given static profile, if all
+    /// extension elements are of that profile.
+    pub fn downcast<P: ExtensionValue + Profile>(self) -> Option<Element<P>> {
+        Some(match self {
+            Element::Extension(ext) => Element::Extension(ext.downcast().ok()?),
+            Element::List(l) => {
+                let mut res = Vec::with_capacity(l.len());
+                for elt in l {
+                    res.push(elt.downcast()?);
+                }
+                Element::List(res)
+            }
+            Element::Integer(i) => Element::Integer(i),
+            Element::LongInteger(i) => Element::LongInteger(i),
+            Element::String(s) => Element::String(s),
+            Element::Float(f) => Element::Float(f),
+        })
+    }
+}
+
+impl<P: ExtensionValue + Profile> Element<P> {
+    /// Convert to an element with dynamic extensions
+    pub fn into_dyn(self) -> DynElement {
+        match self {
+            Element::Extension(ext) => Element::Extension(DynExtension::new(ext)),
+            Element::List(l) => Element::List(l.into_iter().map(Element::into_dyn).collect()),
+            Element::Integer(i) => Element::Integer(i),
+            Element::LongInteger(i) => Element::LongInteger(i),
+            Element::String(s) => Element::String(s),
+            Element::Float(f) => Element::Float(f),
+        }
+    }
+}
+
+/// Runtime wrapper of a static profile
+pub struct StaticProfile<P> {
+    name: String,
+    marker: PhantomData<fn() -> P>,
+}
+
+impl<P> StaticProfile<P> {
+    pub fn new(name: &str) -> Self {
+        StaticProfile {
+            name: name.into(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<P: ExtensionValue + Profile> DynProfile for StaticProfile<P> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn decode_dyn<'a>(
+        &self,
+        delimiter: u8,
+        preamble: &'a [u8],
+        full_msg: &'a [u8],
+    ) -> Result<(DynExtension, &'a [u8]), DecodeError> {
+        let (ext, rem) = P::decode(delimiter, preamble, full_msg)?;
+        Ok((DynExtension::new(ext), rem))
+    }
+
+    fn profile_type_id(&self) -> Option<TypeId> {
+        Some(TypeId::of::<P>())
+    }
+
+    fn compress_dyn(&self, string: &[u8]) -> Option<DynExtension> {
+        P::compress(string).map(DynExtension::new)
+    }
+}
+
+/// Profiles, keyed by dialect name
+///
+/// The order of registration is the order of preference in negotiations.
+#[derive(Clone, Debug)]
+pub struct ProfileRegistry {
+    profiles: Vec<Arc<dyn DynProfile>>,
+}
+
+impl Default for ProfileRegistry {
+    /// The profiles provided by this crate, in the same order as Twisted's
+    /// `knownDialects`.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register::<PB>("pb");
+        registry.register::<NoneProfile>("none");
+        registry
+    }
+}
+
+impl ProfileRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        ProfileRegistry { profiles: Vec::new() }
+    }
+
+    /// Register a static profile under given dialect name
+    pub fn register<P: ExtensionValue + Profile>(&mut self, name: &str) {
+        self.register_dyn(Arc::new(StaticProfile::<P>::new(name)));
+    }
+
+    /// Register a profile object. It replaces any profile of the same name,
+    /// keeping its position.
+    pub fn register_dyn(&mut self, profile: Arc<dyn DynProfile>) {
+        match self.profiles.iter().position(|p| p.name() == profile.name()) {
+            Some(idx) => self.profiles[idx] = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn DynProfile>> {
+        self.profiles.iter().find(|p| p.name() == name).cloned()
+    }
+
+    /// Dialect names, in order of preference
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{DynDecoder, PerspectiveBroker};
+
+    #[test]
+    fn registry() {
+        let mut registry = ProfileRegistry::default();
+        assert_eq!(registry.names(), vec!["pb", "none"]);
+        assert!(registry.get("jelly").is_none());
+        registry.register::<PB>("pb2");
+        registry.register::<NoneProfile>("pb");
+        assert_eq!(registry.names(), vec!["pb", "none", "pb2"]);
+        assert_eq!(
+            registry.get("pb").unwrap().profile_type_id(),
+            Some(TypeId::of::<NoneProfile>())
+        );
+    }
+
+    #[test]
+    fn decode_dyn() {
+        let registry = ProfileRegistry::default();
+        let bytes: &[u8] = &[0x02, 0x80, 0x13, 0x87, 0x06, 0x81];
+        let pb = registry.get("pb").unwrap();
+        let (elt, rem) =
+            DynElement::from_bytes_rem_dyn(bytes, &*pb, &DecodeLimits::default()).unwrap();
+        assert!(rem.is_empty());
+        assert_eq!(
+            elt,
+            Element::List(vec![
+                Element::Extension(DynExtension::new(PB::Version)),
+                Element::Integer(6),
+            ])
+        );
+        assert_eq!(format!("{}", elt), "[Version, 6]");
+        assert_eq!(elt.encode(), bytes);
+        let mut written = Vec::new();
+        elt.encode_to(&mut written).unwrap();
+        assert_eq!(written, bytes);
+        let expected: PerspectiveBroker =
+            Element::List(vec![Element::Extension(PB::Version), Element::Integer(6)]);
+        assert_eq!(elt.clone().downcast::<PB>(), Some(expected.clone()));
+        assert_eq!(expected.into_dyn(), elt);
+        assert_eq!(elt.downcast::<NoneProfile>(), None);
+
+        let none = registry.get("none").unwrap();
+        assert_eq!(
+            DynElement::from_bytes_rem_dyn(bytes, &*none, &DecodeLimits::default()),
+            Err(DecodeError::UnknownType(0x87))
+        );
+    }
+
+    #[test]
+    fn switch_profile() {
+        let registry = ProfileRegistry::default();
+        let mut decoder = DynDecoder::new(registry.get("none").unwrap());
+        decoder.feed(&[0x02, 0x82, b'p', b'b', 0x13, 0x87]);
+        assert_eq!(
+            decoder.next_element(),
+            Ok(Some(Element::String(b"pb".to_vec())))
+        );
+        decoder.set_profile(registry.get("pb").unwrap());
+        assert_eq!(decoder.profile().name(), "pb");
+        assert_eq!(
+            decoder.next_element(),
+            Ok(Some(Element::Extension(DynExtension::new(PB::Version))))
+        );
+        assert!(decoder.is_idle());
+    }
+
+    /// A user-defined profile, with a runtime parameter
+    struct Shifted(u8);
+
+    impl DynProfile for Shifted {
+        fn name(&self) -> &str {
+            "shifted"
+        }
+
+        fn decode_dyn<'a>(
+            &self,
+            delimiter: u8,
+            preamble: &'a [u8],
+            full_msg: &'a [u8],
+        ) -> Result<(DynExtension, &'a [u8]), DecodeError> {
+            let (pb, rem) = PB::decode(delimiter, preamble, full_msg)?;
+            match PB::from_code(pb.code() + self.0) {
+                Some(shifted) => Ok((DynExtension::new(shifted), rem)),
+                None => Err(DecodeError::Invalid("Out of vocabulary".into())),
+            }
+        }
+    }
+
+    #[test]
+    fn user_profile() {
+        let mut registry = ProfileRegistry::new();
+        registry.register_dyn(Arc::new(Shifted(1)));
+        let shifted = registry.get("shifted").unwrap();
+        assert_eq!(
+            DynElement::from_bytes_rem_dyn(&[0x13, 0x87], &*shifted, &DecodeLimits::default()),
+            Ok((Element::Extension(DynExtension::new(PB::Login)), &[][..]))
+        );
+    }
+}
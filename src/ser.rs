@@ -0,0 +1,455 @@
+//! Serde serialization into Banana elements
+//!
+//! See the crate documentation for the mapping rules.
+
+use std::error;
+use std::fmt;
+use serde::ser::{self, Serialize};
+use super::{Banana, DecodeError, Element};
+
+/// Error of the serde integration, in both directions
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SerdeError {
+    /// Message from serde or from the (de)serialized type
+    Custom(String),
+    Decode(DecodeError),
+    /// Integer that doesn't fit in a `Element::LongInteger`, or in the target type
+    IntegerOutOfRange(String),
+    /// Some bytes were left after the element (contains their number)
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerdeError::Custom(ref msg) => write!(f, "{}", msg),
+            SerdeError::Decode(ref err) => write!(f, "{}", err),
+            SerdeError::IntegerOutOfRange(ref i) => write!(f, "integer out of range: {}", i),
+            SerdeError::TrailingBytes(n) => write!(f, "{} trailing bytes after element", n),
+        }
+    }
+}
+
+impl error::Error for SerdeError {}
+
+impl From<DecodeError> for SerdeError {
+    fn from(err: DecodeError) -> Self {
+        SerdeError::Decode(err)
+    }
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+/// Serialize as a bare Banana element
+pub fn to_element<T: Serialize + ?Sized>(value: &T) -> Result<Banana, SerdeError> {
+    value.serialize(Serializer)
+}
+
+/// Serialize as bytes ready to be sent
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerdeError> {
+    Ok(to_element(value)?.encode())
+}
+
+fn string(s: &str) -> Banana {
+    Element::String(s.as_bytes().to_vec())
+}
+
+struct Serializer;
+
+/// Accumulates items of lists, possibly starting with an enum variant name
+struct ListSerializer {
+    items: Vec<Banana>,
+}
+
+/// Map entries become `[key, value]` lists
+struct MapSerializer {
+    entries: Vec<Banana>,
+    key: Option<Banana>,
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = ListSerializer;
+    type SerializeStructVariant = ListSerializer;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<Banana, SerdeError> {
+        Ok(Element::Integer(v as i32))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Banana, SerdeError> {
+        Ok(Element::Integer(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Banana, SerdeError> {
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Banana, SerdeError> {
+        if v > i128::MAX as u128 {
+            return Err(SerdeError::IntegerOutOfRange(v.to_string()));
+        }
+        Ok(Element::integer(v as i128))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Banana, SerdeError> {
+        Ok(Element::Float(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Banana, SerdeError> {
+        Ok(Element::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Banana, SerdeError> {
+        Ok(string(v.encode_utf8(&mut [0; 4])))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Banana, SerdeError> {
+        Ok(string(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Banana, SerdeError> {
+        Ok(Element::String(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Banana, SerdeError> {
+        Ok(Element::List(Vec::new()))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Banana, SerdeError> {
+        Ok(Element::List(vec![to_element(value)?]))
+    }
+
+    fn serialize_unit(self) -> Result<Banana, SerdeError> {
+        Ok(Element::List(Vec::new()))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Banana, SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Banana, SerdeError> {
+        Ok(string(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Banana, SerdeError> {
+        to_element(value)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Banana, SerdeError> {
+        Ok(Element::List(vec![string(variant), to_element(value)?]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerdeError> {
+        Ok(ListSerializer { items: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerdeError> {
+        let mut items = Vec::with_capacity(len + 1);
+        items.push(string(variant));
+        Ok(ListSerializer { items })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, SerdeError> {
+        Ok(MapSerializer {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<ListSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerdeError> {
+        self.serialize_tuple_variant(name, index, variant, len)
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.items.push(to_element(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        Ok(Element::List(self.items))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeStruct for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for ListSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Banana;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        self.key = Some(to_element(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Err(ser::Error::custom("map value without a key")),
+        };
+        self.entries.push(Element::List(vec![key, to_element(value)?]));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Banana, SerdeError> {
+        Ok(Element::List(self.entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use serde::Serialize;
+
+    fn s(st: &str) -> Banana {
+        string(st)
+    }
+
+    #[derive(Serialize)]
+    struct Chunk<'a> {
+        step: u32,
+        stream: char,
+        #[serde(with = "serde_bytes_compat")]
+        data: &'a [u8],
+        ratio: Option<f64>,
+    }
+
+    /// serde serializes `&[u8]` as a sequence unless told otherwise
+    mod serde_bytes_compat {
+        use serde::Serializer;
+
+        pub fn serialize<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(v)
+        }
+    }
+
+    #[derive(Serialize)]
+    enum Command {
+        Stop,
+        Log(String),
+        Move(i32, i32),
+        Rename { from: String, to: String },
+    }
+
+    #[test]
+    fn structs() {
+        let chunk = Chunk {
+            step: 3,
+            stream: 'o',
+            data: b"hello",
+            ratio: Some(0.5),
+        };
+        assert_eq!(
+            to_element(&chunk),
+            Ok(Element::List(vec![
+                Element::Integer(3),
+                s("o"),
+                s("hello"),
+                Element::List(vec![Element::Float(0.5)]),
+            ]))
+        );
+        let bytes = to_bytes(&(1u8, "ab")).unwrap();
+        assert_eq!(bytes, vec![0x02, 0x80, 0x01, 0x81, 0x02, 0x82, b'a', b'b']);
+    }
+
+    #[test]
+    fn enums() {
+        assert_eq!(to_element(&Command::Stop), Ok(s("Stop")));
+        assert_eq!(
+            to_element(&Command::Log("up".into())),
+            Ok(Element::List(vec![s("Log"), s("up")]))
+        );
+        assert_eq!(
+            to_element(&Command::Move(1, -2)),
+            Ok(Element::List(vec![s("Move"), Element::Integer(1), Element::Integer(-2)]))
+        );
+        assert_eq!(
+            to_element(&Command::Rename { from: "a".into(), to: "b".into() }),
+            Ok(Element::List(vec![s("Rename"), s("a"), s("b")]))
+        );
+    }
+
+    #[test]
+    fn integers_and_maps() {
+        assert_eq!(to_element(&true), Ok(Element::Integer(1)));
+        assert_eq!(to_element(&(1u64 << 31)), Ok(Element::LongInteger(1 << 31)));
+        assert_eq!(to_element(&i64::MIN), Ok(Element::LongInteger(i64::MIN as i128)));
+        assert_eq!(
+            to_element(&u128::MAX),
+            Err(SerdeError::IntegerOutOfRange(u128::MAX.to_string()))
+        );
+        let mut map = BTreeMap::new();
+        map.insert("a", 1);
+        map.insert("b", 2);
+        assert_eq!(
+            to_element(&map),
+            Ok(Element::List(vec![
+                Element::List(vec![s("a"), Element::Integer(1)]),
+                Element::List(vec![s("b"), Element::Integer(2)]),
+            ]))
+        );
+        assert_eq!(to_element(&()), Ok(Element::List(vec![])));
+        assert_eq!(to_element(&None::<i32>), Ok(Element::List(vec![])));
+    }
+}